@@ -0,0 +1,35 @@
+//! Minimal plugin: scales the rendered note by the `gain` setting.
+//!
+//! Build with `cargo build -p axis-plugin --example gain`, then register the
+//! resulting library with `axis plugin add`.
+
+use axis_plugin::{AxisPlugin, PluginMetadata, PluginSettings};
+
+struct Gain {
+    gain: f64,
+}
+
+impl AxisPlugin for Gain {
+    fn metadata(&self) -> PluginMetadata {
+        PluginMetadata {
+            name: "Gain".into(),
+            version: "0.1.0".into(),
+            author: "AXIS".into(),
+            description: "Scales the output by a constant gain".into(),
+        }
+    }
+
+    fn configure(&mut self, settings: &PluginSettings) -> anyhow::Result<()> {
+        self.gain = settings.get_f64("gain")?.unwrap_or(1.0);
+        Ok(())
+    }
+
+    fn process_audio(&mut self, samples: &mut [f64], _sample_rate: u32) -> anyhow::Result<()> {
+        for s in samples.iter_mut() {
+            *s *= self.gain;
+        }
+        Ok(())
+    }
+}
+
+axis_plugin::export_plugin!(Gain { gain: 1.0 });
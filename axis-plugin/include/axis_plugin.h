@@ -0,0 +1,83 @@
+/*
+ * C ABI for AXIS resampler plugins. Mirrors axis_plugin::ffi.
+ *
+ * A plugin library exports:
+ *   uint32_t axis_plugin_abi_version(void);             returns AXIS_PLUGIN_ABI_VERSION
+ *   void     axis_plugin_host_range(AxisHostRange *out); optional, AXIS versions supported
+ *   int32_t  axis_plugin_create(AxisPluginVTable *out); fills in *out, returns AXIS_OK
+ */
+#ifndef AXIS_PLUGIN_H
+#define AXIS_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define AXIS_PLUGIN_ABI_VERSION 2u
+
+#define AXIS_OK 0
+#define AXIS_ERROR 1
+#define AXIS_INVALID_ARGUMENT 2
+#define AXIS_PANIC 3
+
+typedef int32_t AxisStatus;
+
+typedef struct AxisVersion {
+    uint32_t major;
+    uint32_t minor;
+    uint32_t patch;
+} AxisVersion;
+
+/* min inclusive, max exclusive. */
+typedef struct AxisHostRange {
+    AxisVersion min;
+    AxisVersion max;
+} AxisHostRange;
+
+/* NUL-terminated UTF-8, owned by the plugin, valid until the next call on the instance. */
+typedef struct AxisPluginInfo {
+    const char *name;
+    const char *version;
+    const char *author;
+    const char *description;
+} AxisPluginInfo;
+
+typedef struct AxisSetting {
+    const char *key;
+    const char *value;
+} AxisSetting;
+
+/* spectral and aperiodicity are row-major frames x bins matrices. */
+typedef struct AxisFeatureBuffer {
+    size_t frames;
+    double *f0;
+    double *spectral;
+    size_t spectral_bins;
+    double *aperiodicity;
+    size_t aperiodicity_bins;
+} AxisFeatureBuffer;
+
+typedef struct AxisPluginVTable {
+    uint32_t abi_version;
+    void *instance;
+    AxisStatus (*metadata)(void *instance, AxisPluginInfo *out);
+    AxisStatus (*on_load)(void *instance);
+    AxisStatus (*on_unload)(void *instance);
+    AxisStatus (*configure)(void *instance, const AxisSetting *settings, size_t len);
+    AxisStatus (*process_audio)(void *instance, double *samples, size_t len, uint32_t sample_rate);
+    AxisStatus (*process_features)(void *instance, AxisFeatureBuffer *features, uint32_t sample_rate);
+    /* Nonzero if the analysis hooks should be called; queried after configure. */
+    uint32_t (*analysis_hooks)(void *instance);
+    AxisStatus (*pre_analysis)(void *instance, double *samples, size_t len, uint32_t sample_rate);
+    /* f0 holds frames values, one every frame_period milliseconds. */
+    AxisStatus (*override_f0)(void *instance, const double *samples, size_t len, uint32_t sample_rate,
+                              double frame_period, double *f0, size_t frames);
+    AxisStatus (*post_analysis)(void *instance, AxisFeatureBuffer *features, uint32_t sample_rate);
+    const char *(*last_error)(void *instance);
+    void (*destroy)(void *instance);
+} AxisPluginVTable;
+
+uint32_t axis_plugin_abi_version(void);
+void axis_plugin_host_range(AxisHostRange *out);
+AxisStatus axis_plugin_create(AxisPluginVTable *out);
+
+#endif
@@ -0,0 +1,452 @@
+//! The C ABI between the AXIS host and plugin libraries.
+//!
+//! A plugin library exports these symbols:
+//!
+//! - `uint32_t axis_plugin_abi_version(void)`, returning [`AXIS_PLUGIN_ABI_VERSION`]
+//! - `void axis_plugin_host_range(AxisHostRange *out)`, the AXIS versions the
+//!   plugin supports (optional; without it any host speaking the ABI is accepted)
+//! - `int32_t axis_plugin_create(AxisPluginVTable *out)`, filling in `out`
+//!
+//! Only `#[repr(C)]` types, plain integers and raw pointers cross the
+//! boundary, so plugins and the host may be built with different compilers.
+//! Rust plugins should use [`export_plugin!`](crate::export_plugin) instead of
+//! implementing this by hand; `include/axis_plugin.h` mirrors it for C.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{AxisPlugin, PluginSettings};
+
+/// Bumped on any change to the layout or meaning of the types in this module.
+pub const AXIS_PLUGIN_ABI_VERSION: u32 = 2;
+
+/// A `major.minor.patch` version of the AXIS host.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AxisVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl AxisVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self { major, minor, patch }
+    }
+
+    /// Parses `major.minor.patch`, ignoring any pre-release or build suffix.
+    pub fn parse(s: &str) -> Option<Self> {
+        let core = s.trim().split(['-', '+']).next()?;
+        let mut parts = core.split('.').map(|p| p.parse::<u32>());
+        let version = Self::new(parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+        parts.next().is_none().then_some(version)
+    }
+}
+
+impl fmt::Display for AxisVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// The AXIS versions a plugin runs on: `min` inclusive, `max` exclusive.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AxisHostRange {
+    pub min: AxisVersion,
+    pub max: AxisVersion,
+}
+
+impl AxisHostRange {
+    pub const fn new(min: AxisVersion, max: AxisVersion) -> Self {
+        Self { min, max }
+    }
+
+    pub fn contains(&self, version: AxisVersion) -> bool {
+        self.min <= version && version < self.max
+    }
+}
+
+impl fmt::Display for AxisHostRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ">={}, <{}", self.min, self.max)
+    }
+}
+
+/// Range exported by [`export_plugin!`](crate::export_plugin) when the plugin
+/// does not give one: every host release speaking [`AXIS_PLUGIN_ABI_VERSION`].
+pub const DEFAULT_HOST_RANGE: AxisHostRange = AxisHostRange::new(AxisVersion::new(0, 1, 2), AxisVersion::new(1, 0, 0));
+
+pub type AxisStatus = i32;
+pub const AXIS_OK: AxisStatus = 0;
+/// The plugin returned an error; details are available from `last_error`.
+pub const AXIS_ERROR: AxisStatus = 1;
+/// A pointer or buffer shape passed to the plugin was invalid.
+pub const AXIS_INVALID_ARGUMENT: AxisStatus = 2;
+/// The plugin panicked. The instance should not be used again.
+pub const AXIS_PANIC: AxisStatus = 3;
+
+/// NUL-terminated UTF-8 strings owned by the plugin. They stay valid until
+/// the next call on the same instance.
+#[repr(C)]
+pub struct AxisPluginInfo {
+    pub name: *const c_char,
+    pub version: *const c_char,
+    pub author: *const c_char,
+    pub description: *const c_char,
+}
+
+/// One `key "value"` pair from the plugin's `config.kdl` block.
+#[repr(C)]
+pub struct AxisSetting {
+    pub key: *const c_char,
+    pub value: *const c_char,
+}
+
+/// Per-frame features. `spectral` and `aperiodicity` are row-major
+/// `frames x bins` matrices.
+#[repr(C)]
+pub struct AxisFeatureBuffer {
+    pub frames: usize,
+    pub f0: *mut f64,
+    pub spectral: *mut f64,
+    pub spectral_bins: usize,
+    pub aperiodicity: *mut f64,
+    pub aperiodicity_bins: usize,
+}
+
+/// A plugin instance and its entry points. Every function takes `instance`
+/// as its first argument and must not be called concurrently on one instance.
+#[repr(C)]
+pub struct AxisPluginVTable {
+    pub abi_version: u32,
+    pub instance: *mut c_void,
+    pub metadata: unsafe extern "C" fn(instance: *mut c_void, out: *mut AxisPluginInfo) -> AxisStatus,
+    pub on_load: unsafe extern "C" fn(instance: *mut c_void) -> AxisStatus,
+    pub on_unload: unsafe extern "C" fn(instance: *mut c_void) -> AxisStatus,
+    pub configure: unsafe extern "C" fn(instance: *mut c_void, settings: *const AxisSetting, len: usize) -> AxisStatus,
+    pub process_audio: unsafe extern "C" fn(instance: *mut c_void, samples: *mut f64, len: usize, sample_rate: u32) -> AxisStatus,
+    pub process_features: unsafe extern "C" fn(instance: *mut c_void, features: *mut AxisFeatureBuffer, sample_rate: u32) -> AxisStatus,
+    /// Nonzero if the analysis hooks below should be called. Queried after `configure`.
+    pub analysis_hooks: unsafe extern "C" fn(instance: *mut c_void) -> u32,
+    pub pre_analysis: unsafe extern "C" fn(instance: *mut c_void, samples: *mut f64, len: usize, sample_rate: u32) -> AxisStatus,
+    /// `f0` holds `frames` values, one every `frame_period` milliseconds.
+    pub override_f0: unsafe extern "C" fn(
+        instance: *mut c_void,
+        samples: *const f64,
+        len: usize,
+        sample_rate: u32,
+        frame_period: f64,
+        f0: *mut f64,
+        frames: usize,
+    ) -> AxisStatus,
+    pub post_analysis: unsafe extern "C" fn(instance: *mut c_void, features: *mut AxisFeatureBuffer, sample_rate: u32) -> AxisStatus,
+    /// Message for the last non-`AXIS_OK` status, or null.
+    pub last_error: unsafe extern "C" fn(instance: *mut c_void) -> *const c_char,
+    /// Frees the instance. No other entry point may be called afterwards.
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void),
+}
+
+pub type AbiVersionFn = unsafe extern "C" fn() -> u32;
+pub type HostRangeFn = unsafe extern "C" fn(out: *mut AxisHostRange);
+pub type CreateFn = unsafe extern "C" fn(out: *mut AxisPluginVTable) -> AxisStatus;
+
+/// Exports a Rust [`AxisPlugin`] through the C ABI. `$ctor` is evaluated once
+/// per instance the host creates. An optional [`AxisHostRange`] restricts the
+/// AXIS versions that will load the plugin (default [`DEFAULT_HOST_RANGE`]).
+///
+/// ```ignore
+/// use axis_plugin::ffi::{AxisHostRange, AxisVersion};
+///
+/// axis_plugin::export_plugin!(MyPlugin::default());
+/// // or
+/// axis_plugin::export_plugin!(
+///     MyPlugin::default(),
+///     AxisHostRange::new(AxisVersion::new(0, 2, 0), AxisVersion::new(0, 3, 0))
+/// );
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+    ($ctor:expr) => {
+        $crate::export_plugin!($ctor, $crate::ffi::DEFAULT_HOST_RANGE);
+    };
+    ($ctor:expr, $host_range:expr) => {
+        #[no_mangle]
+        pub extern "C" fn axis_plugin_abi_version() -> u32 {
+            $crate::ffi::AXIS_PLUGIN_ABI_VERSION
+        }
+
+        /// # Safety
+        ///
+        /// `out` must be null or valid for writes.
+        #[no_mangle]
+        pub unsafe extern "C" fn axis_plugin_host_range(out: *mut $crate::ffi::AxisHostRange) {
+            const RANGE: $crate::ffi::AxisHostRange = $host_range;
+            if !out.is_null() {
+                out.write(RANGE);
+            }
+        }
+
+        /// # Safety
+        ///
+        /// `out` must be valid for writes.
+        #[no_mangle]
+        pub unsafe extern "C" fn axis_plugin_create(out: *mut $crate::ffi::AxisPluginVTable) -> $crate::ffi::AxisStatus {
+            $crate::ffi::export::create(|| $ctor, out)
+        }
+    };
+}
+
+/// Plugin-side glue used by [`export_plugin!`](crate::export_plugin).
+#[doc(hidden)]
+pub mod export {
+    use super::*;
+
+    struct Instance<P> {
+        plugin: P,
+        info: Vec<CString>,
+        last_error: Option<CString>,
+    }
+
+    fn to_cstring(s: &str) -> CString {
+        CString::new(s.replace('\0', "")).unwrap_or_default()
+    }
+
+    /// Runs `f` on the instance, turning errors and panics into status codes.
+    unsafe fn guard<P, F>(instance: *mut c_void, f: F) -> AxisStatus
+    where
+        F: FnOnce(&mut Instance<P>) -> Result<(), (AxisStatus, String)>,
+    {
+        let Some(inst) = (instance as *mut Instance<P>).as_mut() else {
+            return AXIS_INVALID_ARGUMENT;
+        };
+        inst.last_error = None;
+        match catch_unwind(AssertUnwindSafe(|| f(inst))) {
+            Ok(Ok(())) => AXIS_OK,
+            Ok(Err((status, message))) => {
+                inst.last_error = Some(to_cstring(&message));
+                status
+            }
+            Err(_) => {
+                inst.last_error = Some(to_cstring("plugin panicked"));
+                AXIS_PANIC
+            }
+        }
+    }
+
+    fn plugin_error(e: anyhow::Error) -> (AxisStatus, String) {
+        (AXIS_ERROR, format!("{:#}", e))
+    }
+
+    fn invalid(message: &str) -> (AxisStatus, String) {
+        (AXIS_INVALID_ARGUMENT, message.to_string())
+    }
+
+    unsafe extern "C" fn metadata<P: AxisPlugin>(instance: *mut c_void, out: *mut AxisPluginInfo) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            let out = out.as_mut().ok_or_else(|| invalid("null metadata pointer"))?;
+            let meta = inst.plugin.metadata();
+            inst.info = [meta.name, meta.version, meta.author, meta.description]
+                .iter()
+                .map(|s| to_cstring(s))
+                .collect();
+            out.name = inst.info[0].as_ptr();
+            out.version = inst.info[1].as_ptr();
+            out.author = inst.info[2].as_ptr();
+            out.description = inst.info[3].as_ptr();
+            Ok(())
+        })
+    }
+
+    unsafe extern "C" fn on_load<P: AxisPlugin>(instance: *mut c_void) -> AxisStatus {
+        guard::<P, _>(instance, |inst| inst.plugin.on_load().map_err(plugin_error))
+    }
+
+    unsafe extern "C" fn on_unload<P: AxisPlugin>(instance: *mut c_void) -> AxisStatus {
+        guard::<P, _>(instance, |inst| inst.plugin.on_unload().map_err(plugin_error))
+    }
+
+    unsafe extern "C" fn configure<P: AxisPlugin>(instance: *mut c_void, settings: *const AxisSetting, len: usize) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            let raw = if len == 0 {
+                &[][..]
+            } else if settings.is_null() {
+                return Err(invalid("null settings pointer"));
+            } else {
+                std::slice::from_raw_parts(settings, len)
+            };
+            let mut pairs = Vec::with_capacity(raw.len());
+            for s in raw {
+                if s.key.is_null() || s.value.is_null() {
+                    return Err(invalid("null setting key or value"));
+                }
+                pairs.push((
+                    CStr::from_ptr(s.key).to_string_lossy().into_owned(),
+                    CStr::from_ptr(s.value).to_string_lossy().into_owned(),
+                ));
+            }
+            inst.plugin.configure(&PluginSettings::new(pairs)).map_err(plugin_error)
+        })
+    }
+
+    unsafe fn samples_mut<'a>(samples: *mut f64, len: usize) -> Result<&'a mut [f64], (AxisStatus, String)> {
+        if len == 0 {
+            Ok(&mut [])
+        } else if samples.is_null() {
+            Err(invalid("null sample buffer"))
+        } else {
+            Ok(std::slice::from_raw_parts_mut(samples, len))
+        }
+    }
+
+    unsafe extern "C" fn process_audio<P: AxisPlugin>(instance: *mut c_void, samples: *mut f64, len: usize, sample_rate: u32) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            let samples = samples_mut(samples, len)?;
+            inst.plugin.process_audio(samples, sample_rate).map_err(plugin_error)
+        })
+    }
+
+    unsafe extern "C" fn pre_analysis<P: AxisPlugin>(instance: *mut c_void, samples: *mut f64, len: usize, sample_rate: u32) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            let samples = samples_mut(samples, len)?;
+            inst.plugin.pre_analysis(samples, sample_rate).map_err(plugin_error)
+        })
+    }
+
+    unsafe extern "C" fn override_f0<P: AxisPlugin>(
+        instance: *mut c_void,
+        samples: *const f64,
+        len: usize,
+        sample_rate: u32,
+        frame_period: f64,
+        f0: *mut f64,
+        frames: usize,
+    ) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            let samples: &[f64] = if len == 0 {
+                &[]
+            } else if samples.is_null() {
+                return Err(invalid("null sample buffer"));
+            } else {
+                std::slice::from_raw_parts(samples, len)
+            };
+            let f0 = samples_mut(f0, frames)?;
+            inst.plugin.override_f0(samples, sample_rate, frame_period, f0).map_err(plugin_error)
+        })
+    }
+
+    unsafe extern "C" fn analysis_hooks<P: AxisPlugin>(instance: *mut c_void) -> u32 {
+        match (instance as *mut Instance<P>).as_ref() {
+            Some(inst) => catch_unwind(AssertUnwindSafe(|| inst.plugin.analysis_hooks() as u32)).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    unsafe fn unpack(ptr: *mut f64, frames: usize, bins: usize) -> Result<Vec<Vec<f64>>, (AxisStatus, String)> {
+        if frames * bins == 0 {
+            return Ok(vec![Vec::new(); frames]);
+        }
+        if ptr.is_null() {
+            return Err(invalid("null feature matrix"));
+        }
+        let flat = std::slice::from_raw_parts(ptr, frames * bins);
+        Ok(flat.chunks(bins).map(|row| row.to_vec()).collect())
+    }
+
+    unsafe fn pack(rows: &[Vec<f64>], ptr: *mut f64, bins: usize) -> Result<(), (AxisStatus, String)> {
+        if rows.iter().any(|r| r.len() != bins) {
+            return Err(invalid("plugin changed the number of bins in a feature frame"));
+        }
+        if bins > 0 {
+            let flat = std::slice::from_raw_parts_mut(ptr, rows.len() * bins);
+            for (dst, row) in flat.chunks_mut(bins).zip(rows) {
+                dst.copy_from_slice(row);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpacks a feature buffer, runs `f` on it and writes the result back.
+    unsafe fn with_features<F>(features: *mut AxisFeatureBuffer, f: F) -> Result<(), (AxisStatus, String)>
+    where
+        F: FnOnce(&mut [f64], &mut [Vec<f64>], &mut [Vec<f64>]) -> anyhow::Result<()>,
+    {
+        let buf = features.as_mut().ok_or_else(|| invalid("null feature buffer"))?;
+        if buf.frames > 0 && buf.f0.is_null() {
+            return Err(invalid("null f0 buffer"));
+        }
+        let f0: &mut [f64] = if buf.frames == 0 { &mut [] } else { std::slice::from_raw_parts_mut(buf.f0, buf.frames) };
+        let mut spectral = unpack(buf.spectral, buf.frames, buf.spectral_bins)?;
+        let mut aperiodicity = unpack(buf.aperiodicity, buf.frames, buf.aperiodicity_bins)?;
+        f(f0, &mut spectral, &mut aperiodicity).map_err(plugin_error)?;
+        pack(&spectral, buf.spectral, buf.spectral_bins)?;
+        pack(&aperiodicity, buf.aperiodicity, buf.aperiodicity_bins)
+    }
+
+    unsafe extern "C" fn process_features<P: AxisPlugin>(instance: *mut c_void, features: *mut AxisFeatureBuffer, sample_rate: u32) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            with_features(features, |f0, spectral, aperiodicity| {
+                inst.plugin.process_features(f0, spectral, aperiodicity, sample_rate)
+            })
+        })
+    }
+
+    unsafe extern "C" fn post_analysis<P: AxisPlugin>(instance: *mut c_void, features: *mut AxisFeatureBuffer, sample_rate: u32) -> AxisStatus {
+        guard::<P, _>(instance, |inst| {
+            with_features(features, |f0, spectral, aperiodicity| {
+                inst.plugin.post_analysis(f0, spectral, aperiodicity, sample_rate)
+            })
+        })
+    }
+
+    unsafe extern "C" fn last_error<P: AxisPlugin>(instance: *mut c_void) -> *const c_char {
+        match (instance as *mut Instance<P>).as_ref().and_then(|i| i.last_error.as_ref()) {
+            Some(message) => message.as_ptr(),
+            None => std::ptr::null(),
+        }
+    }
+
+    unsafe extern "C" fn destroy<P: AxisPlugin>(instance: *mut c_void) {
+        if !instance.is_null() {
+            let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(instance as *mut Instance<P>))));
+        }
+    }
+
+    /// Builds a plugin with `ctor` and fills `out` with its vtable.
+    ///
+    /// # Safety
+    ///
+    /// `out` must be null or valid for writes.
+    pub unsafe fn create<P, F>(ctor: F, out: *mut AxisPluginVTable) -> AxisStatus
+    where
+        P: AxisPlugin + 'static,
+        F: FnOnce() -> P,
+    {
+        if out.is_null() {
+            return AXIS_INVALID_ARGUMENT;
+        }
+        let plugin = match catch_unwind(AssertUnwindSafe(ctor)) {
+            Ok(plugin) => plugin,
+            Err(_) => return AXIS_PANIC,
+        };
+        let instance = Box::new(Instance { plugin, info: Vec::new(), last_error: None });
+        out.write(AxisPluginVTable {
+            abi_version: AXIS_PLUGIN_ABI_VERSION,
+            instance: Box::into_raw(instance) as *mut c_void,
+            metadata: metadata::<P>,
+            on_load: on_load::<P>,
+            on_unload: on_unload::<P>,
+            configure: configure::<P>,
+            process_audio: process_audio::<P>,
+            process_features: process_features::<P>,
+            analysis_hooks: analysis_hooks::<P>,
+            pre_analysis: pre_analysis::<P>,
+            override_f0: override_f0::<P>,
+            post_analysis: post_analysis::<P>,
+            last_error: last_error::<P>,
+            destroy: destroy::<P>,
+        });
+        AXIS_OK
+    }
+}
@@ -0,0 +1,140 @@
+//! SDK for AXIS resampler plugins.
+//!
+//! Implement [`AxisPlugin`] and export it with [`export_plugin!`]. The macro
+//! wraps the plugin in the C ABI described in [`ffi`], so the resulting
+//! `cdylib` works with any AXIS build that speaks the same ABI version,
+//! regardless of the compiler used for either side.
+
+use serde::{Deserialize, Serialize};
+
+pub mod ffi;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginMetadata {
+    pub name: String,
+    pub version: String,
+    pub author: String,
+    pub description: String,
+}
+
+/// Settings from a plugin's block in `config.kdl`:
+///
+/// ```kdl
+/// plugin "My Plugin" enabled=true {
+///     gain "0.5"
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PluginSettings {
+    values: Vec<(String, String)>,
+}
+
+impl PluginSettings {
+    pub fn new<I, K, V>(values: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Self { values: values.into_iter().map(|(k, v)| (k.into(), v.into())).collect() }
+    }
+
+    /// Raw value of `key`. If a key is repeated, the last occurrence wins.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Parses `key` as `T`, returning `None` when it is not set.
+    pub fn parse<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.get(key)
+            .map(|v| v.parse::<T>().map_err(|e| anyhow::anyhow!("Invalid value '{}' for setting '{}': {}", v, key, e)))
+            .transpose()
+    }
+
+    pub fn get_f64(&self, key: &str) -> anyhow::Result<Option<f64>> {
+        self.parse(key)
+    }
+
+    pub fn get_i64(&self, key: &str) -> anyhow::Result<Option<i64>> {
+        self.parse(key)
+    }
+
+    /// Accepts `true`/`false`, `yes`/`no`, `on`/`off` and `1`/`0`.
+    pub fn get_bool(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        self.get(key)
+            .map(|v| match v.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(true),
+                "false" | "no" | "off" | "0" => Ok(false),
+                _ => Err(anyhow::anyhow!("Invalid value '{}' for setting '{}': expected a boolean", v, key)),
+            })
+            .transpose()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+pub trait AxisPlugin: Send + Sync {
+    fn metadata(&self) -> PluginMetadata;
+    fn on_load(&mut self) -> anyhow::Result<()> { Ok(()) }
+    fn on_unload(&mut self) -> anyhow::Result<()> { Ok(()) }
+
+    /// Receives this plugin's settings from `config.kdl`. Called once after
+    /// `on_load`, with empty settings when the config has no block for it.
+    fn configure(&mut self, _settings: &PluginSettings) -> anyhow::Result<()> {
+        Ok(())
+    }
+    
+    fn process_audio(&mut self, _samples: &mut [f64], _sample_rate: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn process_features(
+        &mut self,
+        _f0: &mut [f64],
+        _spectral: &mut [Vec<f64>],
+        _aperiodicity: &mut [Vec<f64>],
+        _sample_rate: u32,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Opts in to the analysis hooks below, checked after `configure`. Their
+    /// output is saved in the sample's `.axxf` file, so they run once per
+    /// sample rather than once per note; the plugin's name, version and
+    /// settings are recorded there and a change re-analyzes the voicebank.
+    fn analysis_hooks(&self) -> bool {
+        false
+    }
+
+    /// Edits the raw source sample before analysis, e.g. to denoise it.
+    fn pre_analysis(&mut self, _samples: &mut [f64], _sample_rate: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Replaces the estimated F0 track (one value per `frame_period` ms,
+    /// 0 for unvoiced) before voicing is smoothed.
+    fn override_f0(&mut self, _samples: &[f64], _sample_rate: u32, _frame_period: f64, _f0: &mut [f64]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Edits the finished analysis before it is cached.
+    fn post_analysis(
+        &mut self,
+        _f0: &mut [f64],
+        _spectral: &mut [Vec<f64>],
+        _aperiodicity: &mut [Vec<f64>],
+        _sample_rate: u32,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,989 @@
+use anyhow::{bail, Context, Result};
+use bincode::Options;
+use crate::api::{AnalysisConfig, AxisConfig, AxisPlugin, PluginLoader};
+use crate::audio;
+use crate::cache::AnalysisCache;
+use crate::util::Fnv1a;
+use crate::lab;
+use crate::rate::{self, RateConversion};
+use crate::vocoder::hmm::{HmmMode, HmmTrainer, VoicingHmm, VoicingHmmParams, VoicingState, VOICING_FLOOR};
+use crate::vocoder::coding::{CacheCompression, CodedFeatures};
+use crate::vocoder::stydl::StydlVocoder;
+use log::{debug, info, warn};
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+pub const FRAME_PERIOD: f64 = 5.0;
+
+/// Leading bytes of every `.axxf` file.
+pub const AXXF_MAGIC: [u8; 4] = *b"AXXF";
+/// Bumped whenever the header or `AxisFeatures` layout changes.
+pub const AXXF_VERSION: u32 = 7;
+
+/// Everything an `.axxf` file depends on. A cached file is only reused when
+/// its stored header is identical to the one computed for the current run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnalysisHeader {
+    pub version: u32,
+    pub sample_rate: u32,
+    pub frame_period: f64,
+    /// Samples per frame, `sample_rate * frame_period / 1000`.
+    pub hop_size: f64,
+    pub fft_size: usize,
+    pub f0_estimator: String,
+    pub pitch_tracker: String,
+    pub spectral_estimator: String,
+    pub aperiodicity_estimator: String,
+    /// How the features are stored, see `CacheCompression::name`.
+    pub compression: String,
+    pub source_len: usize,
+    pub source_hash: u64,
+    /// Plugins whose analysis hooks shaped the result, in order.
+    pub analysis_plugins: Vec<String>,
+}
+
+/// Analysis settings resolved from `config.kdl`.
+#[derive(Debug, Clone)]
+pub struct AnalysisOptions {
+    /// Worker threads for per-frame spectral and aperiodicity estimation.
+    pub threads: usize,
+    /// Read and write `.axxf` files; when off, analysis only lives in memory.
+    pub cache: bool,
+    /// `PluginLoader::analysis_id` of every plugin with analysis hooks, in order.
+    pub plugin_chain: Vec<String>,
+    /// The `analysis` node of `config.kdl`: F0 estimator and range, per voicebank.
+    pub analysis: AnalysisConfig,
+    /// `cache-compression` of `config.kdl`: `"none"` or `"mcep"`.
+    pub compression: Option<String>,
+    /// Store `.axxf` files here instead of next to their samples.
+    pub central_cache: Option<AnalysisCache>,
+    /// Convert samples to this rate before analysis (`rate-conversion="before-analysis"`).
+    pub sample_rate: Option<u32>,
+    /// Rate of rendered notes, `output-rate` of `config.kdl`.
+    pub output_rate: Option<u32>,
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        Self { threads: 1, cache: true, plugin_chain: Vec::new(), analysis: AnalysisConfig::default(), compression: None, central_cache: None, sample_rate: None, output_rate: None }
+    }
+}
+
+impl AnalysisOptions {
+    pub fn from_config(config: &AxisConfig) -> Result<Self> {
+        let central_cache = match config.central_cache().then(|| AnalysisCache::from_config(config)) {
+            Some(Ok(cache)) => Some(cache),
+            Some(Err(e)) => {
+                warn!("Central analysis cache unavailable, storing analysis next to samples: {:#}", e);
+                None
+            }
+            None => None,
+        };
+        let output_rate = config.output_rate()?;
+        let sample_rate = match config.rate_conversion().context("Invalid rate-conversion")? {
+            RateConversion::BeforeAnalysis => output_rate,
+            RateConversion::AfterSynthesis => None,
+        };
+        Ok(Self {
+            threads: config.threads(),
+            cache: config.analysis_enabled(),
+            plugin_chain: Vec::new(),
+            analysis: config.analysis.clone().unwrap_or_default(),
+            compression: config.cache_compression(),
+            central_cache,
+            sample_rate,
+            output_rate,
+        })
+    }
+
+    /// Decodes `source` with its voicebank's channel mode, converted to `sample_rate` if one is set.
+    pub fn load_source(&self, source: &Path) -> Result<(Vec<f64>, u32)> {
+        let mode = self.analysis.settings(source).channel_mode()
+            .with_context(|| format!("Invalid channel-mode for {}", source.display()))?;
+        let (samples, rate) = audio::load_audio_with(source, mode)?;
+        match self.sample_rate {
+            Some(to) if to != rate && !samples.is_empty() => {
+                debug!("Converting {} from {} Hz to {} Hz", source.display(), rate, to);
+                Ok((rate::convert(&samples, rate, to)?, to))
+            }
+            _ => Ok((samples, rate)),
+        }
+    }
+
+    /// Where the `.axxf` file of `source`, analyzed as described by `header`, is stored.
+    pub fn analysis_path(&self, source: &Path, header: &AnalysisHeader) -> PathBuf {
+        match &self.central_cache {
+            Some(cache) => cache.path_for(header),
+            None => get_analysis_path(source),
+        }
+    }
+
+    pub fn compression(&self) -> Result<CacheCompression> {
+        self.compression.as_deref().map_or(Ok(CacheCompression::default()), str::parse)
+    }
+
+    /// The analysis chain for `source`, with the F0 estimator configured for its voicebank.
+    pub fn vocoder(&self, source: &Path, sample_rate: u32) -> Result<StydlVocoder> {
+        let settings = self.analysis.settings(source);
+        let estimator = settings.f0_estimator(sample_rate)
+            .with_context(|| format!("Invalid F0 settings for {}", source.display()))?;
+        let mode = settings.hmm_mode().with_context(|| format!("Invalid hmm-mode for {}", source.display()))?;
+        settings.hmm.validate().with_context(|| format!("Invalid hmm parameters for {}", source.display()))?;
+        let spectral = settings.spectral_method().with_context(|| format!("Invalid spectral-estimator for {}", source.display()))?;
+        let voicing = VoicingHmm::with_params(settings.hmm).with_mode(mode);
+        Ok(StydlVocoder::new(sample_rate, 4096).with_f0_estimator(estimator).with_voicing(voicing).with_spectral_method(spectral))
+    }
+
+    /// Records the analysis hooks of `loaders` in the options.
+    pub fn with_plugins(mut self, loaders: &[PluginLoader]) -> Self {
+        self.plugin_chain = loaders.iter().filter_map(|l| l.analysis_id()).map(str::to_string).collect();
+        self
+    }
+}
+
+/// The plugins in `loaders` that opted in to analysis hooks.
+pub fn hook_plugins(loaders: &mut [PluginLoader]) -> Vec<&mut dyn AxisPlugin> {
+    loaders
+        .iter_mut()
+        .filter(|l| l.analysis_id().is_some())
+        .map(|l| l.plugin())
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AxisFeatures {
+    pub f0: Vec<f64>,
+    pub spec: Vec<Vec<f64>>,
+    pub ap: Vec<Vec<f64>>,
+    /// Posterior probability of each frame being voiced.
+    pub voicing: Vec<f64>,
+    pub source_base_hz: f64,
+    pub fft_size: usize,
+    /// F0 below which a frame is unvoiced, the `f0-threshold` of the voicing HMM.
+    pub f0_threshold: f64,
+}
+
+/// Body of an `.axxf` file, in the coding selected by `cache-compression`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StoredFeatures {
+    Full(AxisFeatures),
+    Coded(CodedFeatures),
+}
+
+impl StoredFeatures {
+    pub fn new(features: AxisFeatures, compression: CacheCompression, sample_rate: u32) -> Self {
+        match compression {
+            CacheCompression::None => StoredFeatures::Full(features),
+            CacheCompression::MelCepstrum => StoredFeatures::Coded(CodedFeatures::encode(&features, sample_rate)),
+        }
+    }
+
+    pub fn into_features(self) -> AxisFeatures {
+        match self {
+            StoredFeatures::Full(features) => features,
+            StoredFeatures::Coded(coded) => coded.decode(),
+        }
+    }
+}
+
+impl AnalysisHeader {
+    pub fn new(vocoder: &StydlVocoder, samples: &[f64], options: &AnalysisOptions, compression: CacheCompression) -> Self {
+        Self {
+            version: AXXF_VERSION,
+            sample_rate: vocoder.sample_rate,
+            frame_period: vocoder.frame_period.period_ms,
+            hop_size: vocoder.frame_period.hop(),
+            fft_size: vocoder.fft_size,
+            f0_estimator: vocoder.f0_estimator.name(),
+            pitch_tracker: vocoder.voicing.name(),
+            spectral_estimator: vocoder.spectral_resolver.name().to_string(),
+            aperiodicity_estimator: vocoder.aperiodicity_estimator.name().to_string(),
+            compression: compression.name(),
+            source_len: samples.len(),
+            source_hash: fingerprint(samples),
+            analysis_plugins: options.plugin_chain.clone(),
+        }
+    }
+}
+
+/// Hash of the decoded source audio, so a re-recorded sample invalidates its cache.
+pub fn fingerprint(samples: &[f64]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    for &s in samples {
+        hasher.write(&s.to_bits().to_le_bytes());
+    }
+    hasher.finish()
+}
+
+pub fn get_analysis_path<P: AsRef<Path>>(source: P) -> PathBuf {
+    let path = source.as_ref();
+    let mut analysis = path.to_path_buf();
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".axxf");
+    analysis.set_file_name(name);
+    analysis
+}
+
+/// Runs the full STYDL analysis chain on `input_samples`, calling the
+/// analysis hooks of `plugins` along the way.
+pub fn analyze(
+    vocoder: &StydlVocoder,
+    input_samples: &[f64],
+    options: &AnalysisOptions,
+    plugins: &mut [&mut dyn AxisPlugin],
+) -> Result<AxisFeatures> {
+    let sample_rate = vocoder.sample_rate;
+    let fft_size = vocoder.fft_size;
+
+    let frames = vocoder.frame_period;
+
+    let mut processed;
+    let input_samples = if plugins.is_empty() {
+        input_samples
+    } else {
+        processed = input_samples.to_vec();
+        for plugin in plugins.iter_mut() {
+            plugin.pre_analysis(&mut processed, sample_rate)
+                .with_context(|| format!("Plugin {} failed in pre_analysis", plugin.metadata().name))?;
+        }
+        &processed[..]
+    };
+
+    // 1. F0 Estimation (raw). In candidate mode the raw track is the most confident candidate of each frame.
+    let candidates = match vocoder.voicing.mode {
+        HmmMode::Voicing => None,
+        HmmMode::Candidates => Some(vocoder.f0_estimator.candidates(input_samples, &frames)),
+    };
+    let raw_f0 = match &candidates {
+        Some(candidates) => candidates.iter().map(|c| c.first().map_or(0.0, |c| c.f0)).collect(),
+        None => vocoder.f0_estimator.estimate(input_samples, &frames),
+    };
+    let mut f0 = raw_f0.clone();
+    for plugin in plugins.iter_mut() {
+        plugin.override_f0(input_samples, sample_rate, frames.period_ms, &mut f0)
+            .with_context(|| format!("Plugin {} failed in override_f0", plugin.metadata().name))?;
+    }
+    // A track replaced by a plugin is smoothed like a raw one below instead of decoding the candidates
+    let decoded = match candidates {
+        Some(candidates) if f0 == raw_f0 => {
+            f0 = vocoder.voicing.track_candidates(&candidates);
+            true
+        }
+        _ => false,
+    };
+
+    let voicing = vocoder.voicing.posteriors(&f0);
+    // Frames that may be partly voiced are measured at the pitch of the nearest voiced frame
+    let ap_f0 = hold_f0(&f0, &voicing, vocoder.voicing.params().f0_threshold);
+
+    // 2. Spectral & Aperiodicity Estimation (uses raw F0), frames in parallel
+    let (mut spec, mut ap): (Vec<_>, Vec<_>) = crate::util::parallel_map(f0.len(), options.threads, |i| {
+        let chunk = frames.chunk(input_samples, i, fft_size);
+        (
+            vocoder.spectral_resolver.resolve(&chunk, f0[i], fft_size),
+            vocoder.aperiodicity_estimator.estimate(&chunk, ap_f0[i], fft_size),
+        )
+    })
+    .into_iter()
+    .unzip();
+
+    // 3. HMM F0 Smoothing (Viterbi V/UV + median filter) — applied AFTER analysis, candidate paths are already decoded
+    if !decoded {
+        f0 = vocoder.voicing.smooth_f0(&f0);
+        info!("HMM smoothed F0: {} frames", f0.len());
+    }
+
+    for plugin in plugins.iter_mut() {
+        plugin.post_analysis(&mut f0, &mut spec, &mut ap, sample_rate)
+            .with_context(|| format!("Plugin {} failed in post_analysis", plugin.metadata().name))?;
+    }
+
+    let f0_threshold = vocoder.voicing.params().f0_threshold;
+    let mut voiced_f0: Vec<f64> = f0.iter().cloned().filter(|&f| f >= f0_threshold).collect();
+    voiced_f0.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let source_base_hz = if voiced_f0.is_empty() { 261.63 } else { voiced_f0[voiced_f0.len() / 2] };
+
+    info!("Analysis complete. Frames: {}, FFT size: {}, Median F0: {:.2}Hz", f0.len(), fft_size, source_base_hz);
+
+    Ok(AxisFeatures { f0, spec, ap, voicing, source_base_hz, fft_size, f0_threshold })
+}
+
+/// `f0` with every frame that is at least `VOICING_FLOOR` voiced but has no
+/// pitch of its own given the pitch of the nearest frame that has one.
+fn hold_f0(f0: &[f64], voicing: &[f64], threshold: f64) -> Vec<f64> {
+    let detected: Vec<usize> = (0..f0.len()).filter(|&i| f0[i] >= threshold).collect();
+    (0..f0.len())
+        .map(|i| {
+            if f0[i] >= threshold || voicing[i] < VOICING_FLOOR || detected.is_empty() {
+                return f0[i];
+            }
+            let next = detected.partition_point(|&j| j < i);
+            let nearest = match (next.checked_sub(1).map(|p| detected[p]), detected.get(next)) {
+                (Some(p), Some(&n)) => if i - p <= n - i { p } else { n },
+                (Some(p), None) => p,
+                (None, Some(&n)) => n,
+                (None, None) => unreachable!(),
+            };
+            f0[nearest]
+        })
+        .collect()
+}
+
+/// Upper bound on the encoded size of an `AnalysisHeader`.
+const MAX_HEADER_LEN: u64 = 64 * 1024;
+
+/// Same encoding as `bincode::serialize`, but reads are capped at `limit`
+/// bytes so a corrupt length prefix cannot exhaust memory.
+fn bincode_options(limit: u64) -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+/// Reads the magic and format version of an `.axxf` file.
+fn read_version<R: Read>(reader: &mut R) -> Option<u32> {
+    let mut prefix = [0u8; 8];
+    reader.read_exact(&mut prefix).ok()?;
+    if prefix[..4] != AXXF_MAGIC {
+        return None;
+    }
+    Some(u32::from_le_bytes(prefix[4..].try_into().unwrap()))
+}
+
+/// Decodes the rest of a current-version header after `read_version`.
+fn read_header_body<R: Read>(reader: &mut R) -> Option<AnalysisHeader> {
+    // `version` is the header's first field, so put it back in front of the stream
+    let stream = std::io::Cursor::new(AXXF_VERSION.to_le_bytes()).chain(reader);
+    bincode_options(MAX_HEADER_LEN).deserialize_from(stream).ok()
+}
+
+/// Reads the header of an `.axxf` file, returning `None` when the file does
+/// not start with a readable AXXF header of the current version.
+pub fn read_header<R: Read>(reader: &mut R) -> Option<AnalysisHeader> {
+    if read_version(reader)? != AXXF_VERSION {
+        return None;
+    }
+    read_header_body(reader)
+}
+
+/// Reads an `.axxf` file, returning `None` when it is missing a valid header
+/// or was produced with different parameters than `expected`.
+pub fn read_features<P: AsRef<Path>>(path: P, expected: &AnalysisHeader) -> Result<Option<AxisFeatures>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    // Headers of other versions have a different layout, so only the version is read
+    let header = match read_version(&mut reader) {
+        Some(version) if version != expected.version => {
+            info!("{} is AXXF v{}, expected v{}", path.display(), version, expected.version);
+            return Ok(None);
+        }
+        Some(_) => read_header_body(&mut reader),
+        None => None,
+    };
+    let Some(header) = header else {
+        info!("{} has no readable AXXF header, ignoring", path.display());
+        return Ok(None);
+    };
+    if header != *expected {
+        info!("{} was analyzed with different parameters or source audio", path.display());
+        return Ok(None);
+    }
+
+    let features: StoredFeatures = bincode_options(file_len).deserialize_from(&mut reader)
+        .with_context(|| format!("Corrupt analysis data in {}", path.display()))?;
+    Ok(Some(features.into_features()))
+}
+
+pub fn write_features<P: AsRef<Path>>(path: P, header: &AnalysisHeader, features: &StoredFeatures) -> Result<()> {
+    let path = path.as_ref();
+    // Write next to the target and rename, so a concurrent reader never sees a partial file.
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    tmp_name.push(format!(".{}-{}.tmp", std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let tmp = path.with_file_name(tmp_name);
+
+    {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(&AXXF_MAGIC)?;
+        bincode::serialize_into(&mut writer, header)?;
+        bincode::serialize_into(&mut writer, features)?;
+        writer.flush()?;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Loads the cached analysis for `source` if it is still valid, otherwise
+/// analyzes `input_samples` and refreshes the cache. `plugins` must match
+/// `options.plugin_chain`.
+pub fn load_or_analyze(
+    source: &str,
+    input_samples: &[f64],
+    sample_rate: u32,
+    options: &AnalysisOptions,
+    plugins: &mut [&mut dyn AxisPlugin],
+) -> Result<AxisFeatures> {
+    let vocoder = options.vocoder(Path::new(source), sample_rate)?;
+
+    if !options.cache {
+        info!("Running STYDL analysis (in memory)...");
+        return analyze(&vocoder, input_samples, options, plugins);
+    }
+
+    let compression = options.compression()?;
+    let header = AnalysisHeader::new(&vocoder, input_samples, options, compression);
+    let analysis_path = options.analysis_path(Path::new(source), &header);
+
+    if analysis_path.exists() {
+        match read_features(&analysis_path, &header) {
+            Ok(Some(features)) => {
+                info!("Loading analysis data from {}", analysis_path.display());
+                if let Some(cache) = &options.central_cache {
+                    cache.touch(&analysis_path);
+                }
+                return Ok(features);
+            }
+            Ok(None) => info!("Re-analyzing stale {}", analysis_path.display()),
+            Err(e) => warn!("Failed to read {}: {:#}. Re-analyzing.", analysis_path.display(), e),
+        }
+    }
+
+    info!("Running STYDL analysis...");
+    let features = StoredFeatures::new(analyze(&vocoder, input_samples, options, plugins)?, compression, sample_rate);
+    if let Err(e) = store_features(&analysis_path, &header, &features, options) {
+        warn!("Failed to write analysis cache {}: {:#}", analysis_path.display(), e);
+    }
+    // What was stored, so the first render matches the ones read back from the cache
+    Ok(features.into_features())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnalysisOutcome {
+    Analyzed,
+    /// The existing `.axxf` already matches the source and parameters.
+    Skipped,
+}
+
+/// Writes an `.axxf` file, keeping the central cache within its size limit.
+fn store_features(path: &Path, header: &AnalysisHeader, features: &StoredFeatures, options: &AnalysisOptions) -> Result<()> {
+    let Some(cache) = &options.central_cache else {
+        return write_features(path, header, features);
+    };
+    std::fs::create_dir_all(&cache.dir)
+        .with_context(|| format!("Failed to create {}", cache.dir.display()))?;
+    write_features(path, header, features)?;
+    let evicted = cache.gc_keeping(path)?;
+    if evicted.files > 0 {
+        info!("Evicted {} least recently used analysis file(s) from {}", evicted.files, cache.dir.display());
+    }
+    Ok(())
+}
+
+/// Makes sure `source` has an up-to-date `.axxf`, next to it or in the central cache.
+pub fn ensure_analysis(
+    source: &Path,
+    force: bool,
+    options: &AnalysisOptions,
+    plugins: &mut [&mut dyn AxisPlugin],
+) -> Result<AnalysisOutcome> {
+    if !options.cache {
+        bail!("Analysis files are disabled (analysis-enabled=false), so renders would never read them");
+    }
+    let (samples, sample_rate) = options.load_source(source)?;
+    if samples.is_empty() {
+        bail!("No audio data");
+    }
+
+    let vocoder = options.vocoder(source, sample_rate)?;
+    let compression = options.compression()?;
+    let header = AnalysisHeader::new(&vocoder, &samples, options, compression);
+    let analysis_path = options.analysis_path(source, &header);
+
+    if !force {
+        if let Ok(file) = File::open(&analysis_path) {
+            if read_header(&mut BufReader::new(file)).as_ref() == Some(&header) {
+                if let Some(cache) = &options.central_cache {
+                    cache.touch(&analysis_path);
+                }
+                return Ok(AnalysisOutcome::Skipped);
+            }
+        }
+    }
+
+    let features = StoredFeatures::new(analyze(&vocoder, &samples, options, plugins)?, compression, sample_rate);
+    store_features(&analysis_path, &header, &features, options)?;
+    Ok(AnalysisOutcome::Analyzed)
+}
+
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub analyzed: usize,
+    pub skipped: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Recursively collects the `.wav` files under `dir`, sorted by path.
+pub fn find_samples(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)
+            .with_context(|| format!("Failed to read directory {}", current.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("wav")) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Analyzes every sample of a voicebank, one file per worker. Each entry of
+/// `workers` is the plugin set of one worker thread; `options.plugin_chain`
+/// must describe them. `on_progress` is called after each file with
+/// (done, total, path, outcome).
+pub fn analyze_voicebank<F>(
+    dir: &Path,
+    options: &AnalysisOptions,
+    force: bool,
+    workers: Vec<Vec<PluginLoader>>,
+    on_progress: F,
+) -> Result<BatchReport>
+where
+    F: Fn(usize, usize, &Path, &Result<AnalysisOutcome>) + Sync,
+{
+    if !options.cache {
+        bail!("Nothing to pre-analyze: analysis-enabled=false keeps analysis in memory only");
+    }
+    let samples = find_samples(dir)?;
+    let total = samples.len();
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let report = Mutex::new(BatchReport::default());
+    // Files are already spread across workers, so each file is analyzed single-threaded.
+    let per_file = AnalysisOptions { threads: 1, ..options.clone() };
+
+    std::thread::scope(|scope| {
+        for mut loaders in workers.into_iter().take(total.max(1)) {
+            let (samples, next, done, report, per_file, on_progress) = (&samples, &next, &done, &report, &per_file, &on_progress);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = samples.get(i) else { break };
+                let outcome = ensure_analysis(path, force, per_file, &mut hook_plugins(&mut loaders));
+                let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+                on_progress(n, total, path, &outcome);
+
+                let mut report = report.lock().unwrap();
+                match outcome {
+                    Ok(AnalysisOutcome::Analyzed) => report.analyzed += 1,
+                    Ok(AnalysisOutcome::Skipped) => report.skipped += 1,
+                    Err(e) => report.failed.push((path.clone(), format!("{:#}", e))),
+                }
+            });
+        }
+    });
+
+    let mut report = report.into_inner().unwrap();
+    report.failed.sort();
+    Ok(report)
+}
+
+#[derive(Debug)]
+pub struct TrainingReport {
+    pub params: VoicingHmmParams,
+    pub files: usize,
+    pub frames: u64,
+    /// Samples without a `.lab` file or that failed to load.
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+/// Fits `VoicingHmmParams` to the samples under `dir` that have a `.lab`
+/// file next to them, comparing each sample's labelled voicing with the raw
+/// F0 of the estimator configured for it. Frames outside every label count
+/// as unvoiced. Emissions only mean something for one estimator and F0
+/// threshold, so all samples must resolve to the same ones.
+pub fn train_voicing_hmm(dir: &Path, options: &AnalysisOptions) -> Result<TrainingReport> {
+    let samples = find_samples(dir)?;
+    let results = crate::util::parallel_map(samples.len(), options.threads, |i| -> Result<(Vec<VoicingState>, Vec<f64>, String, f64)> {
+        let path = &samples[i];
+        let lab_path = path.with_extension("lab");
+        if !lab_path.exists() {
+            bail!("no {}", lab_path.file_name().unwrap_or_default().to_string_lossy());
+        }
+        let labels = lab::read_lab(&lab_path)?;
+        let (audio, sample_rate) = options.load_source(path)?;
+        let vocoder = options.vocoder(path, sample_rate)?;
+        let f0 = vocoder.f0_estimator.estimate(&audio, &vocoder.frame_period);
+        let voicing = (0..f0.len())
+            .map(|frame| {
+                let time = vocoder.frame_period.center(frame) / sample_rate as f64;
+                match lab::label_at(&labels, time) {
+                    Some(label) if label.is_voiced() => VoicingState::Voiced,
+                    _ => VoicingState::Unvoiced,
+                }
+            })
+            .collect();
+        Ok((voicing, f0, vocoder.f0_estimator.name(), vocoder.voicing.params().f0_threshold))
+    });
+
+    let mut trainer = HmmTrainer::default();
+    let mut skipped = Vec::new();
+    // The estimator and threshold of the first trained sample, which the others must match
+    let mut reference: Option<(PathBuf, String, f64)> = None;
+    let mut files = 0;
+    for (path, result) in samples.into_iter().zip(results) {
+        match result {
+            Ok((voicing, f0, estimator, f0_threshold)) => {
+                if let Some((first, first_estimator, first_threshold)) = &reference {
+                    if *first_estimator != estimator || *first_threshold != f0_threshold {
+                        bail!(
+                            "{} is analyzed with {} and f0-threshold={}, but {} with {} and f0-threshold={}; train each voicebank separately",
+                            first.display(), first_estimator, first_threshold, path.display(), estimator, f0_threshold
+                        );
+                    }
+                }
+                trainer.add(&voicing, &f0, f0_threshold);
+                reference.get_or_insert((path, estimator, f0_threshold));
+                files += 1;
+            }
+            Err(e) => skipped.push((path, format!("{:#}", e))),
+        }
+    }
+    let Some((_, _, threshold)) = reference else {
+        bail!("No labelled samples found in {}", dir.display());
+    };
+    Ok(TrainingReport { params: trainer.params(threshold), files, frames: trainer.frames(), skipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scratch directory for one test, removed again even when an assert fails.
+    struct TempDir(PathBuf);
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn temp_dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("axis_{}_{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// `len` samples of a sine at `freq` Hz and half scale.
+    fn tone(freq: f64, sample_rate: u32, len: usize) -> Vec<f64> {
+        (0..len).map(|i| (2.0 * std::f64::consts::PI * freq * i as f64 / sample_rate as f64).sin() * 0.5).collect()
+    }
+
+    fn sample_header() -> AnalysisHeader {
+        let vocoder = StydlVocoder::new(44100, 4096);
+        AnalysisHeader::new(&vocoder, &[0.0, 0.5, -0.5], &AnalysisOptions::default(), CacheCompression::None)
+    }
+
+    fn sample_features() -> AxisFeatures {
+        AxisFeatures {
+            f0: vec![0.0, 220.0],
+            spec: vec![vec![1.0; 3]; 2],
+            ap: vec![vec![0.5; 3]; 2],
+            voicing: vec![0.0, 1.0],
+            source_base_hz: 220.0,
+            fft_size: 4,
+            f0_threshold: 40.0,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_and_stale_header() {
+        let dir = temp_dir("roundtrip");
+        let path = dir.join("a.axxf");
+        let header = sample_header();
+        write_features(&path, &header, &StoredFeatures::Full(sample_features())).unwrap();
+
+        let loaded = read_features(&path, &header).unwrap().expect("matching header should load");
+        assert_eq!(loaded.f0, vec![0.0, 220.0]);
+
+        let mut changed = header.clone();
+        changed.source_hash ^= 1;
+        assert!(read_features(&path, &changed).unwrap().is_none());
+
+        std::fs::write(&path, b"legacy bincode payload").unwrap();
+        assert!(read_features(&path, &header).unwrap().is_none());
+
+        // An older version whose header layout would misparse as the current one
+        let mut old = AXXF_MAGIC.to_vec();
+        old.extend_from_slice(&1u32.to_le_bytes());
+        old.extend_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &old).unwrap();
+        assert!(read_features(&path, &header).unwrap().is_none());
+
+        // A corrupt length prefix in the features must not be trusted
+        let mut corrupt = Vec::new();
+        corrupt.extend_from_slice(&AXXF_MAGIC);
+        bincode::serialize_into(&mut corrupt, &header).unwrap();
+        corrupt.extend_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(read_features(&path, &header).is_err());
+
+        // Likewise in the header: the length of `f0_estimator` follows the fixed-size fields
+        let mut corrupt = AXXF_MAGIC.to_vec();
+        bincode::serialize_into(&mut corrupt, &header).unwrap();
+        assert_eq!(corrupt[36..44], (header.f0_estimator.len() as u64).to_le_bytes());
+        corrupt[36..44].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read_header(&mut corrupt.as_slice()).is_none());
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        // Server workers in one process can analyze the same sample at once
+        let dir = temp_dir("concurrent");
+        let path = dir.join("a.axxf");
+        let header = sample_header();
+        let features = StoredFeatures::Full(sample_features());
+        std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..8).map(|_| scope.spawn(|| write_features(&path, &header, &features))).collect();
+            for writer in writers {
+                writer.join().unwrap().unwrap();
+            }
+        });
+        assert!(read_features(&path, &header).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_analyze_voicebank() {
+        let dir = temp_dir("voicebank");
+        std::fs::create_dir_all(dir.join("append")).unwrap();
+        let samples = tone(220.0, 16000, 4000);
+        for name in ["a.wav", "append/ka.WAV"] {
+            audio::save_audio(dir.join(name), &samples, 16000, audio::OutputFormat::WavFloat, &Default::default(), 0).unwrap();
+        }
+        std::fs::write(dir.join("append/readme.txt"), "not a sample").unwrap();
+        std::fs::write(dir.join("broken.wav"), "not a wav").unwrap();
+        assert_eq!(find_samples(&dir).unwrap(), [dir.join("a.wav"), dir.join("append/ka.WAV"), dir.join("broken.wav")]);
+
+        let run = |force: bool| analyze_voicebank(&dir, &AnalysisOptions::default(), force, vec![Vec::new(), Vec::new()], |_, _, _, _| {}).unwrap();
+        let first = run(false);
+        assert_eq!((first.analyzed, first.skipped), (2, 0));
+        assert_eq!(first.failed.len(), 1);
+        assert_eq!(first.failed[0].0, dir.join("broken.wav"));
+        assert!(get_analysis_path(dir.join("append/ka.WAV")).exists());
+
+        let fresh = run(false);
+        assert_eq!((fresh.analyzed, fresh.skipped), (0, 2));
+        let forced = run(true);
+        assert_eq!((forced.analyzed, forced.skipped), (2, 0));
+    }
+
+    #[test]
+    fn test_in_memory_analysis() {
+        // With analysis-enabled=false renders never read `.axxf` files, so none are written
+        let dir = temp_dir("in_memory");
+        let options = AnalysisOptions { cache: false, ..AnalysisOptions::default() };
+        let samples = tone(220.0, 16000, 4000);
+        let source = dir.join("a.wav");
+        let features = load_or_analyze(source.to_str().unwrap(), &samples, 16000, &options, &mut []).unwrap();
+        assert!(!features.f0.is_empty());
+        assert!(!get_analysis_path(&source).exists());
+
+        audio::save_audio(&source, &samples, 16000, audio::OutputFormat::WavFloat, &Default::default(), 0).unwrap();
+        assert!(analyze_voicebank(&dir, &options, false, vec![Vec::new()], |_, _, _, _| {}).is_err());
+        assert!(ensure_analysis(&source, false, &options, &mut []).is_err());
+        assert!(!get_analysis_path(&source).exists());
+    }
+
+    #[test]
+    fn test_train_voicing_hmm() {
+        let dir = temp_dir("train");
+        let (soft, breathy) = (dir.join("soft"), dir.join("breathy"));
+        std::fs::create_dir_all(&soft).unwrap();
+        std::fs::create_dir_all(&breathy).unwrap();
+        // Silence, a 200 Hz vowel from 0.3 s to 0.8 s, silence
+        let mut sample = tone(200.0, 16000, 16000);
+        sample[..4800].fill(0.0);
+        sample[12800..].fill(0.0);
+        for path in [soft.join("a.wav"), soft.join("i.wav"), soft.join("unlabelled.wav"), breathy.join("u.wav")] {
+            audio::save_audio(&path, &sample, 16000, audio::OutputFormat::WavFloat, &Default::default(), 0).unwrap();
+            if !path.ends_with("unlabelled.wav") {
+                std::fs::write(path.with_extension("lab"), "0 3000000 sil\n3000000 8000000 a\n8000000 10000000 sil\n").unwrap();
+            }
+        }
+
+        let report = train_voicing_hmm(&soft, &AnalysisOptions::default()).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].0, soft.join("unlabelled.wav"));
+        let params = report.params;
+        assert!(params.p_vv > 0.9 && params.p_uu > 0.9, "{:?}", params);
+        // The vowel is detected and the silences are not
+        assert!(params.voiced_detected > params.voiced_missing, "{:?}", params);
+        assert!(params.unvoiced_missing > params.unvoiced_detected, "{:?}", params);
+        params.validate().unwrap();
+
+        // One voicebank counts F0 against another threshold, so the two cannot be pooled
+        let config: AxisConfig = knuffel::parse("config.kdl", &format!(
+            "analysis {{\n voicebank {:?} {{\n hmm f0-threshold=30.0\n }}\n}}",
+            breathy.to_string_lossy()
+        )).unwrap();
+        let options = AnalysisOptions::from_config(&config).unwrap();
+        assert_eq!(train_voicing_hmm(&breathy, &options).unwrap().params.f0_threshold, 30.0);
+        let error = train_voicing_hmm(&dir, &options).unwrap_err();
+        assert!(format!("{:#}", error).contains("train each voicebank separately"), "{:#}", error);
+    }
+
+    #[test]
+    fn test_compressed_cache() {
+        let dir = temp_dir("compressed");
+        let samples = tone(220.0, 44100, 8820);
+        let analyze_with = |compression: &str| {
+            let source = dir.join(format!("{}.wav", compression));
+            let options = AnalysisOptions { compression: Some(compression.to_string()), ..AnalysisOptions::default() };
+            let first = load_or_analyze(source.to_str().unwrap(), &samples, 44100, &options, &mut []).unwrap();
+            let cached = load_or_analyze(source.to_str().unwrap(), &samples, 44100, &options, &mut []).unwrap();
+            (first, cached, std::fs::metadata(get_analysis_path(&source)).unwrap().len())
+        };
+
+        let (full, _, full_size) = analyze_with("none");
+        let (first, cached, coded_size) = analyze_with("mcep");
+        assert!(coded_size * 20 < full_size, "{} vs {} bytes", coded_size, full_size);
+        // The first render already uses the coded features
+        assert_eq!(first.spec, cached.spec);
+        assert_eq!(first.f0, full.f0);
+        assert_eq!(first.spec[0].len(), full.spec[0].len());
+
+        let options = AnalysisOptions { compression: Some("zip".into()), ..AnalysisOptions::default() };
+        assert!(load_or_analyze(dir.join("a.wav").to_str().unwrap(), &samples, 44100, &options, &mut []).is_err());
+    }
+
+    #[test]
+    fn test_central_cache() {
+        let dir = temp_dir("central");
+        let cache = AnalysisCache::new(dir.join("cache"), 1 << 30);
+        let options = AnalysisOptions { central_cache: Some(cache.clone()), ..AnalysisOptions::default() };
+        let samples = tone(220.0, 44100, 2205);
+
+        // The same audio in two voicebanks shares one entry, and nothing is written next to it
+        for bank in ["bank_a", "bank_b"] {
+            let source = dir.join(bank).join("a.wav");
+            load_or_analyze(source.to_str().unwrap(), &samples, 44100, &options, &mut []).unwrap();
+            assert!(!get_analysis_path(&source).exists());
+        }
+        assert_eq!(cache.stats().unwrap().files, 1);
+
+        let louder: Vec<f64> = samples.iter().map(|s| s * 1.5).collect();
+        load_or_analyze(dir.join("bank_a/b.wav").to_str().unwrap(), &louder, 44100, &options, &mut []).unwrap();
+        assert_eq!(cache.stats().unwrap().files, 2);
+
+        // A limit below one entry keeps only the newest
+        let small = AnalysisOptions { central_cache: Some(AnalysisCache::new(cache.dir.clone(), 1)), ..options };
+        load_or_analyze(dir.join("bank_a/c.wav").to_str().unwrap(), &samples[..1000], 44100, &small, &mut []).unwrap();
+        assert_eq!(cache.stats().unwrap().files, 1);
+    }
+
+    struct FixedPitch {
+        pre_calls: usize,
+    }
+
+    impl AxisPlugin for FixedPitch {
+        fn metadata(&self) -> crate::api::PluginMetadata {
+            crate::api::PluginMetadata {
+                name: "FixedPitch".into(),
+                version: "1.0.0".into(),
+                author: "test".into(),
+                description: "".into(),
+            }
+        }
+
+        fn pre_analysis(&mut self, samples: &mut [f64], _sample_rate: u32) -> Result<()> {
+            self.pre_calls += 1;
+            samples.iter_mut().for_each(|s| *s *= 0.5);
+            Ok(())
+        }
+
+        fn override_f0(&mut self, _samples: &[f64], _sample_rate: u32, frame_period: f64, f0: &mut [f64]) -> Result<()> {
+            assert_eq!(frame_period, FRAME_PERIOD);
+            f0.iter_mut().for_each(|f| *f = 150.0);
+            Ok(())
+        }
+
+        fn post_analysis(&mut self, _f0: &mut [f64], _spectral: &mut [Vec<f64>], aperiodicity: &mut [Vec<f64>], _sample_rate: u32) -> Result<()> {
+            aperiodicity.iter_mut().flatten().for_each(|a| *a = 0.25);
+            Ok(())
+        }
+    }
+
+    /// Records the F0 track `override_f0` is given, leaving it unchanged.
+    struct RecordF0 {
+        seen: Vec<f64>,
+    }
+
+    impl AxisPlugin for RecordF0 {
+        fn metadata(&self) -> crate::api::PluginMetadata {
+            crate::api::PluginMetadata {
+                name: "RecordF0".into(),
+                version: "1.0.0".into(),
+                author: "test".into(),
+                description: "".into(),
+            }
+        }
+
+        fn override_f0(&mut self, _samples: &[f64], _sample_rate: u32, _frame_period: f64, f0: &mut [f64]) -> Result<()> {
+            self.seen = f0.to_vec();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_analysis_hooks() {
+        let sample_rate = 16000;
+        let samples = tone(220.0, sample_rate, sample_rate as usize / 4);
+        let vocoder = StydlVocoder::new(sample_rate, 1024);
+        let mut plugin = FixedPitch { pre_calls: 0 };
+
+        let features = analyze(&vocoder, &samples, &AnalysisOptions::default(), &mut [&mut plugin]).unwrap();
+        assert_eq!(plugin.pre_calls, 1);
+        assert!(features.f0.iter().all(|&f| f == 150.0), "{:?}", features.f0);
+        assert_eq!(features.source_base_hz, 150.0);
+        assert!(features.ap.iter().flatten().all(|&a| a == 0.25));
+
+        // In candidate mode the hook sees the raw estimate before the candidates are decoded...
+        let vocoder = vocoder.with_voicing(VoicingHmm::new().with_mode(HmmMode::Candidates));
+        let mut recorder = RecordF0 { seen: Vec::new() };
+        let features = analyze(&vocoder, &samples, &AnalysisOptions::default(), &mut [&mut recorder]).unwrap();
+        let candidates = vocoder.f0_estimator.candidates(&samples, &vocoder.frame_period);
+        let best: Vec<f64> = candidates.iter().map(|c| c.first().map_or(0.0, |c| c.f0)).collect();
+        let decoded = vocoder.voicing.track_candidates(&candidates);
+        assert_ne!(best, decoded);
+        assert_eq!(recorder.seen, best);
+        assert_eq!(features.f0, decoded);
+
+        // ...and a track it replaces goes through voicing smoothing instead
+        let mut plugin = FixedPitch { pre_calls: 0 };
+        let features = analyze(&vocoder, &samples, &AnalysisOptions::default(), &mut [&mut plugin]).unwrap();
+        assert!(features.f0.iter().all(|&f| f == 150.0), "{:?}", features.f0);
+    }
+}
@@ -1,10 +1,22 @@
+use crate::api::plugin::PluginSettings;
+use crate::audio::ChannelMode;
+use crate::rate::{RateConversion, MAX_OUTPUT_RATE, MIN_OUTPUT_RATE};
+use crate::vocoder::dio::Dio;
+use crate::vocoder::f0::F0Estimator;
+use crate::vocoder::hmm::{HmmMode, VoicingHmmParams};
+use crate::vocoder::pyin::Pyin;
+use crate::vocoder::trickresolve::SpectralMethod;
 use knuffel::Decode;
+use miette::Diagnostic;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
 #[derive(Decode, Debug, Clone, Serialize, Deserialize)]
 pub struct AxisConfig {
     #[knuffel(child)]
     pub general: Option<GeneralConfig>,
+    #[knuffel(child)]
+    pub analysis: Option<AnalysisConfig>,
     #[knuffel(children(name = "plugin"))]
     pub plugins: Vec<PluginConfig>,
 }
@@ -19,21 +31,112 @@ pub struct GeneralConfig {
     pub log: Option<bool>,
     #[knuffel(property)]
     pub stydl: Option<bool>,
+    /// When set, note invocations forward their arguments to `axis serve` on this port.
+    #[knuffel(property)]
+    pub server_port: Option<u16>,
+    /// Fixed synthesis seed for every note instead of one derived from the note's arguments.
+    #[knuffel(property)]
+    pub seed: Option<u64>,
+    /// `"none"` (default) or `"mcep"` to store `.axxf` features coded, see `CacheCompression`.
+    #[knuffel(property)]
+    pub cache_compression: Option<String>,
+    /// Store analysis in one content-addressed cache directory instead of next to each sample.
+    #[knuffel(property)]
+    pub central_cache: Option<bool>,
+    /// Location of the central cache; defaults to `analysis-cache` in the data directory.
+    #[knuffel(property)]
+    pub cache_dir: Option<String>,
+    /// Size limit of the central cache in MB; least recently used entries are evicted past it.
+    #[knuffel(property)]
+    pub cache_size_mb: Option<u64>,
+    /// `"wav16"`, `"wav24"`, `"wav32f"`, `"flac16"` or `"flac24"`; by default picked from the output extension.
+    #[knuffel(property)]
+    pub output_format: Option<String>,
+    /// Sample rate of rendered notes in Hz; by default each sample's own rate.
+    #[knuffel(property)]
+    pub output_rate: Option<u32>,
+    /// `"after-synthesis"` (default) or `"before-analysis"`, see `RateConversion`.
+    #[knuffel(property)]
+    pub rate_conversion: Option<String>,
+}
+
+/// `analysis f0-estimator="pyin" f0-floor=60 f0-ceiling=1100 { voicebank "path" ... }`, frequencies in whole Hz.
+#[derive(Decode, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AnalysisConfig {
+    #[knuffel(property)]
+    pub f0_estimator: Option<String>,
+    #[knuffel(property)]
+    pub f0_floor: Option<u32>,
+    #[knuffel(property)]
+    pub f0_ceiling: Option<u32>,
+    #[knuffel(property)]
+    pub hmm_mode: Option<String>,
+    #[knuffel(property)]
+    pub spectral_estimator: Option<String>,
+    #[knuffel(property)]
+    pub channel_mode: Option<String>,
+    #[knuffel(child)]
+    pub hmm: Option<HmmConfig>,
+    #[knuffel(children(name = "voicebank"))]
+    pub voicebanks: Vec<VoicebankAnalysisConfig>,
+}
+
+/// Overrides for the samples under one voicebank directory.
+#[derive(Decode, Debug, Clone, Serialize, Deserialize)]
+pub struct VoicebankAnalysisConfig {
+    #[knuffel(argument)]
+    pub path: String,
+    #[knuffel(property)]
+    pub f0_estimator: Option<String>,
+    #[knuffel(property)]
+    pub f0_floor: Option<u32>,
+    #[knuffel(property)]
+    pub f0_ceiling: Option<u32>,
+    #[knuffel(property)]
+    pub hmm_mode: Option<String>,
+    #[knuffel(property)]
+    pub spectral_estimator: Option<String>,
+    #[knuffel(property)]
+    pub channel_mode: Option<String>,
+    #[knuffel(child)]
+    pub hmm: Option<HmmConfig>,
+}
+
+/// `hmm p-vv=0.95 ...`: `VoicingHmmParams` overrides, as written by `axis hmm train`.
+/// Values must be written as decimals (`40.0`, not `40`).
+#[derive(Decode, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HmmConfig {
+    #[knuffel(property)]
+    pub p_vv: Option<f64>,
+    #[knuffel(property)]
+    pub p_uu: Option<f64>,
+    #[knuffel(property)]
+    pub voiced_detected: Option<f64>,
+    #[knuffel(property)]
+    pub voiced_missing: Option<f64>,
+    #[knuffel(property)]
+    pub unvoiced_detected: Option<f64>,
+    #[knuffel(property)]
+    pub unvoiced_missing: Option<f64>,
+    #[knuffel(property)]
+    pub f0_threshold: Option<f64>,
 }
 
 #[derive(Decode, Debug, Clone, Serialize, Deserialize)]
 pub struct PluginConfig {
     #[knuffel(argument)]
     pub name: String,
+    /// Overrides the enabled flag stored in `plugin.db` when set.
     #[knuffel(property)]
-    pub enabled: bool,
+    pub enabled: Option<bool>,
     #[knuffel(children)]
     pub settings: Vec<Setting>,
 }
 
 #[derive(Decode, Debug, Clone, Serialize, Deserialize)]
 pub struct Setting {
-    #[knuffel(argument)]
+    /// The node name, so settings are written as `key "value"`.
+    #[knuffel(node_name)]
     pub key: String,
     #[knuffel(argument)]
     pub value: String,
@@ -42,8 +145,187 @@ pub struct Setting {
 impl AxisConfig {
     pub fn load<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)?;
-        let config = knuffel::parse("config.kdl", &content)?;
-        Ok(config)
+        Self::parse(&content)
+    }
+
+    /// Parses `config.kdl` source, reporting every error with its line.
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        knuffel::parse("config.kdl", content).map_err(|e| {
+            let errors: Vec<String> = e
+                .related()
+                .into_iter()
+                .flatten()
+                .map(|d| {
+                    let offset = d.labels().and_then(|mut l| l.next()).map_or(0, |l| l.offset());
+                    let line = content[..offset.min(content.len())].matches('\n').count() + 1;
+                    format!("line {}: {}", line, d)
+                })
+                .collect();
+            anyhow::anyhow!("{}", errors.join("; "))
+        })
+    }
+
+    /// The `plugin "name"` block for `name`, if any.
+    pub fn plugin(&self, name: &str) -> Option<&PluginConfig> {
+        self.plugins.iter().find(|p| p.name == name)
+    }
+
+    /// Worker threads for per-frame analysis and synthesis; `threads=0` means one per CPU.
+    pub fn threads(&self) -> usize {
+        crate::util::thread_count(self.general.as_ref().and_then(|g| g.threads))
+    }
+
+    /// Whether analysis results are cached in `.axxf` files.
+    pub fn analysis_enabled(&self) -> bool {
+        self.general.as_ref().and_then(|g| g.analysis_enabled).unwrap_or(true)
+    }
+
+    /// Whether to synthesize with the STYDL engine rather than the pulse vocoder.
+    pub fn stydl(&self) -> bool {
+        self.general.as_ref().and_then(|g| g.stydl).unwrap_or(true)
+    }
+
+    /// Whether analysis goes to the central cache rather than next to each sample.
+    pub fn central_cache(&self) -> bool {
+        self.general.as_ref().and_then(|g| g.central_cache).unwrap_or(false)
+    }
+
+    /// How `.axxf` features are stored, unparsed; see `AnalysisOptions::compression`.
+    pub fn cache_compression(&self) -> Option<String> {
+        self.general.as_ref().and_then(|g| g.cache_compression.clone())
+    }
+
+    /// Format of rendered notes, unparsed; see `OutputFormat::resolve`.
+    pub fn output_format(&self) -> Option<String> {
+        self.general.as_ref().and_then(|g| g.output_format.clone())
+    }
+
+    /// Rate of rendered notes, `None` to keep each sample's own rate.
+    pub fn output_rate(&self) -> anyhow::Result<Option<u32>> {
+        match self.general.as_ref().and_then(|g| g.output_rate) {
+            Some(rate) if !(MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE).contains(&rate) => {
+                anyhow::bail!("Invalid output-rate {} (expected {} to {} Hz)", rate, MIN_OUTPUT_RATE, MAX_OUTPUT_RATE)
+            }
+            rate => Ok(rate),
+        }
+    }
+
+    /// When `output-rate` is applied.
+    pub fn rate_conversion(&self) -> anyhow::Result<RateConversion> {
+        self.general
+            .as_ref()
+            .and_then(|g| g.rate_conversion.as_deref())
+            .map_or(Ok(RateConversion::default()), str::parse)
+    }
+
+    /// Synthesis seed from `config.kdl`; the `S` flag takes precedence.
+    pub fn seed(&self) -> Option<u64> {
+        self.general.as_ref().and_then(|g| g.seed)
+    }
+}
+
+/// Analysis settings for one sample, as resolved from the `analysis` node
+/// of `config.kdl` and the voicebank containing the sample.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnalysisSettings {
+    /// `"dio"` (default) or `"pyin"`.
+    pub estimator: Option<String>,
+    pub floor: Option<f64>,
+    pub ceiling: Option<f64>,
+    /// `"voicing"` (default) or `"candidates"`, see `HmmMode`.
+    pub hmm_mode: Option<String>,
+    pub hmm: VoicingHmmParams,
+    /// `"trickresolve"` (default) or `"cheaptrick"`, see `SpectralMethod`.
+    pub spectral_estimator: Option<String>,
+    /// How stereo sources are downmixed, see `ChannelMode`.
+    pub channel_mode: Option<String>,
+}
+
+impl AnalysisSettings {
+    pub fn hmm_mode(&self) -> anyhow::Result<HmmMode> {
+        self.hmm_mode.as_deref().map_or(Ok(HmmMode::default()), str::parse)
+    }
+
+    pub fn spectral_method(&self) -> anyhow::Result<SpectralMethod> {
+        self.spectral_estimator.as_deref().map_or(Ok(SpectralMethod::default()), str::parse)
+    }
+
+    pub fn channel_mode(&self) -> anyhow::Result<ChannelMode> {
+        self.channel_mode.as_deref().map_or(Ok(ChannelMode::default()), str::parse)
+    }
+
+    pub fn f0_estimator(&self, sample_rate: u32) -> anyhow::Result<Box<dyn F0Estimator>> {
+        let name = self.estimator.as_deref().unwrap_or("dio");
+        let (default_floor, default_ceiling) = match name {
+            "dio" => Dio::DEFAULT_RANGE,
+            "pyin" => Pyin::DEFAULT_RANGE,
+            other => anyhow::bail!("Unknown F0 estimator \"{}\" (expected \"dio\" or \"pyin\")", other),
+        };
+        let floor = self.floor.unwrap_or(default_floor);
+        let ceiling = self.ceiling.unwrap_or(default_ceiling);
+        if !(floor >= 40.0 && floor < ceiling && ceiling < sample_rate as f64 / 4.0) {
+            anyhow::bail!("Invalid F0 range {}-{} Hz for {} Hz audio", floor, ceiling, sample_rate);
+        }
+        Ok(match name {
+            "pyin" => Box::new(Pyin::new(sample_rate).with_range(floor, ceiling)),
+            _ => Box::new(Dio::new(sample_rate).with_range(floor, ceiling)),
+        })
+    }
+}
+
+impl AnalysisConfig {
+    /// Settings for `sample`, with the overrides of the innermost voicebank containing it.
+    pub fn settings(&self, sample: &Path) -> AnalysisSettings {
+        let sample = std::fs::canonicalize(sample).unwrap_or_else(|_| sample.to_path_buf());
+        let voicebank = self
+            .voicebanks
+            .iter()
+            .map(|v| (v, std::fs::canonicalize(&v.path).unwrap_or_else(|_| PathBuf::from(&v.path))))
+            .filter(|(_, dir)| sample.starts_with(dir))
+            .max_by_key(|(_, dir)| dir.components().count())
+            .map(|(v, _)| v);
+        AnalysisSettings {
+            estimator: voicebank.and_then(|v| v.f0_estimator.clone()).or_else(|| self.f0_estimator.clone()),
+            floor: voicebank.and_then(|v| v.f0_floor).or(self.f0_floor).map(f64::from),
+            ceiling: voicebank.and_then(|v| v.f0_ceiling).or(self.f0_ceiling).map(f64::from),
+            hmm_mode: voicebank.and_then(|v| v.hmm_mode.clone()).or_else(|| self.hmm_mode.clone()),
+            spectral_estimator: voicebank.and_then(|v| v.spectral_estimator.clone()).or_else(|| self.spectral_estimator.clone()),
+            channel_mode: voicebank.and_then(|v| v.channel_mode.clone()).or_else(|| self.channel_mode.clone()),
+            hmm: [self.hmm.as_ref(), voicebank.and_then(|v| v.hmm.as_ref())]
+                .into_iter()
+                .flatten()
+                .fold(VoicingHmmParams::default(), |params, hmm| hmm.apply(params)),
+        }
+    }
+}
+
+impl HmmConfig {
+    /// `params` with the values set in this node replaced.
+    pub fn apply(&self, params: VoicingHmmParams) -> VoicingHmmParams {
+        VoicingHmmParams {
+            p_vv: self.p_vv.unwrap_or(params.p_vv),
+            p_uu: self.p_uu.unwrap_or(params.p_uu),
+            voiced_detected: self.voiced_detected.unwrap_or(params.voiced_detected),
+            voiced_missing: self.voiced_missing.unwrap_or(params.voiced_missing),
+            unvoiced_detected: self.unvoiced_detected.unwrap_or(params.unvoiced_detected),
+            unvoiced_missing: self.unvoiced_missing.unwrap_or(params.unvoiced_missing),
+            f0_threshold: self.f0_threshold.unwrap_or(params.f0_threshold),
+        }
+    }
+
+    /// The `hmm` node setting every parameter, in `config.kdl` syntax.
+    pub fn to_kdl(params: &VoicingHmmParams) -> String {
+        format!(
+            "hmm p-vv={:?} p-uu={:?} voiced-detected={:?} voiced-missing={:?} unvoiced-detected={:?} unvoiced-missing={:?} f0-threshold={:?}",
+            params.p_vv, params.p_uu, params.voiced_detected, params.voiced_missing,
+            params.unvoiced_detected, params.unvoiced_missing, params.f0_threshold,
+        )
+    }
+}
+
+impl PluginConfig {
+    pub fn plugin_settings(&self) -> PluginSettings {
+        PluginSettings::new(self.settings.iter().map(|s| (s.key.clone(), s.value.clone())))
     }
 }
 
@@ -55,8 +337,108 @@ impl Default for AxisConfig {
                 analysis_enabled: Some(true),
                 log: Some(true),
                 stydl: Some(true),
+                server_port: None,
+                seed: None,
+                cache_compression: None,
+                central_cache: None,
+                cache_dir: None,
+                cache_size_mb: None,
+                output_format: None,
+                output_rate: None,
+                rate_conversion: None,
             }),
+            analysis: None,
             plugins: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors_name_their_lines() {
+        assert!(AxisConfig::parse("general threads=2\n").is_ok());
+        let err = AxisConfig::parse("general threads=2\nfoo\n").unwrap_err().to_string();
+        assert_eq!(err, "line 2: unexpected node `foo`");
+        let err = AxisConfig::parse("general threads=\"two\"\n").unwrap_err().to_string();
+        assert!(err.starts_with("line 1: "), "{}", err);
+    }
+
+    #[test]
+    fn test_output_rate_settings() {
+        let parse = |general: &str| -> AxisConfig { knuffel::parse("config.kdl", general).unwrap() };
+        let config = parse(r#"general output-rate=48000 rate-conversion="before-analysis""#);
+        assert_eq!(config.output_rate().unwrap(), Some(48000));
+        assert_eq!(config.rate_conversion().unwrap(), RateConversion::BeforeAnalysis);
+        assert_eq!(AxisConfig::default().rate_conversion().unwrap(), RateConversion::AfterSynthesis);
+
+        assert!(parse("general output-rate=0").output_rate().is_err());
+        assert!(parse("general output-rate=10000000").output_rate().is_err());
+        assert!(parse(r#"general rate-conversion="before-synthesis""#).rate_conversion().is_err());
+    }
+
+    #[test]
+    fn test_plugin_settings_from_kdl() {
+        let config: AxisConfig = knuffel::parse("config.kdl", r#"
+            plugin "Gain" enabled=false {
+                gain "0.5"
+                bypass "yes"
+            }
+            plugin "Limiter"
+        "#).unwrap();
+
+        let gain = config.plugin("Gain").unwrap();
+        assert_eq!(gain.enabled, Some(false));
+        let settings = gain.plugin_settings();
+        assert_eq!(settings.get_f64("gain").unwrap(), Some(0.5));
+        assert_eq!(settings.get_bool("bypass").unwrap(), Some(true));
+        assert_eq!(settings.get_f64("missing").unwrap(), None);
+        assert!(settings.get_i64("gain").is_err());
+
+        let limiter = config.plugin("Limiter").unwrap();
+        assert_eq!(limiter.enabled, None);
+        assert!(limiter.plugin_settings().is_empty());
+    }
+
+    #[test]
+    fn test_voicebank_f0_overrides() {
+        let config: AxisConfig = knuffel::parse("config.kdl", r#"
+            analysis f0-estimator="pyin" f0-ceiling=900 hmm-mode="candidates" {
+                voicebank "/banks/low" f0-estimator="dio" f0-floor=60 spectral-estimator="cheaptrick"
+                voicebank "/banks/low/falsetto" f0-ceiling=1200 channel-mode="side-aware"
+            }
+        "#).unwrap();
+        let analysis = config.analysis.unwrap();
+
+        let settings = |path: &str| analysis.settings(Path::new(path));
+        let candidates = Some("candidates".to_string());
+        assert_eq!(settings("/banks/high/a.wav"), AnalysisSettings { estimator: Some("pyin".into()), floor: None, ceiling: Some(900.0), hmm_mode: candidates.clone(), ..AnalysisSettings::default() });
+        assert_eq!(settings("/banks/low/a.wav"), AnalysisSettings { estimator: Some("dio".into()), floor: Some(60.0), ceiling: Some(900.0), hmm_mode: candidates.clone(), spectral_estimator: Some("cheaptrick".into()), ..AnalysisSettings::default() });
+        assert_eq!(settings("/banks/low/falsetto/a.wav"), AnalysisSettings { estimator: Some("pyin".into()), floor: None, ceiling: Some(1200.0), hmm_mode: candidates, channel_mode: Some("side-aware".into()), ..AnalysisSettings::default() });
+        assert_eq!(settings("/banks/lower/a.wav").estimator.as_deref(), Some("pyin"));
+    }
+
+    #[test]
+    fn test_hmm_params() {
+        let trained = VoicingHmmParams { p_vv: 0.9, voiced_missing: -1.25, ..VoicingHmmParams::default() };
+        let config: AxisConfig = knuffel::parse("config.kdl", &format!(r#"
+            analysis {{
+                hmm p-uu=0.7
+                voicebank "/banks/breathy" {{
+                    {}
+                }}
+                voicebank "/banks/fry" {{
+                    hmm f0-threshold=30.0
+                }}
+            }}
+        "#, HmmConfig::to_kdl(&trained))).unwrap();
+        let analysis = config.analysis.unwrap();
+
+        assert_eq!(analysis.settings(Path::new("/banks/breathy/a.wav")).hmm, trained);
+        let fry = analysis.settings(Path::new("/banks/fry/a.wav")).hmm;
+        assert_eq!(fry, VoicingHmmParams { p_uu: 0.7, f0_threshold: 30.0, ..VoicingHmmParams::default() });
+        assert_eq!(analysis.settings(Path::new("/other/a.wav")).hmm.p_uu, 0.7);
+    }
+}
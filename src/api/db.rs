@@ -1,57 +1,169 @@
+use anyhow::{bail, Context};
+use log::debug;
 use rusqlite::{params, Connection, Result};
 use std::path::Path;
-use crate::api::plugin::PluginMetadata;
+use crate::api::plugin::{host_version, PluginAbi, PluginMetadata, PluginStatus};
+
+/// A row of the `plugins` table.
+#[derive(Debug, Clone)]
+pub struct PluginRecord {
+    pub meta: PluginMetadata,
+    pub path: String,
+    pub enabled: bool,
+    pub abi_version: Option<u32>,
+    /// Host versions the plugin declared, e.g. `>=0.1.2, <1.0.0`.
+    pub host_range: Option<String>,
+    pub status: PluginStatus,
+    /// Place in the plugin chain; plugins run in ascending order.
+    pub position: i64,
+}
+
+/// Where `PluginDatabase::move_plugin` puts a plugin, relative to another one.
+#[derive(Debug, Clone, Copy)]
+pub enum Placement<'a> {
+    Before(&'a str),
+    After(&'a str),
+}
 
 pub struct PluginDatabase {
     conn: Connection,
 }
 
+/// Schema migrations, in order. `MIGRATIONS[i]` upgrades a database from
+/// schema version `i` to `i + 1`; the version is kept in SQLite's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE plugins (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        version TEXT NOT NULL,
+        author TEXT,
+        description TEXT,
+        path TEXT NOT NULL,
+        enabled INTEGER DEFAULT 1
+    );",
+    // 2: plugin compatibility
+    "ALTER TABLE plugins ADD COLUMN abi_version INTEGER;
+     ALTER TABLE plugins ADD COLUMN host_range TEXT;
+     ALTER TABLE plugins ADD COLUMN status TEXT NOT NULL DEFAULT 'unknown';",
+    // 3: chain order, initially registration order
+    "ALTER TABLE plugins ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+     UPDATE plugins SET position = id;",
+];
+
+/// Schema version written by this build.
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
 impl PluginDatabase {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    /// Opens (or creates) a plugin database, upgrading its schema to [`SCHEMA_VERSION`].
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
         let conn = Connection::open(path)?;
         let db = Self { conn };
-        db.init()?;
+        db.migrate().with_context(|| format!("Failed to upgrade {}", path.display()))?;
         Ok(db)
     }
 
-    fn init(&self) -> Result<()> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS plugins (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL UNIQUE,
-                version TEXT NOT NULL,
-                author TEXT,
-                description TEXT,
-                path TEXT NOT NULL,
-                enabled INTEGER DEFAULT 1
-            )",
+    pub fn schema_version(&self) -> Result<usize> {
+        self.conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0).map(|v| v as usize))
+    }
+
+    fn migrate(&self) -> anyhow::Result<()> {
+        let mut version = self.schema_version()?;
+        if version == 0 {
+            version = self.legacy_version()?;
+            if version > 0 {
+                self.conn.pragma_update(None, "user_version", version as i64)?;
+            }
+        }
+        if version > SCHEMA_VERSION {
+            bail!("plugin.db has schema version {}, but this AXIS build only understands up to {}", version, SCHEMA_VERSION);
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.unchecked_transaction()?;
+            tx.execute_batch(migration)
+                .with_context(|| format!("Schema migration to version {} failed", i + 1))?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+            debug!("Upgraded plugin.db to schema version {}", i + 1);
+        }
+        Ok(())
+    }
+
+    /// Works out the schema version of a database written before `user_version`
+    /// was tracked, from the columns it has.
+    fn legacy_version(&self) -> Result<usize> {
+        let table_exists: bool = self.conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'plugins'",
             [],
+            |row| row.get::<_, i64>(0).map(|n| n > 0),
+        )?;
+        Ok(if !table_exists {
+            0
+        } else if !self.has_column("status")? {
+            1
+        } else if !self.has_column("position")? {
+            2
+        } else {
+            3
+        })
+    }
+
+    fn has_column(&self, column: &str) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('plugins') WHERE name = ?1",
+            params![column],
+            |row| row.get::<_, i64>(0).map(|n| n > 0),
+        )
+    }
+
+    /// Registers a plugin at the end of the chain. Re-registering a plugin keeps its position.
+    pub fn register_plugin(&self, meta: &PluginMetadata, path: &str, abi: &PluginAbi) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO plugins (name, version, author, description, path, abi_version, host_range, status, position)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, COALESCE(
+                 (SELECT position FROM plugins WHERE name = ?1),
+                 (SELECT COALESCE(MAX(position), 0) + 1 FROM plugins)
+             ))",
+            params![
+                meta.name, meta.version, meta.author, meta.description, path,
+                abi.abi_version, abi.host_range.map(|r| r.to_string()), abi.status(host_version()).as_str(),
+            ],
         )?;
         Ok(())
     }
 
-    pub fn register_plugin(&self, meta: &PluginMetadata, path: &str) -> Result<()> {
+    /// Records the result of checking a registered plugin against this AXIS build.
+    pub fn set_plugin_compatibility(&self, name: &str, abi: &PluginAbi) -> Result<()> {
         self.conn.execute(
-            "INSERT OR REPLACE INTO plugins (name, version, author, description, path)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![meta.name, meta.version, meta.author, meta.description, path],
+            "UPDATE plugins SET abi_version = ?1, host_range = ?2, status = ?3 WHERE name = ?4",
+            params![abi.abi_version, abi.host_range.map(|r| r.to_string()), abi.status(host_version()).as_str(), name],
         )?;
         Ok(())
     }
 
-    pub fn list_plugins(&self) -> Result<Vec<(PluginMetadata, String, bool)>> {
-        let mut stmt = self.conn.prepare("SELECT name, version, author, description, path, enabled FROM plugins")?;
+    /// Lists plugins in chain order.
+    pub fn list_plugins(&self) -> Result<Vec<PluginRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, version, author, description, path, enabled, abi_version, host_range, status, position
+             FROM plugins ORDER BY position, id",
+        )?;
         let rows = stmt.query_map([], |row| {
-            Ok((
-                PluginMetadata {
+            Ok(PluginRecord {
+                meta: PluginMetadata {
                     name: row.get(0)?,
                     version: row.get(1)?,
-                    author: row.get(2)?,
-                    description: row.get(3)?,
+                    author: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    description: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
                 },
-                row.get::<_, String>(4)?,
-                row.get::<_, i32>(5)? != 0,
-            ))
+                path: row.get(4)?,
+                enabled: row.get::<_, i32>(5)? != 0,
+                abi_version: row.get(6)?,
+                host_range: row.get(7)?,
+                status: PluginStatus::parse(&row.get::<_, String>(8)?),
+                position: row.get(9)?,
+            })
         })?;
 
         let mut plugins = Vec::new();
@@ -69,6 +181,35 @@ impl PluginDatabase {
         Ok(())
     }
 
+    /// Moves `name` next to another plugin and renumbers the chain. Returns
+    /// `false` if either plugin is not registered.
+    pub fn move_plugin(&self, name: &str, placement: Placement) -> Result<bool> {
+        let mut order: Vec<String> = self.list_plugins()?.into_iter().map(|r| r.meta.name).collect();
+        let anchor = match placement {
+            Placement::Before(anchor) | Placement::After(anchor) => anchor,
+        };
+        let Some(from) = order.iter().position(|n| n == name) else { return Ok(false) };
+        if !order.iter().any(|n| n == anchor) {
+            return Ok(false);
+        }
+        if name == anchor {
+            return Ok(true);
+        }
+        let moved = order.remove(from);
+        let mut to = order.iter().position(|n| n == anchor).unwrap_or(order.len());
+        if let Placement::After(_) = placement {
+            to += 1;
+        }
+        order.insert(to.min(order.len()), moved);
+
+        let tx = self.conn.unchecked_transaction()?;
+        for (i, plugin) in order.iter().enumerate() {
+            tx.execute("UPDATE plugins SET position = ?1 WHERE name = ?2", params![i as i64 + 1, plugin])?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
     pub fn remove_plugin(&self, name: &str) -> Result<()> {
         self.conn.execute(
             "DELETE FROM plugins WHERE name = ?1",
@@ -77,3 +218,111 @@ impl PluginDatabase {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(db: &PluginDatabase, name: &str) {
+        let meta = PluginMetadata {
+            name: name.into(),
+            version: "1.0.0".into(),
+            author: "test".into(),
+            description: "".into(),
+        };
+        let abi = PluginAbi { abi_version: Some(axis_plugin::ffi::AXIS_PLUGIN_ABI_VERSION), host_range: None };
+        db.register_plugin(&meta, &format!("/plugins/{}.so", name), &abi).unwrap();
+    }
+
+    fn order(db: &PluginDatabase) -> Vec<String> {
+        db.list_plugins().unwrap().into_iter().map(|r| r.meta.name).collect()
+    }
+
+    #[test]
+    fn test_plugin_order() {
+        let db = PluginDatabase::open(":memory:").unwrap();
+        for name in ["formant", "breathiness", "limiter"] {
+            register(&db, name);
+        }
+        assert_eq!(order(&db), ["formant", "breathiness", "limiter"]);
+
+        assert!(db.move_plugin("limiter", Placement::Before("formant")).unwrap());
+        assert_eq!(order(&db), ["limiter", "formant", "breathiness"]);
+        assert!(db.move_plugin("limiter", Placement::After("breathiness")).unwrap());
+        assert_eq!(order(&db), ["formant", "breathiness", "limiter"]);
+        assert!(db.move_plugin("formant", Placement::After("formant")).unwrap());
+        assert_eq!(order(&db), ["formant", "breathiness", "limiter"]);
+        assert!(!db.move_plugin("reverb", Placement::Before("formant")).unwrap());
+        assert!(!db.move_plugin("formant", Placement::Before("reverb")).unwrap());
+
+        // Re-registering keeps the plugin's place; new plugins go last
+        register(&db, "formant");
+        register(&db, "reverb");
+        assert_eq!(order(&db), ["formant", "breathiness", "limiter", "reverb"]);
+    }
+
+    /// `plugin.db` as created by AXIS 0.1.1 and earlier.
+    const V1_FIXTURE: &str = "
+        CREATE TABLE plugins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            version TEXT NOT NULL,
+            author TEXT,
+            description TEXT,
+            path TEXT NOT NULL,
+            enabled INTEGER DEFAULT 1
+        );
+        INSERT INTO plugins (name, version, author, description, path) VALUES ('b', '1.0', 'x', 'first', '/b.so');
+        INSERT INTO plugins (name, version, author, description, path, enabled) VALUES ('a', '2.0', 'y', 'second', '/a.so', 0);
+    ";
+
+    fn fixture(name: &str, sql: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("axis_test_{}_{}.db", std::process::id(), name));
+        std::fs::remove_file(&path).ok();
+        Connection::open(&path).unwrap().execute_batch(sql).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_upgrade_v1_database() {
+        let path = fixture("v1", V1_FIXTURE);
+        let db = PluginDatabase::open(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+
+        let plugins = db.list_plugins().unwrap();
+        assert_eq!(order(&db), ["b", "a"]);
+        assert_eq!(plugins[1].meta.version, "2.0");
+        assert!(!plugins[1].enabled);
+        assert_eq!(plugins[0].status, PluginStatus::Unknown);
+        assert_eq!(plugins[0].abi_version, None);
+
+        register(&db, "c");
+        assert_eq!(order(&db), ["b", "a", "c"]);
+        drop(db);
+
+        // Reopening an up-to-date database changes nothing
+        let db = PluginDatabase::open(&path).unwrap();
+        assert_eq!(order(&db), ["b", "a", "c"]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_untracked_and_future_schemas() {
+        // Compatibility columns added by a build that predates user_version
+        let path = fixture("v2", &format!("{}
+            ALTER TABLE plugins ADD COLUMN abi_version INTEGER;
+            ALTER TABLE plugins ADD COLUMN host_range TEXT;
+            ALTER TABLE plugins ADD COLUMN status TEXT NOT NULL DEFAULT 'unknown';
+            UPDATE plugins SET status = 'compatible' WHERE name = 'a';", V1_FIXTURE));
+        let db = PluginDatabase::open(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.list_plugins().unwrap()[1].status, PluginStatus::Compatible);
+        drop(db);
+        std::fs::remove_file(&path).ok();
+
+        let path = fixture("future", &format!("{} PRAGMA user_version = {};", V1_FIXTURE, SCHEMA_VERSION + 1));
+        let err = PluginDatabase::open(&path).err().expect("newer schema should be refused");
+        assert!(format!("{:#}", err).contains("only understands"), "{:#}", err);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -2,6 +2,6 @@ pub mod plugin;
 pub mod db;
 pub mod config;
 
-pub use plugin::{AxisPlugin, PluginMetadata, PluginLoader};
-pub use db::PluginDatabase;
-pub use config::AxisConfig;
+pub use plugin::{AxisPlugin, IncompatiblePlugin, PluginAbi, PluginMetadata, PluginLoader, PluginSettings, PluginStatus};
+pub use db::{Placement, PluginDatabase, PluginRecord};
+pub use config::{AnalysisConfig, AnalysisSettings, AxisConfig, HmmConfig, PluginConfig};
@@ -1,54 +1,610 @@
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PluginMetadata {
-    pub name: String,
-    pub version: String,
-    pub author: String,
-    pub description: String,
-}
-
-pub trait AxisPlugin: Send + Sync {
-    fn metadata(&self) -> PluginMetadata;
-    fn on_load(&mut self) -> anyhow::Result<()> { Ok(()) }
-    fn on_unload(&mut self) -> anyhow::Result<()> { Ok(()) }
-    
-    fn process_audio(&mut self, _samples: &mut [f64], _sample_rate: u32) -> anyhow::Result<()> {
+use anyhow::{anyhow, bail, Context};
+use axis_plugin::ffi::{
+    AbiVersionFn, AxisFeatureBuffer, AxisPluginInfo, AxisPluginVTable, AxisSetting, AxisStatus, CreateFn,
+    HostRangeFn, AXIS_OK, AXIS_PLUGIN_ABI_VERSION,
+};
+use std::ffi::{c_char, CStr, CString};
+use std::fmt;
+use std::hash::Hasher;
+use std::mem::MaybeUninit;
+
+pub use axis_plugin::ffi::{AxisHostRange, AxisVersion};
+pub use axis_plugin::{AxisPlugin, PluginMetadata, PluginSettings};
+
+type FeaturesFn = unsafe extern "C" fn(*mut std::ffi::c_void, *mut AxisFeatureBuffer, u32) -> AxisStatus;
+
+/// The AXIS version plugins are checked against.
+pub fn host_version() -> AxisVersion {
+    AxisVersion::parse(env!("CARGO_PKG_VERSION")).expect("crate version is major.minor.patch")
+}
+
+/// Whether a plugin library can be loaded by this AXIS build, as stored in `plugin.db`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginStatus {
+    Compatible,
+    /// Built against the old Rust trait-object interface.
+    Legacy,
+    /// Speaks a different C ABI version.
+    AbiMismatch,
+    /// Requires a host version range this build is outside of.
+    HostMismatch,
+    /// Not checked yet (registered before compatibility was tracked).
+    Unknown,
+}
+
+impl PluginStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Compatible => "compatible",
+            Self::Legacy => "legacy",
+            Self::AbiMismatch => "abi-mismatch",
+            Self::HostMismatch => "host-mismatch",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "compatible" => Self::Compatible,
+            "legacy" => Self::Legacy,
+            "abi-mismatch" => Self::AbiMismatch,
+            "host-mismatch" => Self::HostMismatch,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for PluginStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What a plugin library declares about the interface it was built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PluginAbi {
+    /// `None` for libraries using the legacy trait-object interface.
+    pub abi_version: Option<u32>,
+    /// `None` when the library accepts any host speaking its ABI.
+    pub host_range: Option<AxisHostRange>,
+}
+
+impl PluginAbi {
+    pub fn status(&self, host: AxisVersion) -> PluginStatus {
+        match self.check(host) {
+            Ok(()) => PluginStatus::Compatible,
+            Err(e) => e.status,
+        }
+    }
+
+    pub fn check(&self, host: AxisVersion) -> Result<(), IncompatiblePlugin> {
+        let Some(abi_version) = self.abi_version else {
+            return Err(IncompatiblePlugin {
+                abi: *self,
+                status: PluginStatus::Legacy,
+                message: "Plugin uses the legacy Rust trait-object interface; rebuild it against the axis-plugin crate".into(),
+            });
+        };
+        if abi_version != AXIS_PLUGIN_ABI_VERSION {
+            return Err(IncompatiblePlugin {
+                abi: *self,
+                status: PluginStatus::AbiMismatch,
+                message: format!("Plugin ABI version {} is not supported (this AXIS build speaks version {})", abi_version, AXIS_PLUGIN_ABI_VERSION),
+            });
+        }
+        if let Some(range) = self.host_range.filter(|r| !r.contains(host)) {
+            return Err(IncompatiblePlugin {
+                abi: *self,
+                status: PluginStatus::HostMismatch,
+                message: format!("Plugin requires AXIS {} but this is AXIS {}", range, host),
+            });
+        }
         Ok(())
     }
+}
 
-    fn process_features(
+/// Returned (inside `anyhow::Error`) when a plugin library is refused because
+/// it was built for a different ABI or host version.
+#[derive(Debug)]
+pub struct IncompatiblePlugin {
+    pub abi: PluginAbi,
+    pub status: PluginStatus,
+    message: String,
+}
+
+impl fmt::Display for IncompatiblePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for IncompatiblePlugin {}
+
+/// A plugin instance living behind the C ABI, exposed as an `AxisPlugin`.
+pub struct ForeignPlugin {
+    vtable: AxisPluginVTable,
+    metadata: PluginMetadata,
+    /// Whether `on_load` succeeded and `on_unload` is still owed.
+    loaded: bool,
+}
+
+// The vtable is only ever used through `&mut self` (or `&self` for cached
+// metadata), and `AxisPlugin` requires plugin types to be `Send + Sync`.
+unsafe impl Send for ForeignPlugin {}
+unsafe impl Sync for ForeignPlugin {}
+
+unsafe fn read_cstr(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(ptr).to_string_lossy().into_owned()
+    }
+}
+
+impl ForeignPlugin {
+    /// Takes ownership of the instance in `vtable`. It is destroyed on drop,
+    /// after `on_unload` if `on_load` succeeded.
+    ///
+    /// # Safety
+    ///
+    /// `vtable` must come from a successful `axis_plugin_create` call.
+    pub unsafe fn from_vtable(vtable: AxisPluginVTable) -> anyhow::Result<Self> {
+        let mut plugin = Self {
+            vtable,
+            metadata: PluginMetadata {
+                name: String::new(),
+                version: String::new(),
+                author: String::new(),
+                description: String::new(),
+            },
+            loaded: false,
+        };
+        if plugin.vtable.abi_version != AXIS_PLUGIN_ABI_VERSION {
+            bail!("Plugin instance reports ABI version {}, expected {}", plugin.vtable.abi_version, AXIS_PLUGIN_ABI_VERSION);
+        }
+
+        let mut info = MaybeUninit::<AxisPluginInfo>::zeroed();
+        let status = (plugin.vtable.metadata)(plugin.vtable.instance, info.as_mut_ptr());
+        plugin.check(status).context("Failed to read plugin metadata")?;
+        let info = info.assume_init();
+        plugin.metadata = PluginMetadata {
+            name: read_cstr(info.name),
+            version: read_cstr(info.version),
+            author: read_cstr(info.author),
+            description: read_cstr(info.description),
+        };
+        Ok(plugin)
+    }
+
+    fn check(&self, status: AxisStatus) -> anyhow::Result<()> {
+        if status == AXIS_OK {
+            return Ok(());
+        }
+        let message = unsafe { read_cstr((self.vtable.last_error)(self.vtable.instance)) };
+        if message.is_empty() {
+            Err(anyhow!("Plugin returned error code {}", status))
+        } else {
+            Err(anyhow!("{} (code {})", message, status))
+        }
+    }
+
+    fn flatten(rows: &[Vec<f64>], what: &str) -> anyhow::Result<(Vec<f64>, usize)> {
+        let bins = rows.first().map_or(0, |r| r.len());
+        if rows.iter().any(|r| r.len() != bins) {
+            bail!("{} frames have differing bin counts", what);
+        }
+        Ok((rows.concat(), bins))
+    }
+
+    fn unflatten(flat: &[f64], rows: &mut [Vec<f64>]) {
+        let mut offset = 0;
+        for row in rows.iter_mut() {
+            let n = row.len();
+            row.copy_from_slice(&flat[offset..offset + n]);
+            offset += n;
+        }
+    }
+
+    /// Passes the features to a vtable entry taking an `AxisFeatureBuffer`.
+    fn call_features(
         &mut self,
-        _f0: &mut [f64],
-        _spectral: &mut [Vec<f64>],
-        _aperiodicity: &mut [Vec<f64>],
-        _sample_rate: u32,
+        entry: FeaturesFn,
+        f0: &mut [f64],
+        spectral: &mut [Vec<f64>],
+        aperiodicity: &mut [Vec<f64>],
+        sample_rate: u32,
     ) -> anyhow::Result<()> {
+        if spectral.len() != f0.len() || aperiodicity.len() != f0.len() {
+            bail!("Feature frame counts differ");
+        }
+        let (mut spec_flat, spectral_bins) = Self::flatten(spectral, "Spectral")?;
+        let (mut ap_flat, aperiodicity_bins) = Self::flatten(aperiodicity, "Aperiodicity")?;
+        let mut buffer = AxisFeatureBuffer {
+            frames: f0.len(),
+            f0: f0.as_mut_ptr(),
+            spectral: spec_flat.as_mut_ptr(),
+            spectral_bins,
+            aperiodicity: ap_flat.as_mut_ptr(),
+            aperiodicity_bins,
+        };
+        let status = unsafe { entry(self.vtable.instance, &mut buffer, sample_rate) };
+        self.check(status)?;
+        Self::unflatten(&spec_flat, spectral);
+        Self::unflatten(&ap_flat, aperiodicity);
+        Ok(())
+    }
+}
+
+impl AxisPlugin for ForeignPlugin {
+    fn metadata(&self) -> PluginMetadata {
+        self.metadata.clone()
+    }
+
+    fn on_load(&mut self) -> anyhow::Result<()> {
+        let status = unsafe { (self.vtable.on_load)(self.vtable.instance) };
+        self.check(status)?;
+        self.loaded = true;
         Ok(())
     }
+
+    fn on_unload(&mut self) -> anyhow::Result<()> {
+        self.loaded = false;
+        let status = unsafe { (self.vtable.on_unload)(self.vtable.instance) };
+        self.check(status)
+    }
+
+    fn configure(&mut self, settings: &PluginSettings) -> anyhow::Result<()> {
+        let owned: Vec<(CString, CString)> = settings
+            .iter()
+            .map(|(k, v)| Ok((CString::new(k)?, CString::new(v)?)))
+            .collect::<Result<_, std::ffi::NulError>>()
+            .context("Plugin settings must not contain NUL bytes")?;
+        let raw: Vec<AxisSetting> = owned
+            .iter()
+            .map(|(k, v)| AxisSetting { key: k.as_ptr(), value: v.as_ptr() })
+            .collect();
+        let status = unsafe { (self.vtable.configure)(self.vtable.instance, raw.as_ptr(), raw.len()) };
+        self.check(status)
+    }
+
+    fn process_audio(&mut self, samples: &mut [f64], sample_rate: u32) -> anyhow::Result<()> {
+        let status = unsafe { (self.vtable.process_audio)(self.vtable.instance, samples.as_mut_ptr(), samples.len(), sample_rate) };
+        self.check(status)
+    }
+
+    fn process_features(
+        &mut self,
+        f0: &mut [f64],
+        spectral: &mut [Vec<f64>],
+        aperiodicity: &mut [Vec<f64>],
+        sample_rate: u32,
+    ) -> anyhow::Result<()> {
+        let entry = self.vtable.process_features;
+        self.call_features(entry, f0, spectral, aperiodicity, sample_rate)
+    }
+
+    fn analysis_hooks(&self) -> bool {
+        unsafe { (self.vtable.analysis_hooks)(self.vtable.instance) != 0 }
+    }
+
+    fn pre_analysis(&mut self, samples: &mut [f64], sample_rate: u32) -> anyhow::Result<()> {
+        let status = unsafe { (self.vtable.pre_analysis)(self.vtable.instance, samples.as_mut_ptr(), samples.len(), sample_rate) };
+        self.check(status)
+    }
+
+    fn override_f0(&mut self, samples: &[f64], sample_rate: u32, frame_period: f64, f0: &mut [f64]) -> anyhow::Result<()> {
+        let status = unsafe {
+            (self.vtable.override_f0)(self.vtable.instance, samples.as_ptr(), samples.len(), sample_rate, frame_period, f0.as_mut_ptr(), f0.len())
+        };
+        self.check(status)
+    }
+
+    fn post_analysis(
+        &mut self,
+        f0: &mut [f64],
+        spectral: &mut [Vec<f64>],
+        aperiodicity: &mut [Vec<f64>],
+        sample_rate: u32,
+    ) -> anyhow::Result<()> {
+        let entry = self.vtable.post_analysis;
+        self.call_features(entry, f0, spectral, aperiodicity, sample_rate)
+    }
+}
+
+impl Drop for ForeignPlugin {
+    fn drop(&mut self) {
+        if self.loaded {
+            if let Err(e) = self.on_unload() {
+                log::warn!("Plugin {} failed to unload: {:#}", self.metadata.name, e);
+            }
+        }
+        unsafe { (self.vtable.destroy)(self.vtable.instance) }
+    }
 }
 
 pub struct PluginLoader {
+    // Declared before `_lib` so the instance is destroyed before the library is unloaded.
+    plugin: ForeignPlugin,
+    abi: PluginAbi,
+    analysis_id: Option<String>,
     _lib: libloading::Library,
-    plugin: Box<dyn AxisPlugin>,
 }
 
 impl PluginLoader {
+    unsafe fn read_abi(lib: &libloading::Library) -> anyhow::Result<PluginAbi> {
+        let abi_version: libloading::Symbol<AbiVersionFn> = match lib.get(b"axis_plugin_abi_version") {
+            Ok(symbol) => symbol,
+            Err(_) if lib.get::<*const ()>(b"_axis_plugin_create").is_ok() => {
+                return Ok(PluginAbi { abi_version: None, host_range: None });
+            }
+            Err(e) => return Err(e).context("Not an AXIS plugin: missing axis_plugin_abi_version"),
+        };
+        let host_range = match lib.get::<HostRangeFn>(b"axis_plugin_host_range") {
+            Ok(host_range) => {
+                let mut range = MaybeUninit::<AxisHostRange>::zeroed();
+                host_range(range.as_mut_ptr());
+                Some(range.assume_init())
+            }
+            Err(_) => None,
+        };
+        Ok(PluginAbi { abi_version: Some(abi_version()), host_range })
+    }
+
+    /// Loads a plugin, refusing it with an [`IncompatiblePlugin`] error if it
+    /// was built for a different ABI or host version.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization code. The library must
+    /// implement the AXIS plugin C ABI (see `axis_plugin::ffi`).
     pub unsafe fn load<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
         let lib = libloading::Library::new(path.as_ref())?;
-        
-        let constructor: libloading::Symbol<fn() -> Box<dyn AxisPlugin>> = 
-            lib.get(b"_axis_plugin_create")?;
-            
-        let plugin = constructor();
-        
+        let abi = Self::read_abi(&lib)?;
+        abi.check(host_version())?;
+
+        let create: libloading::Symbol<CreateFn> = lib.get(b"axis_plugin_create")
+            .context("Not an AXIS plugin: missing axis_plugin_create")?;
+        let mut vtable = MaybeUninit::<AxisPluginVTable>::uninit();
+        let status = create(vtable.as_mut_ptr());
+        if status != AXIS_OK {
+            bail!("axis_plugin_create failed with code {}", status);
+        }
+        let plugin = ForeignPlugin::from_vtable(vtable.assume_init())?;
+
         Ok(Self {
-            _lib: lib,
             plugin,
+            abi,
+            analysis_id: None,
+            _lib: lib,
         })
     }
 
     pub fn plugin(&mut self) -> &mut dyn AxisPlugin {
-        self.plugin.as_mut()
+        &mut self.plugin
+    }
+
+    pub fn abi(&self) -> PluginAbi {
+        self.abi
+    }
+
+    /// Passes `settings` to the plugin and works out its [`analysis_id`](Self::analysis_id).
+    pub fn configure(&mut self, settings: &PluginSettings) -> anyhow::Result<()> {
+        self.plugin.configure(settings)?;
+        self.analysis_id = self.plugin.analysis_hooks().then(|| {
+            let mut hasher = crate::util::Fnv1a::default();
+            for (key, value) in settings.iter() {
+                for part in [key, value] {
+                    hasher.write(part.as_bytes());
+                    hasher.write_u8(0);
+                }
+            }
+            format!("{} {} {:016x}", self.plugin.metadata.name, self.plugin.metadata.version, hasher.finish())
+        });
+        Ok(())
+    }
+
+    /// Identifies the plugin, its version and settings in `.axxf` headers.
+    /// `None` unless the plugin opted in to analysis hooks.
+    pub fn analysis_id(&self) -> Option<&str> {
+        self.analysis_id.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axis_plugin::ffi::export;
+
+    struct Doubler {
+        factor: f64,
+    }
+
+    impl AxisPlugin for Doubler {
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata {
+                name: "Doubler".into(),
+                version: "1.0.0".into(),
+                author: "test".into(),
+                description: "doubles everything".into(),
+            }
+        }
+
+        fn configure(&mut self, settings: &PluginSettings) -> anyhow::Result<()> {
+            self.factor = settings.get_f64("factor")?.unwrap_or(2.0);
+            Ok(())
+        }
+
+        fn process_audio(&mut self, samples: &mut [f64], _sample_rate: u32) -> anyhow::Result<()> {
+            if samples.is_empty() {
+                bail!("no samples");
+            }
+            samples.iter_mut().for_each(|s| *s *= self.factor);
+            Ok(())
+        }
+
+        fn process_features(&mut self, f0: &mut [f64], spectral: &mut [Vec<f64>], aperiodicity: &mut [Vec<f64>], _sample_rate: u32) -> anyhow::Result<()> {
+            f0.iter_mut().for_each(|f| *f *= self.factor);
+            spectral.iter_mut().flatten().for_each(|v| *v *= self.factor);
+            aperiodicity.iter_mut().flatten().for_each(|v| *v /= self.factor);
+            Ok(())
+        }
+
+        fn analysis_hooks(&self) -> bool {
+            self.factor != 1.0
+        }
+
+        fn override_f0(&mut self, samples: &[f64], _sample_rate: u32, frame_period: f64, f0: &mut [f64]) -> anyhow::Result<()> {
+            f0.iter_mut().for_each(|f| *f = samples[0] * frame_period);
+            Ok(())
+        }
+
+        fn post_analysis(&mut self, f0: &mut [f64], spectral: &mut [Vec<f64>], aperiodicity: &mut [Vec<f64>], sample_rate: u32) -> anyhow::Result<()> {
+            self.process_features(f0, spectral, aperiodicity, sample_rate)
+        }
+    }
+
+    fn foreign() -> ForeignPlugin {
+        let mut vtable = MaybeUninit::uninit();
+        unsafe {
+            assert_eq!(export::create(|| Doubler { factor: 2.0 }, vtable.as_mut_ptr()), AXIS_OK);
+            ForeignPlugin::from_vtable(vtable.assume_init()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_c_abi_roundtrip() {
+        let mut plugin = foreign();
+        assert_eq!(plugin.metadata().name, "Doubler");
+        plugin.configure(&PluginSettings::new([("factor", "3")])).unwrap();
+
+        let mut samples = vec![1.0, -0.5];
+        plugin.process_audio(&mut samples, 44100).unwrap();
+        assert_eq!(samples, vec![3.0, -1.5]);
+
+        let mut f0 = vec![100.0, 0.0];
+        let mut spec = vec![vec![1.0, 2.0, 3.0]; 2];
+        let mut ap = vec![vec![0.3]; 2];
+        plugin.process_features(&mut f0, &mut spec, &mut ap, 44100).unwrap();
+        assert_eq!(f0, vec![300.0, 0.0]);
+        assert_eq!(spec[1], vec![3.0, 6.0, 9.0]);
+        assert!((ap[0][0] - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_c_abi_analysis_hooks() {
+        let mut plugin = foreign();
+        assert!(plugin.analysis_hooks());
+        plugin.configure(&PluginSettings::new([("factor", "1")])).unwrap();
+        assert!(!plugin.analysis_hooks());
+        plugin.configure(&PluginSettings::new([("factor", "4")])).unwrap();
+
+        let mut samples = vec![0.5, 0.25];
+        plugin.pre_analysis(&mut samples, 44100).unwrap();
+        assert_eq!(samples, vec![0.5, 0.25]);
+        let mut f0 = vec![0.0; 3];
+        plugin.override_f0(&samples, 44100, 5.0, &mut f0).unwrap();
+        assert_eq!(f0, vec![2.5; 3]);
+
+        let mut spec = vec![vec![1.0]; 3];
+        let mut ap = vec![vec![0.4, 0.8]; 3];
+        plugin.post_analysis(&mut f0, &mut spec, &mut ap, 44100).unwrap();
+        assert_eq!(f0, vec![10.0; 3]);
+        assert_eq!(ap[2], vec![0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_c_abi_errors() {
+        let mut plugin = foreign();
+        let err = plugin.process_audio(&mut [], 44100).unwrap_err();
+        assert!(err.to_string().contains("no samples"), "{}", err);
+
+        let err = plugin.configure(&PluginSettings::new([("factor", "loud")])).unwrap_err();
+        assert!(err.to_string().contains("factor"), "{}", err);
+    }
+
+    static UNLOADS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    struct Lifecycle {
+        fail_load: bool,
+    }
+
+    impl AxisPlugin for Lifecycle {
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata { name: "Lifecycle".into(), version: "1.0.0".into(), author: "test".into(), description: String::new() }
+        }
+
+        fn on_load(&mut self) -> anyhow::Result<()> {
+            if self.fail_load {
+                bail!("refusing to load");
+            }
+            Ok(())
+        }
+
+        fn on_unload(&mut self) -> anyhow::Result<()> {
+            UNLOADS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn process_audio(&mut self, _samples: &mut [f64], _sample_rate: u32) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn process_features(&mut self, _f0: &mut [f64], _spectral: &mut [Vec<f64>], _aperiodicity: &mut [Vec<f64>], _sample_rate: u32) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_unload_only_after_load() {
+        let lifecycle = |fail_load: bool| {
+            let mut vtable = MaybeUninit::uninit();
+            unsafe {
+                assert_eq!(export::create(move || Lifecycle { fail_load }, vtable.as_mut_ptr()), AXIS_OK);
+                ForeignPlugin::from_vtable(vtable.assume_init()).unwrap()
+            }
+        };
+        let unloads = || UNLOADS.load(std::sync::atomic::Ordering::SeqCst);
+
+        // Never loaded, as in `plugin add` and `plugin doctor`
+        drop(lifecycle(false));
+        assert_eq!(unloads(), 0);
+
+        let mut failed = lifecycle(true);
+        assert!(failed.on_load().is_err());
+        drop(failed);
+        assert_eq!(unloads(), 0);
+
+        let mut loaded = lifecycle(false);
+        loaded.on_load().unwrap();
+        drop(loaded);
+        assert_eq!(unloads(), 1);
+
+        // Unloaded explicitly, so not again on drop
+        let mut unloaded = lifecycle(false);
+        unloaded.on_load().unwrap();
+        unloaded.on_unload().unwrap();
+        drop(unloaded);
+        assert_eq!(unloads(), 2);
+    }
+
+    #[test]
+    fn test_host_compatibility() {
+        let host = AxisVersion::new(0, 2, 1);
+        let range = |min, max| Some(AxisHostRange::new(min, max));
+        let abi = |abi_version, host_range| PluginAbi { abi_version, host_range };
+
+        assert_eq!(abi(Some(AXIS_PLUGIN_ABI_VERSION), None).status(host), PluginStatus::Compatible);
+        assert_eq!(abi(Some(AXIS_PLUGIN_ABI_VERSION), range(AxisVersion::new(0, 2, 0), AxisVersion::new(0, 3, 0))).status(host), PluginStatus::Compatible);
+        assert_eq!(abi(Some(AXIS_PLUGIN_ABI_VERSION), range(AxisVersion::new(0, 1, 0), AxisVersion::new(0, 2, 1))).status(host), PluginStatus::HostMismatch);
+        assert_eq!(abi(Some(AXIS_PLUGIN_ABI_VERSION), range(AxisVersion::new(0, 2, 2), AxisVersion::new(1, 0, 0))).status(host), PluginStatus::HostMismatch);
+        assert_eq!(abi(Some(AXIS_PLUGIN_ABI_VERSION + 1), None).status(host), PluginStatus::AbiMismatch);
+        assert_eq!(abi(None, None).status(host), PluginStatus::Legacy);
+
+        let err = abi(Some(AXIS_PLUGIN_ABI_VERSION), range(AxisVersion::new(0, 3, 0), AxisVersion::new(0, 4, 0))).check(host).unwrap_err();
+        assert_eq!(err.to_string(), "Plugin requires AXIS >=0.3.0, <0.4.0 but this is AXIS 0.2.1");
+
+        assert_eq!(AxisVersion::parse("1.2.3-beta+7"), Some(AxisVersion::new(1, 2, 3)));
+        assert_eq!(AxisVersion::parse("1.2"), None);
+        assert!(axis_plugin::ffi::DEFAULT_HOST_RANGE.contains(host_version()));
     }
 }
@@ -1,5 +1,6 @@
 use crate::util::{pitch_parser, tempo_parser};
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
@@ -46,6 +47,37 @@ pub enum Commands {
         #[command(subcommand)]
         action: PluginAction,
     },
+    /// Pre-compute `.axxf` analysis for every WAV in a voicebank folder.
+    Analyze {
+        dir: String,
+        /// Re-analyze files whose cache is already up to date.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Voicing HMM tools.
+    Hmm {
+        #[command(subcommand)]
+        action: HmmAction,
+    },
+    /// Central analysis cache maintenance.
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Keep plugins, config and analysis in memory and render notes sent by clients.
+    Serve {
+        /// Read JSON-lines jobs from stdin instead of listening on a socket.
+        #[arg(long)]
+        stdio: bool,
+        #[arg(long)]
+        port: Option<u16>,
+        /// Number of notes rendered concurrently (defaults to the CPU count).
+        #[arg(long)]
+        workers: Option<usize>,
+        /// Maximum number of decoded samples kept in memory.
+        #[arg(long, default_value_t = 256)]
+        cache_size: usize,
+    },
 }
 
 #[derive(Subcommand)]
@@ -55,8 +87,43 @@ pub enum PluginAction {
     Remove { name: String },
     Enable { name: String },
     Disable { name: String },
+    /// Report plugins whose library is missing, incompatible or out of date.
+    Doctor,
+    /// Change where a plugin runs in the chain.
+    #[command(group(ArgGroup::new("anchor").required(true)))]
+    Move {
+        name: String,
+        /// Run just before this plugin.
+        #[arg(long, group = "anchor")]
+        before: Option<String>,
+        /// Run just after this plugin.
+        #[arg(long, group = "anchor")]
+        after: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HmmAction {
+    /// Fit voicing HMM parameters to the WAVs in a folder that have `.lab` files.
+    Train {
+        dir: String,
+        /// Write the `hmm` node to this file instead of printing it.
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheAction {
+    /// Remove every cached analysis file.
+    Clear,
+    /// Show the cache location, size and limit.
+    Stats,
+    /// Evict least recently used files until the cache fits its size limit.
+    Gc,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResamplerArgs {
     pub in_file: String,
     pub out_file: String,
@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
 use symphonia::core::audio::SampleBuffer;
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
 use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::{MediaSourceStream, ReadOnlySource};
 use symphonia::core::meta::MetadataOptions;
@@ -8,16 +9,162 @@ use symphonia::core::probe::Hint;
 use symphonia::default::get_codecs;
 use symphonia::default::get_probe;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
-use log::{info, debug};
+use log::{debug, info, warn};
+use rand::Rng;
+use crate::flac;
+use crate::metadata::{self, WavMetadata};
+
+/// Correlation between channels below which averaging them cancels
+/// enough content to warn about.
+pub const CANCELLATION_CORRELATION: f64 = 0.5;
+
+/// Largest inter-channel delay `ChannelMode::SideAware` compensates, in seconds.
+const MAX_CHANNEL_DELAY: f64 = 0.001;
+
+/// How multi-channel sources become the mono signal that is analyzed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelMode {
+    /// Mean of every channel.
+    #[default]
+    Average,
+    /// One channel, counted from 0.
+    Channel(usize),
+    /// Mean of the front left and right channels, ignoring center, LFE and surrounds.
+    Mid,
+    /// Mean of every channel after aligning each to the first in time and
+    /// polarity, so spaced microphones do not comb-filter.
+    SideAware,
+}
+
+impl std::str::FromStr for ChannelMode {
+    type Err = anyhow::Error;
+
+    /// `"average"`, `"mid"`, `"side-aware"`, `"left"`, `"right"` or `"channel-N"` (1-based).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "average" => Ok(ChannelMode::Average),
+            "mid" => Ok(ChannelMode::Mid),
+            "side-aware" => Ok(ChannelMode::SideAware),
+            "left" => Ok(ChannelMode::Channel(0)),
+            "right" => Ok(ChannelMode::Channel(1)),
+            other => match other.strip_prefix("channel-").and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n >= 1 => Ok(ChannelMode::Channel(n - 1)),
+                _ => anyhow::bail!(
+                    "Unknown channel mode \"{}\" (expected \"average\", \"mid\", \"side-aware\", \"left\", \"right\" or \"channel-N\")",
+                    other
+                ),
+            },
+        }
+    }
+}
 
 pub fn load_audio<P: AsRef<Path>>(path: P) -> Result<(Vec<f64>, u32)> {
+    load_audio_with(path, ChannelMode::default())
+}
+
+/// Decodes `path` and downmixes it as `mode` says, warning when the channels
+/// are so decorrelated that averaging them would cancel content.
+pub fn load_audio_with<P: AsRef<Path>>(path: P, mode: ChannelMode) -> Result<(Vec<f64>, u32)> {
+    let path = path.as_ref();
+    let (channels, sample_rate) = load_channels(path)?;
+    if channels.len() > 1 {
+        let correlation = channel_correlation(&channels);
+        debug!("{} has {} channels, correlation {:.2}", path.display(), channels.len(), correlation);
+        if correlation < CANCELLATION_CORRELATION && matches!(mode, ChannelMode::Average | ChannelMode::Mid) {
+            warn!(
+                "Channels of {} are weakly correlated ({:.2}); averaging them loses {:.1} dB and may comb-filter. \
+                 Consider channel-mode=\"side-aware\" or \"left\"",
+                path.display(), correlation, -downmix_gain_db(&channels)
+            );
+        }
+    }
+    let samples = downmix(&channels, mode, sample_rate)
+        .with_context(|| format!("Failed to downmix {}", path.display()))?;
+    info!("Loaded {} samples", samples.len());
+    Ok((samples, sample_rate))
+}
+
+/// Bytes read ahead to check a WAV header before decoding.
+const WAV_HEADER_PEEK: usize = 4096;
+
+/// Why a source could not be decoded, returned inside `anyhow::Error`.
+/// Each kind has its own process exit code, so hosts and scripts can tell
+/// a missing file from a damaged one.
+#[derive(Debug)]
+pub enum AudioError {
+    /// The file could not be opened or read.
+    Io(std::io::Error),
+    /// Not a format or codec that can be decoded.
+    Unsupported(String),
+    /// A packet could not be decoded.
+    Corrupt(String),
+    /// The stream has fewer frames than its header declares: it was cut
+    /// short, or damaged frames were skipped.
+    Truncated { expected: u64, decoded: u64 },
+    /// The container does not state a sample rate.
+    UnknownSampleRate,
+}
+
+impl AudioError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AudioError::Io(_) => 3,
+            AudioError::Unsupported(_) => 4,
+            AudioError::Corrupt(_) => 5,
+            AudioError::Truncated { .. } => 6,
+            AudioError::UnknownSampleRate => 7,
+        }
+    }
+
+    /// The `AudioError` behind `error`, if decoding a source is what failed.
+    pub fn find(error: &anyhow::Error) -> Option<&AudioError> {
+        error.chain().find_map(|e| e.downcast_ref::<AudioError>())
+    }
+
+    fn from_symphonia(error: SymphoniaError) -> Self {
+        match error {
+            SymphoniaError::IoError(e) => AudioError::Io(e),
+            SymphoniaError::Unsupported(what) => AudioError::Unsupported(what.to_string()),
+            other => AudioError::Corrupt(other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioError::Io(e) => write!(f, "I/O error: {}", e),
+            AudioError::Unsupported(what) => write!(f, "unsupported audio: {}", what),
+            AudioError::Corrupt(what) => write!(f, "corrupt audio data: {}", what),
+            AudioError::Truncated { expected, decoded } => {
+                write!(f, "truncated audio: {} of {} declared frames present", decoded, expected)
+            }
+            AudioError::UnknownSampleRate => write!(f, "the file does not state its sample rate"),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+/// Decodes every channel of `path`.
+pub fn load_channels<P: AsRef<Path>>(path: P) -> Result<(Vec<Vec<f64>>, u32)> {
     let path = path.as_ref();
     info!("Loading audio from {}", path.display());
-    
-    let file = File::open(path)
-        .with_context(|| format!("Failed to open audio file: {}", path.display()))?;
+    decode_channels(path).with_context(|| format!("Failed to decode {}", path.display()))
+}
+
+fn decode_channels(path: &Path) -> Result<(Vec<Vec<f64>>, u32), AudioError> {
+    let mut file = File::open(path).map_err(AudioError::Io)?;
+
+    // Symphonia panics on WAV files declaring a sample rate of 0
+    let mut header = Vec::with_capacity(WAV_HEADER_PEEK);
+    (&mut file).take(WAV_HEADER_PEEK as u64).read_to_end(&mut header).map_err(AudioError::Io)?;
+    if metadata::wav_sample_rate(&header) == Some(0) {
+        return Err(AudioError::UnknownSampleRate);
+    }
+    file.seek(SeekFrom::Start(0)).map_err(AudioError::Io)?;
     
     let mss = MediaSourceStream::new(Box::new(ReadOnlySource::new(BufReader::new(file))), Default::default());
     
@@ -31,35 +178,42 @@ pub fn load_audio<P: AsRef<Path>>(path: P) -> Result<(Vec<f64>, u32)> {
     
     let probed = get_probe()
         .format(&hint, mss, &fmt_opts, &meta_opts)
-        .with_context(|| "Failed to probe audio format")?;
+        .map_err(AudioError::from_symphonia)?;
     
     let mut format = probed.format;
     let track = format
         .tracks()
         .iter()
         .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .with_context(|| "No supported audio tracks found")?;
+        .ok_or_else(|| AudioError::Unsupported("no supported audio tracks".into()))?;
     
     let track_id = track.id;
     let codec_params = &track.codec_params;
-    let sample_rate = codec_params.sample_rate.unwrap_or(44100);
+    let sample_rate = match codec_params.sample_rate {
+        Some(rate) if rate > 0 => rate,
+        _ => return Err(AudioError::UnknownSampleRate),
+    };
+    let expected_frames = codec_params.n_frames;
     info!("Audio sample rate: {}Hz", sample_rate);
     
     let dec_opts: DecoderOptions = Default::default();
     let mut decoder = get_codecs()
         .make(&codec_params, &dec_opts)
-        .with_context(|| "Failed to create decoder")?;
+        .map_err(AudioError::from_symphonia)?;
     
-    let mut samples = Vec::new();
+    let mut channels: Vec<Vec<f64>> = Vec::new();
     
     loop {
         let packet = match format.next_packet() {
             Ok(packet) => packet,
-            Err(symphonia::core::errors::Error::ResetRequired) => {
+            Err(SymphoniaError::ResetRequired) => {
                 debug!("Decoder reset required");
                 continue;
             }
-            Err(_) => break,
+            // Symphonia reports the end of the stream as an unexpected EOF;
+            // a stream cut short is caught by the frame count below
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(AudioError::from_symphonia(e)),
         };
         
         if packet.track_id() != track_id {
@@ -75,98 +229,409 @@ pub fn load_audio<P: AsRef<Path>>(path: P) -> Result<(Vec<f64>, u32)> {
                     continue;
                 }
                 
-                let channels = spec.channels.count();
+                let count = spec.channels.count();
+                if channels.len() < count {
+                    channels.resize(count, Vec::new());
+                }
                 
                 let mut sample_buf = SampleBuffer::<f64>::new(duration, spec);
                 sample_buf.copy_interleaved_ref(decoded);
                 
-                if channels > 1 {
-                    let interleaved = sample_buf.samples();
-                    let mono_samples: Vec<f64> = interleaved
-                        .chunks(channels)
-                        .map(|chunk| chunk.iter().sum::<f64>() / channels as f64)
-                        .collect();
-                    samples.extend_from_slice(&mono_samples);
-                } else {
-                    samples.extend_from_slice(sample_buf.samples());
+                for frame in sample_buf.samples().chunks(count) {
+                    for (channel, &sample) in channels.iter_mut().zip(frame) {
+                        channel.push(sample);
+                    }
                 }
             }
-            Err(symphonia::core::errors::Error::DecodeError(_)) => {
-                debug!("Decode error encountered, skipping packet");
-                continue;
-            }
-            Err(symphonia::core::errors::Error::ResetRequired) => {
+            Err(SymphoniaError::ResetRequired) => {
                 debug!("Decoder reset required during decode");
                 continue;
             }
-            Err(e) => {
-                return Err(anyhow::anyhow!("Decode error: {}", e));
-            }
+            Err(e) => return Err(AudioError::from_symphonia(e)),
         }
     }
     
-    info!("Loaded {} samples", samples.len());
-    Ok((samples, sample_rate))
+    let decoded = channels.first().map_or(0, |c| c.len() as u64);
+    if let Some(expected) = expected_frames {
+        if decoded < expected {
+            return Err(AudioError::Truncated { expected, decoded });
+        }
+    }
+    Ok((channels, sample_rate))
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Lowest normalized zero-lag correlation between the first channel and
+/// any other; 1.0 for mono or silent sources.
+pub fn channel_correlation(channels: &[Vec<f64>]) -> f64 {
+    let Some((first, rest)) = channels.split_first() else { return 1.0 };
+    let first_energy = dot(first, first);
+    rest.iter()
+        .map(|channel| {
+            let energy = first_energy * dot(channel, channel);
+            if energy > 0.0 { dot(first, channel) / energy.sqrt() } else { 1.0 }
+        })
+        .fold(1.0, f64::min)
+}
+
+/// Energy of the channel average relative to the mean channel energy:
+/// 0 dB for identical channels, about -3 dB for unrelated ones.
+pub fn downmix_gain_db(channels: &[Vec<f64>]) -> f64 {
+    let mean_energy = channels.iter().map(|c| dot(c, c)).sum::<f64>() / channels.len() as f64;
+    let average = average(&channels.iter().map(Vec::as_slice).collect::<Vec<_>>());
+    10.0 * (dot(&average, &average) / mean_energy.max(f64::MIN_POSITIVE)).max(1e-12).log10()
+}
+
+fn average(channels: &[&[f64]]) -> Vec<f64> {
+    let len = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+    let mut out = vec![0.0; len];
+    for channel in channels {
+        for (o, &s) in out.iter_mut().zip(channel.iter()) {
+            *o += s / channels.len() as f64;
+        }
+    }
+    out
+}
+
+/// The delay of `channel` relative to `reference` within `max_lag` samples,
+/// and whether it is inverted, from the cross-correlation peak.
+fn alignment(reference: &[f64], channel: &[f64], max_lag: usize) -> (isize, bool) {
+    let max_lag = max_lag as isize;
+    let (lag, value) = (-max_lag..=max_lag)
+        .map(|lag| {
+            let value: f64 = (0..reference.len() as isize)
+                .filter_map(|i| channel.get(usize::try_from(i + lag).ok()?).map(|&c| reference[i as usize] * c))
+                .sum();
+            (lag, value)
+        })
+        .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+        .unwrap_or((0, 1.0));
+    (lag, value < 0.0)
+}
+
+/// Mixes `channels` down to mono as `mode` says.
+pub fn downmix(channels: &[Vec<f64>], mode: ChannelMode, sample_rate: u32) -> Result<Vec<f64>> {
+    let all: Vec<&[f64]> = channels.iter().map(Vec::as_slice).collect();
+    match mode {
+        _ if channels.len() <= 1 => Ok(channels.first().cloned().unwrap_or_default()),
+        ChannelMode::Average => Ok(average(&all)),
+        ChannelMode::Mid => Ok(average(&all[..2])),
+        ChannelMode::Channel(n) => match channels.get(n) {
+            Some(channel) => Ok(channel.clone()),
+            None => anyhow::bail!("channel {} requested but the source has {} channels", n + 1, channels.len()),
+        },
+        ChannelMode::SideAware => {
+            let max_lag = (sample_rate as f64 * MAX_CHANNEL_DELAY).round() as usize;
+            let aligned: Vec<Vec<f64>> = channels
+                .iter()
+                .enumerate()
+                .map(|(c, channel)| {
+                    let (lag, inverted) = if c == 0 { (0, false) } else { alignment(&channels[0], channel, max_lag) };
+                    if lag != 0 || inverted {
+                        info!("Aligning channel {} by {} samples{}", c + 1, lag, if inverted { ", inverted" } else { "" });
+                    }
+                    let sign = if inverted { -1.0 } else { 1.0 };
+                    (0..channel.len() as isize)
+                        .map(|i| channel.get(usize::try_from(i + lag).unwrap_or(usize::MAX)).map_or(0.0, |&s| sign * s))
+                        .collect()
+                })
+                .collect();
+            Ok(average(&aligned.iter().map(Vec::as_slice).collect::<Vec<_>>()))
+        }
+    }
+}
+
+/// Sample coding and container of rendered notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 16-bit PCM WAV with TPDF dither, readable by every UTAU wavtool.
+    Wav16,
+    Wav24,
+    /// 32-bit float WAV, keeping peaks above full scale.
+    WavFloat,
+    /// 16-bit FLAC with TPDF dither.
+    Flac16,
+    Flac24,
+}
+
+impl OutputFormat {
+    /// The format for `path` when `output-format` is not set: FLAC for
+    /// `.flac` files, 16-bit WAV otherwise.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+            Some("flac") => OutputFormat::Flac24,
+            _ => OutputFormat::Wav16,
+        }
+    }
+
+    /// `setting` (the `output-format` value) if given, otherwise the format for `path`.
+    pub fn resolve(path: &Path, setting: Option<&str>) -> Result<Self> {
+        setting.map_or(Ok(Self::for_path(path)), str::parse)
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wav16" => Ok(OutputFormat::Wav16),
+            "wav24" => Ok(OutputFormat::Wav24),
+            "wav32f" => Ok(OutputFormat::WavFloat),
+            "flac16" => Ok(OutputFormat::Flac16),
+            "flac24" => Ok(OutputFormat::Flac24),
+            other => anyhow::bail!(
+                "Unknown output format \"{}\" (expected \"wav16\", \"wav24\", \"wav32f\", \"flac16\" or \"flac24\")",
+                other
+            ),
+        }
+    }
 }
 
+/// Writes `samples` as `format`. WAV files also get the cue points, tags
+/// and loops of `metadata`; FLAC output has no place for them.
 pub fn save_audio<P: AsRef<Path>>(
     path: P,
     samples: &[f64],
     sample_rate: u32,
+    format: OutputFormat,
+    metadata: &WavMetadata,
+    seed: u64,
 ) -> Result<()> {
     let path = path.as_ref();
-    info!("Saving audio to {}", path.display());
-    
-    let ext = path
-        .extension()
-        .and_then(|e| e.to_str())
-        .unwrap_or("wav");
-    
-    match ext.to_lowercase().as_str() {
-        "wav" => write_wav(path, samples, sample_rate),
-        _ => write_wav(path, samples, sample_rate),
-    }
+    info!("Saving audio to {} as {:?}", path.display(), format);
+
+    // Hosts expect a file even for an empty note
+    let samples = if samples.is_empty() { &[0.0][..] } else { samples };
+    let bytes = match format {
+        OutputFormat::Wav16 => wav_bytes(&quantize(samples, 16, seed), sample_rate, 16, metadata),
+        OutputFormat::Wav24 => wav_bytes(&quantize(samples, 24, seed), sample_rate, 24, metadata),
+        OutputFormat::WavFloat => wav_float_bytes(samples, sample_rate, metadata),
+        OutputFormat::Flac16 | OutputFormat::Flac24 => {
+            if !metadata.is_empty() {
+                debug!("Dropping {} cue point(s) for FLAC output", metadata.cues.len());
+            }
+            let bits = if format == OutputFormat::Flac16 { 16 } else { 24 };
+            flac::encode(&quantize(samples, bits, seed), sample_rate, bits)
+        }
+    };
+    std::fs::write(path, bytes)
+        .with_context(|| format!("Failed to write {}", path.display()))
 }
 
-fn write_wav<P: AsRef<Path>>(path: P, samples: &[f64], sample_rate: u32) -> Result<()> {
-    use std::io::Write;
-    
-    let mut file = File::create(path)?;
-    
+/// Rounds `samples` to `bits`-bit integers, clipping at full scale. 16-bit
+/// output gets triangular (TPDF) dither of ±1 LSB so that quiet tails fade
+/// into noise instead of truncation distortion. The dither comes from the
+/// note's synthesis `seed`: a note renders to identical bytes every time,
+/// while overlapping notes get uncorrelated dither.
+fn quantize(samples: &[f64], bits: u32, seed: u64) -> Vec<i32> {
+    let scale = (1i64 << (bits - 1)) as f64;
+    let (min, max) = (-scale, scale - 1.0);
+    let mut rng = crate::util::seeded_rng(seed, crate::util::DITHER_STREAM);
+    samples
+        .iter()
+        .map(|&s| {
+            let dither = if bits <= 16 { rng.gen::<f64>() - rng.gen::<f64>() } else { 0.0 };
+            (s * scale + dither).round().clamp(min, max) as i32
+        })
+        .collect()
+}
+
+/// A WAV file of `data`, followed by the chunks of `metadata`.
+fn wav_file(format_tag: u16, sample_rate: u32, bits_per_sample: u16, data: &[u8], metadata: &WavMetadata) -> Vec<u8> {
     let num_channels = 1u16;
-    let bits_per_sample = 16u16;
-    let byte_rate = sample_rate as u32 * num_channels as u32 * (bits_per_sample / 8) as u32;
     let block_align = num_channels * (bits_per_sample / 8);
-    let data_size = samples.len() * 2;
-    
-    debug!("Writing WAV: channels={}, bits={}, rate={}, size={}", num_channels, bits_per_sample, sample_rate, data_size);
+    let byte_rate = sample_rate * block_align as u32;
+    // Non-PCM formats need the extension size field and a fact chunk
+    let extended = format_tag != 1;
+    let fmt_size: u32 = if extended { 18 } else { 16 };
+    let chunks = metadata.to_chunks();
 
-    file.write_all(b"RIFF")?;
-    file.write_all(&((36 + data_size) as u32).to_le_bytes())?;
-    file.write_all(b"WAVE")?;
-    
-    file.write_all(b"fmt ")?;
-    file.write_all(&16u32.to_le_bytes())?;
-    file.write_all(&1u16.to_le_bytes())?;
-    file.write_all(&num_channels.to_le_bytes())?;
-    file.write_all(&sample_rate.to_le_bytes())?;
-    file.write_all(&byte_rate.to_le_bytes())?;
-    file.write_all(&block_align.to_le_bytes())?;
-    file.write_all(&bits_per_sample.to_le_bytes())?;
-    
-    file.write_all(b"data")?;
-    file.write_all(&(data_size as u32).to_le_bytes())?;
-    
-    if samples.is_empty() {
-        let silent: i16 = 0;
-        file.write_all(&silent.to_le_bytes())?;
-    } else {
-        for &sample in samples {
-            let clamped = sample.max(-1.0).min(1.0);
-            let int_sample = (clamped * 32767.0) as i16;
-            file.write_all(&int_sample.to_le_bytes())?;
+    debug!("Writing WAV: format={}, channels={}, bits={}, rate={}, size={}, metadata={} bytes",
+        format_tag, num_channels, bits_per_sample, sample_rate, data.len(), chunks.len());
+
+    let mut out = Vec::with_capacity(58 + data.len() + 1 + chunks.len());
+    out.extend_from_slice(b"RIFF");
+    // Patched once the size is known
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&fmt_size.to_le_bytes());
+    out.extend_from_slice(&format_tag.to_le_bytes());
+    out.extend_from_slice(&num_channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&byte_rate.to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&bits_per_sample.to_le_bytes());
+    if extended {
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(b"fact");
+        out.extend_from_slice(&4u32.to_le_bytes());
+        out.extend_from_slice(&((data.len() / block_align as usize) as u32).to_le_bytes());
+    }
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    // Chunks start on even offsets
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+    out.extend_from_slice(&chunks);
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    out
+}
+
+fn wav_bytes(samples: &[i32], sample_rate: u32, bits_per_sample: u16, metadata: &WavMetadata) -> Vec<u8> {
+    let width = bits_per_sample as usize / 8;
+    let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes().into_iter().take(width)).collect();
+    wav_file(1, sample_rate, bits_per_sample, &data, metadata)
+}
+
+fn wav_float_bytes(samples: &[f64], sample_rate: u32, metadata: &WavMetadata) -> Vec<u8> {
+    let data: Vec<u8> = samples.iter().flat_map(|&s| (s as f32).to_le_bytes()).collect();
+    wav_file(3, sample_rate, 32, &data, metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::CuePoint;
+
+    fn test_signal() -> Vec<f64> {
+        (0..10000)
+            .map(|i| {
+                let t = i as f64 / 44100.0;
+                // A decaying tone that ends below one 16-bit step, plus an overshoot
+                0.8 * (2.0 * std::f64::consts::PI * 220.0 * t).sin() * (-t * 30.0).exp() + if i == 10 { 1.0 } else { 0.0 }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_output_formats_round_trip() {
+        let dir = std::env::temp_dir().join(format!("axis_audio_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let signal = test_signal();
+
+        for (format, name, tolerance) in [
+            (OutputFormat::Wav16, "out.wav", 1.5 / 32768.0),
+            (OutputFormat::Wav24, "out24.wav", 1.0 / 8388608.0),
+            (OutputFormat::WavFloat, "outf.wav", 1e-7),
+            (OutputFormat::Flac16, "out16.flac", 1.5 / 32768.0),
+            (OutputFormat::Flac24, "out.flac", 1.0 / 8388608.0),
+        ] {
+            let path = dir.join(name);
+            save_audio(&path, &signal, 44100, format, &WavMetadata::default(), 0).unwrap();
+            let (decoded, rate) = load_audio(&path).unwrap();
+            assert_eq!(rate, 44100, "{:?}", format);
+            assert_eq!(decoded.len(), signal.len(), "{:?}", format);
+            let max_error = signal.iter().zip(&decoded).map(|(&a, b)| (if format == OutputFormat::WavFloat { a } else { a.clamp(-1.0, 1.0) } - b).abs()).fold(0.0, f64::max);
+            assert!(max_error <= tolerance, "{:?}: error {}", format, max_error);
         }
+
+        // Markers follow the odd-sized 24-bit data chunk and do not disturb decoding
+        let metadata = WavMetadata { sample_rate: 44100, cues: vec![CuePoint { position: 441, label: "consonant end".into() }], ..WavMetadata::default() };
+        let path = dir.join("cues.wav");
+        save_audio(&path, &signal[..999], 44100, OutputFormat::Wav24, &metadata, 0).unwrap();
+        assert_eq!(WavMetadata::read(&path).unwrap(), metadata);
+        assert_eq!(load_audio(&path).unwrap().0.len(), 999);
+
+        // Float keeps the overshoot that integer formats clip
+        let (decoded, _) = load_audio(dir.join("outf.wav")).unwrap();
+        assert!(decoded[10] > 1.0);
+        let flac_size = std::fs::metadata(dir.join("out.flac")).unwrap().len();
+        let wav_size = std::fs::metadata(dir.join("out24.wav")).unwrap().len();
+        assert!(flac_size < wav_size / 2, "FLAC {} bytes, WAV {} bytes", flac_size, wav_size);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_channel_modes() {
+        let rate = 44100;
+        let voice: Vec<f64> = (0..4410).map(|i| (i as f64 * 0.07).sin() + 0.5 * (i as f64 * 0.23).sin()).collect();
+        // The right microphone hears the voice 5 samples later and wired in reverse polarity
+        let right: Vec<f64> = (0..voice.len()).map(|i| if i < 5 { 0.0 } else { -voice[i - 5] }).collect();
+        let channels = vec![voice.clone(), right];
+
+        assert!(channel_correlation(&channels) < CANCELLATION_CORRELATION);
+        assert!(downmix_gain_db(&channels) < -6.0);
+        assert!(channel_correlation(&[voice.clone(), voice.clone()]) > 0.999);
+
+        let energy = |x: &[f64]| dot(&x[100..4000], &x[100..4000]);
+        let averaged = downmix(&channels, ChannelMode::Average, rate).unwrap();
+        let aligned = downmix(&channels, ChannelMode::SideAware, rate).unwrap();
+        assert!(energy(&averaged) < 0.5 * energy(&voice));
+        let error = (100..4000).map(|i| (aligned[i] - voice[i]).abs()).fold(0.0, f64::max);
+        assert!(error < 1e-9, "side-aware error {}", error);
+
+        assert_eq!(downmix(&channels, ChannelMode::Channel(1), rate).unwrap(), channels[1]);
+        assert!(downmix(&channels, ChannelMode::Channel(2), rate).is_err());
+        assert_eq!("channel-2".parse::<ChannelMode>().unwrap(), ChannelMode::Channel(1));
+        assert!("channel-0".parse::<ChannelMode>().is_err());
+    }
+
+    #[test]
+    fn test_dither_decorrelates_quiet_signals() {
+        // A tone at a third of an LSB truncates to silence without dither
+        let quiet: Vec<f64> = (0..44100).map(|i| (i as f64 * 0.05).sin() / 3.0 / 32768.0).collect();
+        let quantized = quantize(&quiet, 16, 7);
+        assert!(quantized.iter().any(|&s| s != 0));
+        let correlation: f64 = quiet.iter().zip(&quantized).map(|(a, &b)| a * b as f64).sum();
+        assert!(correlation > 0.0);
+        assert!(quantized.iter().all(|s| s.abs() <= 1));
+        assert_eq!(quantized, quantize(&quiet, 16, 7));
+
+        // Notes with different seeds must not add up identical dither where they overlap
+        let other = quantize(&quiet, 16, 8);
+        let matching = quantized.iter().zip(&other).filter(|(a, b)| a == b).count();
+        assert!(matching < quiet.len() * 3 / 4, "{} of {} samples match", matching, quiet.len());
+    }
+
+    #[test]
+    fn test_audio_error_fixtures() {
+        let dir = std::env::temp_dir().join(format!("axis_audio_errors_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let signal: Vec<f64> = (0..20000).map(|i| (i as f64 * 0.05).sin() * 0.5).collect();
+        save_audio(dir.join("ok.wav"), &signal, 44100, OutputFormat::Wav16, &WavMetadata::default(), 0).unwrap();
+        save_audio(dir.join("ok.flac"), &signal, 44100, OutputFormat::Flac16, &WavMetadata::default(), 0).unwrap();
+        let wav = std::fs::read(dir.join("ok.wav")).unwrap();
+        let flac = std::fs::read(dir.join("ok.flac")).unwrap();
+
+        let mut fixtures: Vec<(&str, Vec<u8>)> = vec![("junk.wav", b"RIFX this is not audio at all".to_vec())];
+        // The data chunk still declares all 20000 frames
+        fixtures.push(("truncated.wav", wav[..wav.len() - 1001].to_vec()));
+        let mut damaged = flac.clone();
+        let middle = damaged.len() / 2;
+        damaged[middle..middle + 40].iter_mut().for_each(|b| *b ^= 0x5a);
+        fixtures.push(("damaged.flac", damaged));
+        let mut bad_fmt = wav.clone();
+        bad_fmt[34..36].copy_from_slice(&13u16.to_le_bytes());
+        fixtures.push(("bad_fmt.wav", bad_fmt));
+        let mut no_rate = wav.clone();
+        no_rate[24..28].copy_from_slice(&0u32.to_le_bytes());
+        fixtures.push(("no_rate.wav", no_rate));
+        for (name, bytes) in &fixtures {
+            std::fs::write(dir.join(name), bytes).unwrap();
+        }
+
+        let error = |name: &str| load_audio(dir.join(name)).expect_err(name);
+        let kind = |name: &str| AudioError::find(&error(name)).map(|e| e.exit_code());
+        assert_eq!(kind("missing.wav"), Some(3));
+        assert_eq!(kind("junk.wav"), Some(4));
+        assert_eq!(kind("bad_fmt.wav"), Some(5));
+        assert_eq!(kind("no_rate.wav"), Some(7));
+        for name in ["truncated.wav", "damaged.flac"] {
+            assert!(matches!(AudioError::find(&error(name)), Some(AudioError::Truncated { expected: 20000, .. })), "{}", name);
+        }
+        assert!(format!("{:#}", error("truncated.wav")).contains("truncated.wav"));
+        assert_eq!(load_audio(dir.join("ok.flac")).unwrap().0.len(), 20000);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-    
-    Ok(())
 }
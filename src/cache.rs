@@ -0,0 +1,199 @@
+use crate::analysis::AnalysisHeader;
+use crate::api::AxisConfig;
+use crate::util::Fnv1a;
+use anyhow::{Context, Result};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Size limit of the central cache when `cache-size-mb` is not set.
+pub const DEFAULT_SIZE_MB: u64 = 2048;
+
+/// Temporary files older than this are left over from a crashed write.
+const STALE_TMP_AGE: Duration = Duration::from_secs(3600);
+
+/// Central, content-addressed store of `.axxf` files. Entries are named
+/// after a hash of their header, which covers the source audio and every
+/// analysis parameter, so identical samples in different voicebanks share
+/// one entry. The least recently used entries are evicted past `max_bytes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisCache {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl AnalysisCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    /// The cache configured by `cache-dir` and `cache-size-mb`, whether or not `central-cache` is on.
+    pub fn from_config(config: &AxisConfig) -> Result<Self> {
+        let general = config.general.as_ref();
+        let dir = match general.and_then(|g| g.cache_dir.as_ref()) {
+            Some(dir) => PathBuf::from(dir),
+            None => crate::util::project_dirs()
+                .context("Could not determine project directories")?
+                .data_dir()
+                .join("analysis-cache"),
+        };
+        let max_mb = general.and_then(|g| g.cache_size_mb).unwrap_or(DEFAULT_SIZE_MB);
+        Ok(Self::new(dir, max_mb * 1024 * 1024))
+    }
+
+    /// Where the analysis described by `header` is stored.
+    pub fn path_for(&self, header: &AnalysisHeader) -> PathBuf {
+        let mut hasher = Fnv1a::default();
+        hasher.write(&bincode::serialize(header).unwrap_or_default());
+        self.dir.join(format!("{:016x}.axxf", hasher.finish()))
+    }
+
+    /// Marks an entry as used, for LRU eviction.
+    pub fn touch(&self, path: &Path) {
+        if let Ok(file) = std::fs::File::options().write(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let read_dir = match std::fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.dir.display())),
+        };
+        let mut entries = Vec::new();
+        for entry in read_dir {
+            let path = entry?.path();
+            // Entries removed by a concurrent gc are skipped
+            let Ok(metadata) = std::fs::metadata(&path) else { continue };
+            if metadata.is_file() {
+                entries.push(Entry { path, size: metadata.len(), last_used: metadata.modified()? });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn is_entry(path: &Path) -> bool {
+        path.extension().is_some_and(|e| e == "axxf")
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let entries = self.entries()?;
+        let entries = entries.iter().filter(|e| Self::is_entry(&e.path));
+        Ok(CacheStats { files: entries.clone().count(), bytes: entries.map(|e| e.size).sum() })
+    }
+
+    /// Removes every entry, returning what was removed.
+    pub fn clear(&self) -> Result<CacheStats> {
+        let mut removed = CacheStats::default();
+        for entry in self.entries()? {
+            if Self::is_entry(&entry.path) && Self::remove(&entry.path)? {
+                removed.files += 1;
+                removed.bytes += entry.size;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Evicts the least recently used entries until the cache fits in
+    /// `max_bytes`, and deletes stale temporary files. Returns what was evicted.
+    pub fn gc(&self) -> Result<CacheStats> {
+        self.evict(None)
+    }
+
+    /// `gc`, but never evicting `keep`, such as an entry that was just written.
+    pub fn gc_keeping(&self, keep: &Path) -> Result<CacheStats> {
+        self.evict(Some(keep))
+    }
+
+    fn evict(&self, keep: Option<&Path>) -> Result<CacheStats> {
+        let (mut entries, others): (Vec<Entry>, Vec<Entry>) = self.entries()?.into_iter().partition(|e| Self::is_entry(&e.path));
+        let now = SystemTime::now();
+        for tmp in others {
+            if now.duration_since(tmp.last_used).unwrap_or_default() > STALE_TMP_AGE {
+                Self::remove(&tmp.path)?;
+            }
+        }
+
+        entries.sort_by_key(|e| e.last_used);
+        let mut total: u64 = entries.iter().map(|e| e.size).sum();
+        let mut evicted = CacheStats::default();
+        for entry in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if keep == Some(entry.path.as_path()) {
+                continue;
+            }
+            total -= entry.size;
+            if Self::remove(&entry.path)? {
+                evicted.files += 1;
+                evicted.bytes += entry.size;
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Whether the file was removed here; another process may have beaten us to it.
+    fn remove(path: &Path) -> Result<bool> {
+        match std::fs::remove_file(path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+        }
+    }
+}
+
+/// `bytes` in MB with one decimal, for reports.
+pub fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_entry(dir: &Path, name: &str, size: usize, age_secs: u64) {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![0u8; size]).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age_secs)).unwrap();
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let dir = std::env::temp_dir().join(format!("axis_cache_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = AnalysisCache::new(dir.clone(), 2500);
+        write_entry(&dir, "old.axxf", 1000, 300);
+        write_entry(&dir, "used.axxf", 1000, 200);
+        write_entry(&dir, "new.axxf", 1000, 100);
+        write_entry(&dir, "crashed.axxf.1-0.tmp", 10, 7200);
+        write_entry(&dir, "writing.axxf.2-0.tmp", 10, 0);
+        assert_eq!(cache.stats().unwrap(), CacheStats { files: 3, bytes: 3000 });
+
+        // Using an entry protects it from eviction
+        cache.touch(&dir.join("old.axxf"));
+        assert_eq!(cache.gc().unwrap(), CacheStats { files: 1, bytes: 1000 });
+        assert!(dir.join("old.axxf").exists() && !dir.join("used.axxf").exists() && dir.join("new.axxf").exists());
+        assert!(!dir.join("crashed.axxf.1-0.tmp").exists() && dir.join("writing.axxf.2-0.tmp").exists());
+        assert_eq!(cache.gc().unwrap(), CacheStats::default());
+
+        assert_eq!(cache.clear().unwrap(), CacheStats { files: 2, bytes: 2000 });
+        assert_eq!(cache.stats().unwrap(), CacheStats::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(cache.stats().unwrap(), CacheStats::default());
+    }
+}
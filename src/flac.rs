@@ -0,0 +1,315 @@
+//! A small FLAC encoder for mono integer PCM: each frame picks the cheapest
+//! of a constant, verbatim or fixed-predictor (order 0-4) subframe, with
+//! partitioned Rice coding of the residual.
+
+/// Samples per frame.
+const BLOCK_SIZE: usize = 4096;
+
+const MAX_FIXED_ORDER: usize = 4;
+
+const MAX_PARTITION_ORDER: u32 = 6;
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self { bytes: Vec::new(), acc: 0, bits: 0 }
+    }
+
+    /// Writes the low `n` bits of `value`, most significant first. `n` is at most 56.
+    fn write(&mut self, value: u64, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1u64 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, n: u32) {
+        self.write(value as u64, n);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        let mut left = zeros;
+        while left >= 32 {
+            self.write(0, 32);
+            left -= 32;
+        }
+        self.write(1, left as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |c, _| if c & 0x80 != 0 { (c << 1) ^ 0x07 } else { c << 1 })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |c, _| if c & 0x8000 != 0 { (c << 1) ^ 0x8005 } else { c << 1 })
+    })
+}
+
+/// Encodes `samples`, already quantized to `bits_per_sample` (4-24) signed integers, as a FLAC stream.
+pub fn encode(samples: &[i32], sample_rate: u32, bits_per_sample: u32) -> Vec<u8> {
+    let mut out = BitWriter::new();
+    out.bytes.extend_from_slice(b"fLaC");
+
+    // STREAMINFO, the only metadata block. Frame sizes and the MD5 are left unknown.
+    out.write(1, 1);
+    out.write(0, 7);
+    out.write(34, 24);
+    out.write(BLOCK_SIZE as u64, 16);
+    out.write(BLOCK_SIZE as u64, 16);
+    out.write(0, 24);
+    out.write(0, 24);
+    out.write(sample_rate as u64, 20);
+    out.write(0, 3);
+    out.write(bits_per_sample as u64 - 1, 5);
+    out.write(samples.len() as u64, 36);
+    for _ in 0..4 {
+        out.write(0, 32);
+    }
+
+    for (number, block) in samples.chunks(BLOCK_SIZE).enumerate() {
+        let frame = encode_frame(block, number as u64, sample_rate, bits_per_sample);
+        out.bytes.extend_from_slice(&frame);
+    }
+    out.bytes
+}
+
+fn sample_rate_code(sample_rate: u32) -> (u64, Option<(u64, u32)>) {
+    match sample_rate {
+        88200 => (0b0001, None),
+        176400 => (0b0010, None),
+        192000 => (0b0011, None),
+        8000 => (0b0100, None),
+        16000 => (0b0101, None),
+        22050 => (0b1000, None),
+        24000 => (0b0110, None),
+        32000 => (0b0111, None),
+        44100 => (0b1001, None),
+        48000 => (0b1010, None),
+        96000 => (0b1011, None),
+        rate if rate < 1 << 16 => (0b1101, Some((rate as u64, 16))),
+        _ => (0b0000, None),
+    }
+}
+
+/// The frame number in FLAC's extended UTF-8 coding.
+fn write_utf8(w: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        w.write(value, 8);
+        return;
+    }
+    let mut continuation = 1;
+    while value >= 1 << (6 * continuation + 6 - continuation) {
+        continuation += 1;
+    }
+    let marker = (0xff00u64 >> (continuation + 1)) & 0xff;
+    w.write(marker | (value >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        w.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+fn encode_frame(block: &[i32], number: u64, sample_rate: u32, bits_per_sample: u32) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    w.write(0b11111111111110, 14);
+    w.write(0, 1);
+    // Fixed blocking strategy
+    w.write(0, 1);
+    // Block size as a 16-bit value after the frame number
+    w.write(0b0111, 4);
+    let (rate_code, rate_extra) = sample_rate_code(sample_rate);
+    w.write(rate_code, 4);
+    // Mono
+    w.write(0, 4);
+    let size_code = match bits_per_sample {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    };
+    w.write(size_code, 3);
+    w.write(0, 1);
+    write_utf8(&mut w, number);
+    w.write(block.len() as u64 - 1, 16);
+    if let Some((value, bits)) = rate_extra {
+        w.write(value, bits);
+    }
+    let crc = crc8(&w.bytes);
+    w.write(crc as u64, 8);
+
+    encode_subframe(&mut w, block, bits_per_sample);
+    w.align();
+    let crc = crc16(&w.bytes);
+    w.write(crc as u64, 16);
+    w.bytes
+}
+
+fn encode_subframe(w: &mut BitWriter, block: &[i32], bps: u32) {
+    let verbatim_bits = block.len() as u64 * bps as u64;
+    if block.iter().all(|&s| s == block[0]) {
+        w.write(0, 1);
+        w.write(0b000000, 6);
+        w.write(0, 1);
+        w.write_signed(block[0] as i64, bps);
+        return;
+    }
+
+    let best = (0..=MAX_FIXED_ORDER.min(block.len() - 1))
+        .map(|order| {
+            let residual = fixed_residual(block, order);
+            let rice = plan_rice(&residual, order, block.len());
+            let bits = order as u64 * bps as u64 + rice.bits;
+            (order, residual, rice, bits)
+        })
+        .min_by_key(|(.., bits)| *bits);
+
+    match best {
+        Some((order, residual, rice, bits)) if bits < verbatim_bits => {
+            w.write(0, 1);
+            w.write(0b001000 | order as u64, 6);
+            w.write(0, 1);
+            for &s in &block[..order] {
+                w.write_signed(s as i64, bps);
+            }
+            write_residual(w, &residual, &rice, order, block.len());
+        }
+        _ => {
+            w.write(0, 1);
+            w.write(0b000001, 6);
+            w.write(0, 1);
+            for &s in block {
+                w.write_signed(s as i64, bps);
+            }
+        }
+    }
+}
+
+/// Residual of the fixed polynomial predictor of `order`, from sample `order` on.
+fn fixed_residual(block: &[i32], order: usize) -> Vec<i64> {
+    let s = |i: usize| block[i] as i64;
+    (order..block.len())
+        .map(|i| match order {
+            0 => s(i),
+            1 => s(i) - s(i - 1),
+            2 => s(i) - 2 * s(i - 1) + s(i - 2),
+            3 => s(i) - 3 * s(i - 1) + 3 * s(i - 2) - s(i - 3),
+            _ => s(i) - 4 * s(i - 1) + 6 * s(i - 2) - 4 * s(i - 3) + s(i - 4),
+        })
+        .collect()
+}
+
+struct RicePlan {
+    partition_order: u32,
+    params: Vec<u32>,
+    /// Whether a parameter needs the 5-bit coding.
+    wide: bool,
+    bits: u64,
+}
+
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Bits for `values` Rice coded with the best parameter near their mean.
+fn best_param(values: &[i64]) -> (u32, u64) {
+    let sum: u64 = values.iter().map(|&r| zigzag(r)).sum();
+    let mean = sum / values.len().max(1) as u64;
+    let guess = 64 - mean.leading_zeros();
+    let cost = |k: u32| values.iter().map(|&r| zigzag(r) >> k).sum::<u64>() + values.len() as u64 * (k as u64 + 1);
+    [guess.saturating_sub(1), guess, (guess + 1).min(30)]
+        .into_iter()
+        .map(|k| (k, cost(k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+fn plan_rice(residual: &[i64], order: usize, block_len: usize) -> RicePlan {
+    let mut best: Option<RicePlan> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        let size = block_len >> partition_order;
+        if !block_len.is_multiple_of(partitions) || size <= order {
+            break;
+        }
+        let mut params = Vec::with_capacity(partitions);
+        let mut bits = 0;
+        let mut start = 0;
+        for p in 0..partitions {
+            let len = if p == 0 { size - order } else { size };
+            let (k, cost) = best_param(&residual[start..start + len]);
+            params.push(k);
+            bits += cost;
+            start += len;
+        }
+        let wide = params.iter().any(|&k| k > 14);
+        bits += 2 + 4 + partitions as u64 * if wide { 5 } else { 4 };
+        if best.as_ref().is_none_or(|b| bits < b.bits) {
+            best = Some(RicePlan { partition_order, params, wide, bits });
+        }
+    }
+    best.expect("partition order 0 always fits")
+}
+
+fn write_residual(w: &mut BitWriter, residual: &[i64], plan: &RicePlan, order: usize, block_len: usize) {
+    w.write(if plan.wide { 0b01 } else { 0b00 }, 2);
+    w.write(plan.partition_order as u64, 4);
+    let size = block_len >> plan.partition_order;
+    let mut start = 0;
+    for (p, &k) in plan.params.iter().enumerate() {
+        let len = if p == 0 { size - order } else { size };
+        w.write(k as u64, if plan.wide { 5 } else { 4 });
+        for &r in &residual[start..start + len] {
+            let u = zigzag(r);
+            w.write_unary(u >> k);
+            w.write(u, k);
+        }
+        start += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc_check_values() {
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+
+    #[test]
+    fn test_frame_number_coding() {
+        let coded = |n: u64| {
+            let mut w = BitWriter::new();
+            write_utf8(&mut w, n);
+            w.bytes
+        };
+        assert_eq!(coded(0x7f), [0x7f]);
+        assert_eq!(coded(0x80), [0xc2, 0x80]);
+        assert_eq!(coded(0x7ff), [0xdf, 0xbf]);
+        assert_eq!(coded(0x800), [0xe0, 0xa0, 0x80]);
+        assert_eq!(coded(1 << 35), [0xfe, 0xa0, 0x80, 0x80, 0x80, 0x80, 0x80]);
+    }
+}
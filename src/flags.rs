@@ -1,6 +1,8 @@
 pub struct Flags {
     pub gender: f64,
     pub breathiness: f64,
+    /// `S`: seed for the synthesis noise, overriding the one derived from the note.
+    pub seed: Option<u64>,
 }
 
 impl std::str::FromStr for Flags {
@@ -9,6 +11,7 @@ impl std::str::FromStr for Flags {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut gender = 0.0;
         let mut breathiness = 50.0;
+        let mut seed = None;
 
         let s = s.replace("/", "");
         
@@ -36,10 +39,20 @@ impl std::str::FromStr for Flags {
                     }
                     i = start;
                 }
+                'S' | 's' => {
+                    let mut start = i + 1;
+                    while start < chars.len() && chars[start].is_ascii_digit() {
+                        start += 1;
+                    }
+                    if let Ok(val) = s[i+1..start].parse::<u64>() {
+                        seed = Some(val);
+                    }
+                    i = start;
+                }
                 _ => i += 1,
             }
         }
 
-        Ok(Flags { gender, breathiness })
+        Ok(Flags { gender, breathiness, seed })
     }
 }
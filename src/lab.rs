@@ -0,0 +1,75 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// Unvoiced labels, compared case-insensitively: silences, breaths, closures
+/// and voiceless consonants of the romaji and ARPAbet-like sets used by
+/// voicebank labels.
+const UNVOICED_LABELS: &[&str] = &[
+    "uv", "unvoiced", "sil", "pau", "sp", "ap", "br", "cl", "q", "-",
+    "p", "t", "k", "s", "sh", "f", "h", "ch", "ts", "hh", "th", "ky", "py", "hy", "fy",
+];
+
+/// Unvoiced labels whose lowercase forms are voiced: devoiced vowels and the UTAU rest.
+const UNVOICED_EXACT: &[&str] = &["U", "I", "R"];
+
+/// One segment of an HTK-style `.lab` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    /// Seconds.
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+impl Label {
+    /// Labels are read as phonemes, voiced unless they are a silence or a
+    /// voiceless consonant. `uv`/`unvoiced` can mark unvoiced frames directly.
+    pub fn is_voiced(&self) -> bool {
+        let lower = self.text.to_ascii_lowercase();
+        !UNVOICED_LABELS.contains(&lower.as_str()) && !UNVOICED_EXACT.contains(&self.text.as_str())
+    }
+}
+
+/// Parses `start end label` lines with times in 100 ns units.
+pub fn parse_lab(content: &str) -> Result<Vec<Label>> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [start, end, text] = fields[..] else {
+                bail!("line {}: expected `start end label`", i + 1);
+            };
+            let time = |s: &str| s.parse::<f64>().map(|t| t / 1e7).with_context(|| format!("line {}: invalid time {:?}", i + 1, s));
+            Ok(Label { start: time(start)?, end: time(end)?, text: text.to_string() })
+        })
+        .collect()
+}
+
+pub fn read_lab(path: &Path) -> Result<Vec<Label>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_lab(&content).with_context(|| format!("Invalid label file {}", path.display()))
+}
+
+/// The label covering `time` seconds, if any.
+pub fn label_at(labels: &[Label], time: f64) -> Option<&Label> {
+    labels.iter().find(|l| l.start <= time && time < l.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lab() {
+        let labels = parse_lab("0 1500000 pau\n1500000 2500000 k\n2500000 5000000 a\n\n5000000 6000000 U\n6000000 6500000 u\n").unwrap();
+        assert_eq!(labels.len(), 5);
+        assert_eq!(labels[1], Label { start: 0.15, end: 0.25, text: "k".into() });
+        let voiced: Vec<bool> = labels.iter().map(Label::is_voiced).collect();
+        assert_eq!(voiced, [false, false, true, false, true]);
+        assert_eq!(label_at(&labels, 0.3).map(|l| l.text.as_str()), Some("a"));
+        assert!(label_at(&labels, 0.7).is_none());
+        assert!(parse_lab("0 10 a b\n").is_err());
+    }
+}
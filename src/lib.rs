@@ -1,8 +1,17 @@
+pub mod analysis;
 pub mod api;
 pub mod args;
 pub mod audio;
+pub mod cache;
 pub mod resampler;
+pub mod server;
+pub mod session;
 pub mod flags;
+pub mod lab;
+pub mod metadata;
+pub mod oto;
+pub mod rate;
 pub mod filter;
+pub mod flac;
 pub mod util;
 pub mod vocoder;
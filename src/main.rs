@@ -1,23 +1,37 @@
 use anyhow::{Context, Result};
 use clap::Parser;
+use std::path::Path;
 use std::process;
-use axis::args::{Cli, Commands, PluginAction};
-use axis::api::PluginDatabase;
-use axis::audio;
-use axis::resampler;
-use directories::ProjectDirs;
+use std::sync::Arc;
+use axis::analysis::{self, AnalysisOptions, AnalysisOutcome};
+use axis::args::{CacheAction, Cli, Commands, HmmAction, PluginAction};
+use axis::api::{HmmConfig, Placement, PluginDatabase};
+use axis::cache::{format_size, AnalysisCache};
+use axis::audio::AudioError;
+use axis::server::{self, RemoteError};
+use axis::session::{self, SampleCache, Session};
 
 fn main() {
     env_logger::init();
     if let Err(e) = run() {
-        log::error!("Error: {}", e);
-        process::exit(1);
+        log::error!("Error: {:#}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+/// 1, unless decoding the source failed (see `AudioError::exit_code`),
+/// here or on the server the note was forwarded to.
+fn exit_code(error: &anyhow::Error) -> i32 {
+    match (AudioError::find(error), error.downcast_ref::<RemoteError>()) {
+        (Some(audio), _) => audio.exit_code(),
+        (None, Some(remote)) => remote.exit_code,
+        (None, None) => 1,
     }
 }
 
 fn run() -> Result<()> {
     let cli = Cli::parse();
-    let proj_dirs = ProjectDirs::from("com", "cyntheria", "axis")
+    let proj_dirs = axis::util::project_dirs()
         .context("Could not determine project directories")?;
     let data_dir = proj_dirs.data_dir();
     let config_dir = proj_dirs.config_dir();
@@ -27,7 +41,8 @@ fn run() -> Result<()> {
     std::fs::create_dir_all(config_dir)?;
     
     let config = if config_path.exists() {
-        axis::api::AxisConfig::load(&config_path).unwrap_or_default()
+        axis::api::AxisConfig::load(&config_path)
+            .with_context(|| format!("Failed to load {}", config_path.display()))?
     } else {
         axis::api::AxisConfig::default()
     };
@@ -50,8 +65,11 @@ fn run() -> Result<()> {
                     if plugins.is_empty() {
                         println!("No plugins registered.");
                     } else {
-                        for (meta, path, enabled) in plugins {
-                            println!("{} v{} ({}): {} [Enabled: {}]", meta.name, meta.version, path, meta.description, enabled);
+                        for p in plugins {
+                            let abi = p.abi_version.map_or("?".to_string(), |v| v.to_string());
+                            let host = p.host_range.as_deref().unwrap_or("any");
+                            println!("{}. {} v{} ({}): {} [Enabled: {}] [ABI {}, host {}: {}]",
+                                p.position, p.meta.name, p.meta.version, p.path, p.meta.description, p.enabled, abi, host, p.status);
                         }
                     }
                 }
@@ -63,8 +81,11 @@ fn run() -> Result<()> {
                         let mut loader = axis::api::PluginLoader::load(&full_path)
                             .with_context(|| format!("Failed to load plugin: {:?}", full_path))?;
                         let meta = loader.plugin().metadata();
-                        db.register_plugin(&meta, full_path.to_str().unwrap())?;
-                        println!("Registered plugin: {} v{} from {:?}", meta.name, meta.version, full_path);
+                        let abi = loader.abi();
+                        db.register_plugin(&meta, full_path.to_str().unwrap(), &abi)?;
+                        println!("Registered plugin: {} v{} from {:?} (ABI {}, host {})", meta.name, meta.version, full_path,
+                            abi.abi_version.unwrap_or_default(),
+                            abi.host_range.map_or("any".to_string(), |r| r.to_string()));
                     }
                 }
                 PluginAction::Remove { name } => {
@@ -79,7 +100,114 @@ fn run() -> Result<()> {
                     db.set_plugin_enabled(&name, false)?;
                     println!("Disabled plugin: {}", name);
                 }
+                PluginAction::Doctor => {
+                    println!("plugin.db: {} (schema version {})", db_path.display(), db.schema_version()?);
+                    let problems = session::check_plugins(&db, &config)?;
+                    for (name, problem) in &problems {
+                        println!("{}: {}", name, problem);
+                    }
+                    if !problems.is_empty() {
+                        anyhow::bail!("{} problem(s) found", problems.len());
+                    }
+                    println!("Checked {} plugin(s), no problems found.", db.list_plugins()?.len());
+                }
+                PluginAction::Move { name, before, after } => {
+                    let placement = match (&before, &after) {
+                        (Some(anchor), _) => Placement::Before(anchor),
+                        (None, Some(anchor)) => Placement::After(anchor),
+                        (None, None) => unreachable!("clap requires --before or --after"),
+                    };
+                    if !db.move_plugin(&name, placement)? {
+                        let registered = db.list_plugins()?;
+                        let missing = [Some(&name), before.as_ref(), after.as_ref()]
+                            .into_iter()
+                            .flatten()
+                            .find(|n| !registered.iter().any(|r| &r.meta.name == *n));
+                        anyhow::bail!("Plugin not registered: {}", missing.map_or("?", |n| n.as_str()));
+                    }
+                    let order: Vec<String> = db.list_plugins()?.into_iter().map(|r| r.meta.name).collect();
+                    println!("Plugin order: {}", order.join(" -> "));
+                }
             },
+            Commands::Analyze { dir, force } => {
+                let options = AnalysisOptions::from_config(&config)?;
+                let plugins = session::enabled_plugins(&db, &config)?;
+                let workers: Vec<_> = (0..options.threads).map(|_| session::load_plugins(&plugins, &db)).collect();
+                let options = options.with_plugins(&workers[0]);
+                println!("Analyzing {} with {} threads", dir, options.threads);
+                for id in &options.plugin_chain {
+                    println!("Analysis plugin: {}", id);
+                }
+                let report = analysis::analyze_voicebank(Path::new(&dir), &options, force, workers, |done, total, path, outcome| {
+                    let status = match outcome {
+                        Ok(AnalysisOutcome::Analyzed) => "analyzed".to_string(),
+                        Ok(AnalysisOutcome::Skipped) => "up to date".to_string(),
+                        Err(e) => format!("FAILED: {:#}", e),
+                    };
+                    println!("[{}/{}] {}: {}", done, total, path.display(), status);
+                })?;
+                println!("Done. Analyzed: {}, skipped: {}, failed: {}", report.analyzed, report.skipped, report.failed.len());
+                for (path, err) in &report.failed {
+                    println!("  {}: {}", path.display(), err);
+                }
+            }
+            Commands::Hmm { action: HmmAction::Train { dir, output } } => {
+                let options = AnalysisOptions::from_config(&config)?;
+                let report = analysis::train_voicing_hmm(Path::new(&dir), &options)?;
+                for (path, reason) in &report.skipped {
+                    println!("Skipped {}: {}", path.display(), reason);
+                }
+                println!("Trained on {} frames from {} file(s)", report.frames, report.files);
+                let node = HmmConfig::to_kdl(&report.params);
+                match output {
+                    Some(path) => {
+                        std::fs::write(&path, format!("{}\n", node))
+                            .with_context(|| format!("Failed to write {}", path))?;
+                        println!("Wrote {}", path);
+                    }
+                    None => println!("{}", node),
+                }
+                println!("Put the hmm node inside `analysis` or a `voicebank` block of config.kdl to use it.");
+            }
+            Commands::Cache { action } => {
+                let cache = AnalysisCache::from_config(&config)?;
+                match action {
+                    CacheAction::Stats => {
+                        let stats = cache.stats()?;
+                        println!("Analysis cache: {}", cache.dir.display());
+                        println!("{} file(s), {} of {}", stats.files, format_size(stats.bytes), format_size(cache.max_bytes));
+                        if !config.central_cache() {
+                            println!("central-cache is off; analysis is stored next to each sample.");
+                        }
+                    }
+                    CacheAction::Clear => {
+                        let removed = cache.clear()?;
+                        println!("Removed {} file(s), {}", removed.files, format_size(removed.bytes));
+                    }
+                    CacheAction::Gc => {
+                        let evicted = cache.gc()?;
+                        let stats = cache.stats()?;
+                        println!("Evicted {} file(s), {}; {} in use", evicted.files, format_size(evicted.bytes), format_size(stats.bytes));
+                    }
+                }
+            }
+            Commands::Serve { stdio, port, workers, cache_size } => {
+                let plugins = session::enabled_plugins(&db, &config)?;
+                let workers = workers.unwrap_or_else(|| axis::util::thread_count(None)).max(1);
+                let config = Arc::new(config);
+                let cache = Arc::new(SampleCache::new(cache_size));
+                let sessions = (0..workers)
+                    .map(|_| Session::new(config.clone(), session::load_plugins(&plugins, &db), cache.clone()))
+                    .collect::<Result<Vec<_>>>()?;
+                if stdio {
+                    server::serve_stdio(sessions)?;
+                } else {
+                    let port = port
+                        .or_else(|| config.general.as_ref().and_then(|g| g.server_port))
+                        .unwrap_or(server::DEFAULT_PORT);
+                    server::serve_tcp(sessions, port)?;
+                }
+            }
         }
         return Ok(());
     }
@@ -87,39 +215,14 @@ fn run() -> Result<()> {
     let args = cli.to_resampler_args()
         .context("No subcommand provided and resampling arguments are incomplete")?;
 
-    let mut loaders = Vec::new();
-    let plugins_info = db.list_plugins()?;
-    for (meta, path, enabled) in plugins_info {
-        if enabled {
-            log::info!("Loading plugin: {} v{} from {}", meta.name, meta.version, path);
-            unsafe {
-                match axis::api::PluginLoader::load(&path) {
-                    Ok(loader) => loaders.push(loader),
-                    Err(e) => log::error!("Failed to load plugin {}: {}", meta.name, e),
-                }
-            }
+    if let Some(port) = config.general.as_ref().and_then(|g| g.server_port) {
+        if let Some(result) = server::try_forward(port, &args) {
+            return result;
         }
+        log::debug!("No server listening on port {}, rendering locally", port);
     }
 
-    let mut plugin_refs: Vec<&mut dyn axis::api::AxisPlugin> = loaders
-        .iter_mut()
-        .map(|l| l.plugin())
-        .collect();
-
-    let (samples, sample_rate) = audio::load_audio(&args.in_file)
-        .with_context(|| format!("Failed to load audio from {}", args.in_file))?;
-    
-    if samples.is_empty() {
-        audio::save_audio(&args.out_file, &Vec::new(), sample_rate)
-            .with_context(|| format!("Failed to save audio to {}", args.out_file))?;
-        return Ok(());
-    }
-    
-    let resampled = resampler::resample(&args, &samples, sample_rate, &mut plugin_refs, &config)
-        .context("Failed to resample audio")?;
-    
-    audio::save_audio(&args.out_file, &resampled, sample_rate)
-        .with_context(|| format!("Failed to save audio to {}", args.out_file))?;
-    
-    Ok(())
+    let loaders = session::load_plugins(&session::enabled_plugins(&db, &config)?, &db);
+    let mut session = Session::new(Arc::new(config), loaders, Arc::new(SampleCache::new(1)))?;
+    session.render(&args)
 }
@@ -0,0 +1,282 @@
+//! `cue `, `LIST` and `smpl` chunks of WAV files. Symphonia's WAV reader
+//! skips these chunks, so they are read from the RIFF structure directly.
+
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+
+/// A marker, at a sample frame of the file it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CuePoint {
+    pub position: u64,
+    /// The `labl` text of the cue, empty when it has none.
+    pub label: String,
+}
+
+/// A loop of the `smpl` chunk, in sample frames, `end` inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleLoop {
+    pub start: u64,
+    pub end: u64,
+    /// 0 forward, 1 alternating, 2 backward.
+    pub kind: u32,
+    /// 0 for infinite.
+    pub play_count: u32,
+}
+
+/// The `smpl` chunk fields AXIS keeps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplerInfo {
+    pub unity_note: u32,
+    pub loops: Vec<SampleLoop>,
+}
+
+/// Markers and tags of a WAV file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WavMetadata {
+    /// Rate the positions are counted at.
+    pub sample_rate: u32,
+    pub cues: Vec<CuePoint>,
+    /// `LIST INFO` entries such as `("INAM", "a ka")`.
+    pub info: Vec<(String, String)>,
+    pub sampler: Option<SamplerInfo>,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// The text of a zero-terminated string field.
+fn zstring(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// `(id, body)` of every chunk in `data`, stopping at the first truncated one.
+fn chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let size = u32_at(data, pos + 4).unwrap_or(0) as usize;
+        let body_start = pos + 8;
+        let Some(body) = data.get(body_start..body_start + size) else { break };
+        out.push((id, body));
+        pos = body_start + size + size % 2;
+    }
+    out
+}
+
+/// The sample rate in the `fmt ` chunk of a RIFF WAVE file starting with
+/// `header`, or `None` if `header` holds no `fmt ` chunk.
+pub fn wav_sample_rate(header: &[u8]) -> Option<u32> {
+    if header.len() < 12 || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return None;
+    }
+    chunks(&header[12..]).into_iter().find(|(id, _)| id == b"fmt ").and_then(|(_, body)| u32_at(body, 4))
+}
+
+impl WavMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.cues.is_empty() && self.info.is_empty() && self.sampler.is_none()
+    }
+
+    /// Parses the chunks of a RIFF WAVE file. Anything else has no metadata.
+    pub fn parse(data: &[u8]) -> Self {
+        let mut metadata = WavMetadata::default();
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return metadata;
+        }
+
+        let mut cue_ids = Vec::new();
+        let mut labels = Vec::new();
+        for (id, body) in chunks(&data[12..]) {
+            match &id {
+                b"fmt " => metadata.sample_rate = u32_at(body, 4).unwrap_or(0),
+                b"cue " => {
+                    let count = u32_at(body, 0).unwrap_or(0) as usize;
+                    for i in 0..count {
+                        let base = 4 + i * 24;
+                        if let (Some(cue_id), Some(offset)) = (u32_at(body, base), u32_at(body, base + 20)) {
+                            cue_ids.push(cue_id);
+                            metadata.cues.push(CuePoint { position: offset as u64, label: String::new() });
+                        }
+                    }
+                }
+                b"LIST" if body.starts_with(b"adtl") => {
+                    for (sub, text) in chunks(&body[4..]) {
+                        if &sub == b"labl" {
+                            if let Some(cue_id) = u32_at(text, 0) {
+                                labels.push((cue_id, zstring(&text[4..])));
+                            }
+                        }
+                    }
+                }
+                b"LIST" if body.starts_with(b"INFO") => {
+                    for (sub, text) in chunks(&body[4..]) {
+                        metadata.info.push((String::from_utf8_lossy(&sub).into_owned(), zstring(text)));
+                    }
+                }
+                b"smpl" => {
+                    let unity_note = u32_at(body, 12).unwrap_or(60);
+                    let count = u32_at(body, 28).unwrap_or(0) as usize;
+                    let loops = (0..count)
+                        .filter_map(|i| {
+                            let base = 36 + i * 24;
+                            Some(SampleLoop {
+                                kind: u32_at(body, base + 4)?,
+                                start: u32_at(body, base + 8)? as u64,
+                                end: u32_at(body, base + 12)? as u64,
+                                play_count: u32_at(body, base + 20)?,
+                            })
+                        })
+                        .collect();
+                    metadata.sampler = Some(SamplerInfo { unity_note, loops });
+                }
+                _ => {}
+            }
+        }
+        for (cue_id, label) in labels {
+            if let Some(i) = cue_ids.iter().position(|&id| id == cue_id) {
+                metadata.cues[i].label = label;
+            }
+        }
+        metadata
+    }
+
+    /// Reads the metadata of the WAV file at `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let metadata = Self::parse(&data);
+        debug!("{}: {} cue point(s), {} INFO tag(s), smpl chunk: {}",
+            path.display(), metadata.cues.len(), metadata.info.len(), metadata.sampler.is_some());
+        Ok(metadata)
+    }
+
+    /// This metadata moved to another timeline at `sample_rate`: `map` takes
+    /// a time in seconds to the new timeline, or `None` where it has no place
+    /// there. Cues and loops that do not map are dropped.
+    pub fn remap(&self, sample_rate: u32, map: impl Fn(f64) -> Option<f64>) -> Self {
+        let from = self.sample_rate.max(1) as f64;
+        let position = |p: u64| map(p as f64 / from).map(|t| (t * sample_rate as f64).round().max(0.0) as u64);
+        WavMetadata {
+            sample_rate,
+            cues: self
+                .cues
+                .iter()
+                .filter_map(|c| Some(CuePoint { position: position(c.position)?, label: c.label.clone() }))
+                .collect(),
+            info: self.info.clone(),
+            sampler: self.sampler.as_ref().map(|s| SamplerInfo {
+                unity_note: s.unity_note,
+                loops: s
+                    .loops
+                    .iter()
+                    .filter_map(|l| {
+                        let (start, end) = (position(l.start)?, position(l.end)?);
+                        (start < end).then(|| SampleLoop { start, end, ..l.clone() })
+                    })
+                    .collect(),
+            }),
+        }
+    }
+
+    /// The `cue `, `LIST` and `smpl` chunks to append after a `data` chunk.
+    pub fn to_chunks(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let chunk = |out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]| {
+            out.extend_from_slice(id);
+            out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            out.extend_from_slice(body);
+            if body.len() % 2 == 1 {
+                out.push(0);
+            }
+        };
+        let zstring = |text: &str| {
+            let mut bytes = text.as_bytes().to_vec();
+            bytes.push(0);
+            bytes
+        };
+
+        if !self.cues.is_empty() {
+            let mut cue = (self.cues.len() as u32).to_le_bytes().to_vec();
+            let mut adtl = b"adtl".to_vec();
+            for (i, point) in self.cues.iter().enumerate() {
+                let id = i as u32 + 1;
+                cue.extend_from_slice(&id.to_le_bytes());
+                cue.extend_from_slice(&(point.position as u32).to_le_bytes());
+                cue.extend_from_slice(b"data");
+                cue.extend_from_slice(&0u32.to_le_bytes());
+                cue.extend_from_slice(&0u32.to_le_bytes());
+                cue.extend_from_slice(&(point.position as u32).to_le_bytes());
+                if !point.label.is_empty() {
+                    let mut labl = id.to_le_bytes().to_vec();
+                    labl.extend_from_slice(&zstring(&point.label));
+                    chunk(&mut adtl, b"labl", &labl);
+                }
+            }
+            chunk(&mut out, b"cue ", &cue);
+            if adtl.len() > 4 {
+                chunk(&mut out, b"LIST", &adtl);
+            }
+        }
+
+        if !self.info.is_empty() {
+            let mut list = b"INFO".to_vec();
+            for (key, value) in &self.info {
+                let mut id = [b' '; 4];
+                for (slot, byte) in id.iter_mut().zip(key.bytes()) {
+                    *slot = byte;
+                }
+                chunk(&mut list, &id, &zstring(value));
+            }
+            chunk(&mut out, b"LIST", &list);
+        }
+
+        if let Some(sampler) = &self.sampler {
+            let period = (1e9 / self.sample_rate.max(1) as f64).round() as u32;
+            let mut smpl = Vec::new();
+            for value in [0, 0, period, sampler.unity_note, 0, 0, 0, sampler.loops.len() as u32, 0] {
+                smpl.extend_from_slice(&value.to_le_bytes());
+            }
+            for (i, l) in sampler.loops.iter().enumerate() {
+                for value in [i as u32, l.kind, l.start as u32, l.end as u32, 0, l.play_count] {
+                    smpl.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            chunk(&mut out, b"smpl", &smpl);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let metadata = WavMetadata {
+            sample_rate: 44100,
+            cues: vec![
+                CuePoint { position: 4410, label: "consonant end".into() },
+                CuePoint { position: 2205, label: String::new() },
+            ],
+            info: vec![("INAM".into(), "a ka".into()), ("ISFT".into(), "AXIS".into())],
+            sampler: Some(SamplerInfo { unity_note: 60, loops: vec![SampleLoop { start: 8820, end: 17639, kind: 0, play_count: 0 }] }),
+        };
+
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend_from_slice(b"fmt \x10\0\0\0\x01\0\x01\0\x44\xac\0\0\x88\x58\x01\0\x02\0\x10\0");
+        // An odd-sized unknown chunk, to check padding is skipped
+        wav.extend_from_slice(b"junk\x03\0\0\0abc\0");
+        wav.extend_from_slice(&metadata.to_chunks());
+        assert_eq!(WavMetadata::parse(&wav), metadata);
+        assert!(WavMetadata::parse(b"fLaC").is_empty());
+
+        // Halving the time drops what falls past the end
+        let remapped = metadata.remap(48000, |t| (t < 0.15).then_some(t / 2.0));
+        assert_eq!(remapped.cues, [CuePoint { position: 2400, label: "consonant end".into() }, CuePoint { position: 1200, label: String::new() }]);
+        assert!(remapped.sampler.unwrap().loops.is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// How far the offset and consonant of a note may be from an `oto.ini`
+/// entry for the note to be matched to it, in ms.
+const MATCH_TOLERANCE: f64 = 0.5;
+
+/// One `file.wav=alias,offset,consonant,cutoff,preutterance,overlap` line of `oto.ini`, times in ms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtoEntry {
+    pub file: String,
+    pub alias: String,
+    pub offset: f64,
+    pub consonant: f64,
+    pub cutoff: f64,
+    /// From `offset`.
+    pub preutterance: f64,
+    /// From `offset`; negative when the note overlaps the previous one before its offset.
+    pub overlap: f64,
+}
+
+/// Parses `oto.ini`, skipping lines that are not entries.
+pub fn parse_oto(content: &str) -> Vec<OtoEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (file, params) = line.trim().split_once('=')?;
+            let fields: Vec<&str> = params.split(',').collect();
+            let [alias, offset, consonant, cutoff, preutterance, overlap] = fields[..] else { return None };
+            let num = |s: &str| if s.trim().is_empty() { Some(0.0) } else { s.trim().parse::<f64>().ok() };
+            Some(OtoEntry {
+                file: file.to_string(),
+                alias: alias.to_string(),
+                offset: num(offset)?,
+                consonant: num(consonant)?,
+                cutoff: num(cutoff)?,
+                preutterance: num(preutterance)?,
+                overlap: num(overlap)?,
+            })
+        })
+        .collect()
+}
+
+/// The `oto.ini` entries for `sample`, from the `oto.ini` in its directory.
+/// Voicebanks saved by older tools are often Shift-JIS, whose non-ASCII
+/// file names will not match.
+pub fn entries_for(sample: &Path) -> Result<Vec<OtoEntry>> {
+    let Some(dir) = sample.parent() else { return Ok(Vec::new()) };
+    let path = dir.join("oto.ini");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let name = sample.file_name().unwrap_or_default().to_string_lossy();
+    Ok(parse_oto(&String::from_utf8_lossy(&content)).into_iter().filter(|e| e.file == name).collect())
+}
+
+/// The entry a note with `offset` and `consonant` (ms) was rendered from, if exactly one fits.
+pub fn find_entry(entries: &[OtoEntry], offset: f64, consonant: f64) -> Option<&OtoEntry> {
+    let mut fits = entries
+        .iter()
+        .filter(|e| (e.offset - offset).abs() <= MATCH_TOLERANCE && (e.consonant - consonant).abs() <= MATCH_TOLERANCE);
+    let entry = fits.next()?;
+    fits.all(|e| e.preutterance == entry.preutterance && e.overlap == entry.overlap).then_some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match_oto() {
+        let entries = parse_oto("_ああいあうえあ.wav=- あ,450,200,-600,120,40\r\n_ああいあうえあ.wav=a あ,900,180,-500,100,30\r\nka.wav=ka,0,,0,50,-10\r\n# comment\nbroken.wav=x,1,2\n");
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2], OtoEntry { file: "ka.wav".into(), alias: "ka".into(), offset: 0.0, consonant: 0.0, cutoff: 0.0, preutterance: 50.0, overlap: -10.0 });
+
+        assert_eq!(find_entry(&entries, 900.2, 180.0).map(|e| e.alias.as_str()), Some("a あ"));
+        assert!(find_entry(&entries, 905.0, 180.0).is_none());
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::{bail, Result};
+use std::f64::consts::PI;
+
+/// Zero crossings of the sinc on each side of a tap, at the lower of the two rates.
+const ZERO_CROSSINGS: usize = 64;
+
+/// Passband edge as a fraction of the lower Nyquist frequency. The Kaiser
+/// transition band ends just below Nyquist, so nothing folds back.
+const ROLLOFF: f64 = 0.91;
+
+/// About 90 dB of stopband attenuation.
+const KAISER_BETA: f64 = 9.0;
+
+/// Filter phases are tabulated exactly up to this many, and interpolated
+/// beyond it for rate pairs with no small common divisor.
+const MAX_PHASES: usize = 1024;
+
+/// Range accepted for `output-rate`.
+pub const MIN_OUTPUT_RATE: u32 = 8000;
+pub const MAX_OUTPUT_RATE: u32 = 384_000;
+
+/// When `output-rate` is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateConversion {
+    /// Render at the sample's own rate and convert the note.
+    #[default]
+    AfterSynthesis,
+    /// Convert the sample once, so analysis and synthesis run at the output rate.
+    BeforeAnalysis,
+}
+
+impl std::str::FromStr for RateConversion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "after-synthesis" => Ok(RateConversion::AfterSynthesis),
+            "before-analysis" => Ok(RateConversion::BeforeAnalysis),
+            other => bail!("Unknown rate conversion \"{}\" (expected \"after-synthesis\" or \"before-analysis\")", other),
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Zeroth-order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+/// Band-limited polyphase sample-rate converter: a Kaiser-windowed sinc
+/// lowpass at the lower of the two Nyquist frequencies, evaluated at every
+/// output position.
+pub struct RateConverter {
+    /// Every `down` input samples make `up` output samples.
+    up: u64,
+    down: u64,
+    /// Taps on each side of the output position, in input samples.
+    half_width: usize,
+    phases: usize,
+    /// `phases + 1` rows of `2 * half_width` taps. Row `p` holds the filter
+    /// for an output `p / phases` input samples past the tap origin.
+    table: Vec<f64>,
+}
+
+impl RateConverter {
+    pub fn new(from: u32, to: u32) -> Result<Self> {
+        if from == 0 || to == 0 {
+            bail!("Cannot convert from {} Hz to {} Hz: sample rates must be positive", from, to);
+        }
+        let g = gcd(from as u64, to as u64);
+        let (up, down) = (to as u64 / g, from as u64 / g);
+
+        // Cutoff in cycles per input sample
+        let cutoff = 0.5 * ROLLOFF * (to as f64 / from as f64).min(1.0);
+        let half_width = (ZERO_CROSSINGS as f64 / (2.0 * cutoff)).ceil() as usize;
+        let phases = (up as usize).min(MAX_PHASES);
+        let taps = 2 * half_width;
+        let norm = bessel_i0(KAISER_BETA);
+
+        let mut table = vec![0.0; (phases + 1) * taps];
+        for p in 0..=phases {
+            let frac = p as f64 / phases as f64;
+            let row = &mut table[p * taps..(p + 1) * taps];
+            for (k, tap) in row.iter_mut().enumerate() {
+                // Distance from the output position to input sample `k - half_width + 1`
+                let t = k as f64 - half_width as f64 + 1.0 - frac;
+                let x = t / half_width as f64;
+                if x.abs() >= 1.0 {
+                    continue;
+                }
+                let arg = 2.0 * cutoff * t;
+                let sinc = if arg == 0.0 { 1.0 } else { (PI * arg).sin() / (PI * arg) };
+                let window = bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / norm;
+                *tap = 2.0 * cutoff * sinc * window;
+            }
+        }
+        Ok(Self { up, down, half_width, phases, table })
+    }
+
+    /// Output samples for `input_len` input samples.
+    pub fn output_len(&self, input_len: usize) -> usize {
+        (input_len as u64 * self.up).div_ceil(self.down) as usize
+    }
+
+    pub fn process(&self, input: &[f64]) -> Vec<f64> {
+        let taps = 2 * self.half_width;
+        (0..self.output_len(input.len()))
+            .map(|n| {
+                let pos = n as u64 * self.down;
+                let base = (pos / self.up) as isize;
+                let phase = (pos % self.up) as f64 * self.phases as f64 / self.up as f64;
+                let p = (phase as usize).min(self.phases - 1);
+                let weight = phase - p as f64;
+                let (row, next) = (&self.table[p * taps..(p + 1) * taps], &self.table[(p + 1) * taps..(p + 2) * taps]);
+
+                let first = base - self.half_width as isize + 1;
+                let mut sum = 0.0;
+                for k in 0..taps {
+                    let i = first + k as isize;
+                    if i < 0 || i as usize >= input.len() {
+                        continue;
+                    }
+                    let h = if weight == 0.0 { row[k] } else { row[k] + weight * (next[k] - row[k]) };
+                    sum += input[i as usize] * h;
+                }
+                sum
+            })
+            .collect()
+    }
+}
+
+/// `samples` at `from` Hz converted to `to` Hz.
+pub fn convert(samples: &[f64], from: u32, to: u32) -> Result<Vec<f64>> {
+    if from == to {
+        return Ok(samples.to_vec());
+    }
+    Ok(RateConverter::new(from, to)?.process(samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f64, rate: u32, len: usize) -> Vec<f64> {
+        (0..len).map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin()).collect()
+    }
+
+    /// Amplitude of `freq` in `signal`, away from the edges.
+    fn amplitude(signal: &[f64], freq: f64, rate: u32) -> f64 {
+        let body = &signal[signal.len() / 4..signal.len() * 3 / 4];
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, &s) in body.iter().enumerate() {
+            let w = 2.0 * PI * freq * i as f64 / rate as f64;
+            re += s * w.cos();
+            im += s * w.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / body.len() as f64
+    }
+
+    #[test]
+    fn test_passband_is_preserved() {
+        for (from, to) in [(44100, 48000), (48000, 44100), (22050, 44100), (44100, 47999)] {
+            let input = tone(1000.0, from, from as usize / 4);
+            let output = convert(&input, from, to).unwrap();
+            assert_eq!(output.len(), RateConverter::new(from, to).unwrap().output_len(input.len()));
+            assert!((output.len() as f64 - input.len() as f64 * to as f64 / from as f64).abs() <= 1.0);
+            let expected = tone(1000.0, to, output.len());
+            let body = output.len() / 4..output.len() * 3 / 4;
+            let error = body.clone().map(|i| (output[i] - expected[i]).abs()).fold(0.0, f64::max);
+            assert!(error < 1e-3, "{} -> {}: error {}", from, to, error);
+        }
+    }
+
+    #[test]
+    fn test_downsampling_rejects_aliases() {
+        // 23 kHz has no place at 44.1 kHz and would alias to 21.1 kHz
+        let input = tone(23000.0, 48000, 12000);
+        let output = convert(&input, 48000, 44100).unwrap();
+        assert!(amplitude(&output, 21100.0, 44100) < 1e-4);
+    }
+
+    #[test]
+    fn test_zero_rate_is_an_error() {
+        assert!(RateConverter::new(44100, 0).is_err());
+        assert!(convert(&[0.0; 4], 0, 44100).is_err());
+    }
+}
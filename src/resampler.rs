@@ -1,25 +1,17 @@
 use anyhow::Result;
+use crate::analysis::{load_or_analyze, AnalysisOptions, AxisFeatures, FRAME_PERIOD};
 use crate::args::ResamplerArgs;
 use crate::util::{decode_pitchbend, midi_to_hz, arange, linspace, lerp};
 use crate::flags::Flags;
+use crate::metadata::{CuePoint, WavMetadata};
+use crate::oto::OtoEntry;
+use crate::vocoder::frames::FramePeriod;
+use crate::vocoder::hmm::VOICING_FLOOR;
+use crate::vocoder::pulse::PulseEngine;
 use crate::vocoder::stydl::StydlVocoder;
+use std::hash::Hasher;
 use std::str::FromStr;
 use log::{info, debug};
-use serde::{Serialize, Deserialize};
-use std::path::{Path, PathBuf};
-use std::fs::File;
-use std::io::{Read, Write};
-
-const FRAME_PERIOD: f64 = 5.0;
-
-#[derive(Serialize, Deserialize)]
-struct AxisFeatures {
-    f0: Vec<f64>,
-    spec: Vec<Vec<f64>>,
-    ap: Vec<Vec<f64>>,
-    source_base_hz: f64,
-    fft_size: usize,
-}
 
 fn apply_volume(samples: &mut [f64], volume: f64) {
     let scale = volume / 100.0;
@@ -28,13 +20,27 @@ fn apply_volume(samples: &mut [f64], volume: f64) {
     }
 }
 
-fn get_analysis_path(source: &str) -> PathBuf {
-    let path = Path::new(source);
-    let mut analysis = path.to_path_buf();
-    let mut name = path.file_name().unwrap_or_default().to_os_string();
-    name.push(".axxf");
-    analysis.set_file_name(name);
-    analysis
+/// Default synthesis seed for a note: a hash of its arguments, so rendering
+/// the same note twice gives the same output. The output path and the
+/// sample's directory are left out, as hosts render to temporary files.
+pub fn note_seed(args: &ResamplerArgs) -> u64 {
+    let mut hasher = crate::util::Fnv1a::default();
+    let sample = std::path::Path::new(&args.in_file).file_name().unwrap_or_default();
+    hasher.write(sample.as_encoded_bytes());
+    hasher.write_i32(args.pitch);
+    for value in [args.velocity, args.offset, args.length, args.consonant, args.cutoff, args.volume, args.modulation, args.tempo] {
+        hasher.write_u64(value.to_bits());
+    }
+    hasher.write(args.flags.as_bytes());
+    hasher.write(args.pitchbend.as_deref().unwrap_or_default().as_bytes());
+    hasher.finish()
+}
+
+/// Seed of a note's synthesis noise and output dither: the `S` flag, else
+/// `seed` of `config.kdl`, else `note_seed`.
+pub fn synthesis_seed(args: &ResamplerArgs, config: &crate::api::AxisConfig) -> u64 {
+    let flag = Flags::from_str(&args.flags).ok().and_then(|f| f.seed);
+    flag.or(config.seed()).unwrap_or_else(|| note_seed(args))
 }
 
 pub fn resample(
@@ -42,75 +48,22 @@ pub fn resample(
     input_samples: &[f64], 
     sample_rate: u32,
     plugins: &mut [&mut dyn crate::api::AxisPlugin],
-    _config: &crate::api::AxisConfig,
+    config: &crate::api::AxisConfig,
 ) -> Result<Vec<f64>> {
     if input_samples.is_empty() {
         return Ok(vec![]);
     }
 
-    info!("Starting resampling [STYDL]: pitch={}Hz (MIDI {}), tempo={}", midi_to_hz(args.pitch as f64), args.pitch, args.tempo);
-    
-    let velocity = (1.0 - args.velocity as f64 / 100.0).exp2();
-    let modulation = args.modulation / 100.0;
-    let flags = Flags::from_str(&args.flags).unwrap_or(Flags { gender: 0.0, breathiness: 50.0 });
-    
-    debug!("Flags applied: gender={}, breathiness={}", flags.gender, flags.breathiness);
-
-    let analysis_path = get_analysis_path(&args.in_file);
-    
-    let features = if analysis_path.exists() {
-        info!("Loading analysis data from {}", analysis_path.display());
-        let mut f = File::open(&analysis_path)?;
-        let mut buf = Vec::new();
-        f.read_to_end(&mut buf)?;
-        bincode::deserialize::<AxisFeatures>(&buf)?
-    } else {
-        info!("Running STYDL analysis...");
-        let vocoder = StydlVocoder::new(sample_rate, 4096);
-        let fft_size = vocoder.fft_size;
-        
-        let hop_size = (sample_rate as f64 * FRAME_PERIOD / 1000.0) as usize;
-        let num_frames = input_samples.len() / hop_size;
-        
-        let mut f0 = vec![0.0; num_frames];
-        let mut spec = Vec::with_capacity(num_frames);
-        let mut ap = Vec::with_capacity(num_frames);
-
-        // 1. F0 Estimation (raw)
-        f0 = vocoder.f0_estimator.estimate(input_samples);
-        f0.truncate(num_frames); // Align
-
-        // 2. Spectral & Aperiodicity Estimation (uses raw F0)
-        for i in 0..f0.len() {
-            let start = i * hop_size;
-            let end = (start + fft_size).min(input_samples.len());
-            let chunk = &input_samples[start..end];
-            
-            spec.push(vocoder.spectral_resolver.resolve(chunk, f0[i], fft_size));
-            ap.push(vocoder.aperiodicity_estimator.estimate(chunk, f0[i], fft_size));
-        }
-
-        // 3. HMM F0 Smoothing (Viterbi V/UV + median filter) — applied AFTER analysis
-        let hmm = crate::vocoder::hmm::VoicingHmm::new();
-        f0 = hmm.smooth_f0(&f0);
-        info!("HMM smoothed F0: {} frames", f0.len());
-
-        let mut voiced_f0: Vec<f64> = f0.iter().cloned().filter(|&f| f > 40.0).collect();
-        voiced_f0.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        let source_base_hz = if voiced_f0.is_empty() { 261.63 } else { voiced_f0[voiced_f0.len() / 2] };
-        
-        info!("Analysis complete. Frames: {}, FFT size: {}, Median F0: {:.2}Hz", f0.len(), fft_size, source_base_hz);
-
-        let feats = AxisFeatures { f0, spec, ap, source_base_hz, fft_size };
-        let bin = bincode::serialize(&feats)?;
-        let mut f = File::create(&analysis_path)?;
-        f.write_all(&bin)?;
-        feats
-    };
-
-    let f0_len = features.f0.len();
-    let f0_off: Vec<f64> = features.f0.iter().map(|&f| if f == 0.0 { 0.0 } else { 12.0 * (f.log2() - features.source_base_hz.log2()) }).collect();
+    // Plugins are only applied at render time here; `Session` also runs their analysis hooks.
+    let features = load_or_analyze(&args.in_file, input_samples, sample_rate, &AnalysisOptions::from_config(config)?, &mut [])?;
+    render(args, &features, input_samples, sample_rate, plugins, config)
+}
 
+/// The source frame (fractional, at `FRAME_PERIOD`) rendered at every output
+/// frame: the consonant scaled by velocity, then the rest stretched or cut to
+/// the note length. `f0_len` is the number of analysis frames.
+pub fn time_map(args: &ResamplerArgs, f0_len: usize) -> Vec<f64> {
+    let velocity = (1.0 - args.velocity / 100.0).exp2();
     let fps = 1000.0 / FRAME_PERIOD;
     let feature_length_sec = f0_len as f64 / fps;
 
@@ -129,20 +82,104 @@ pub fn resample(
         linspace(consonant_src, end, (length_req * fps) as usize, true)
     };
 
-    let t_render: Vec<f64> = t_consonant.into_iter().chain(t_stretch.into_iter()).map(|x: f64| (x * fps).clamp(0.0, (f0_len - 1) as f64)).collect();
+    t_consonant.into_iter().chain(t_stretch).map(|x: f64| (x * fps).clamp(0.0, (f0_len - 1) as f64)).collect()
+}
+
+/// Where `input_sec` of the source plays in a note rendered along
+/// `time_map`, in seconds, or `None` if the note does not reach it.
+pub fn output_time(time_map: &[f64], input_sec: f64) -> Option<f64> {
+    let fps = 1000.0 / FRAME_PERIOD;
+    let x = input_sec * fps;
+    let k = time_map.partition_point(|&t| t < x);
+    match k {
+        _ if k == time_map.len() => None,
+        0 => ((time_map[0] - x).abs() < 1e-9).then_some(0.0),
+        _ => {
+            let frac = (x - time_map[k - 1]) / (time_map[k] - time_map[k - 1]);
+            Some((k as f64 - 1.0 + frac) / fps)
+        }
+    }
+}
+
+/// Metadata for a rendered note at `sample_rate`: the cues and loops of the
+/// source moved along the time map, plus markers at the end of the consonant
+/// and, when the note's `oto.ini` entry is known, at the preutterance and
+/// overlap. The `smpl` unity note becomes the note's pitch.
+pub fn note_metadata(args: &ResamplerArgs, f0_len: usize, source: &WavMetadata, oto: Option<&OtoEntry>, sample_rate: u32) -> WavMetadata {
+    let times = time_map(args, f0_len);
+    let map = |sec: f64| output_time(&times, sec);
+    let mut metadata = source.remap(sample_rate, map);
+
+    let offset = args.offset / 1000.0;
+    let markers = [
+        ("consonant end", Some(args.consonant)),
+        ("preutterance", oto.map(|e| e.preutterance)),
+        ("overlap", oto.map(|e| e.overlap)),
+    ];
+    for (label, ms) in markers {
+        if let Some(t) = ms.and_then(|ms| map(offset + ms / 1000.0)) {
+            metadata.cues.push(CuePoint { position: (t * sample_rate as f64).round() as u64, label: label.to_string() });
+        }
+    }
+    metadata.cues.sort_by_key(|c| c.position);
+    if let Some(sampler) = &mut metadata.sampler {
+        sampler.unity_note = args.pitch.max(0) as u32;
+    }
+    metadata
+}
+
+/// Renders a note from already analyzed features. Long-running callers such as
+/// `axis serve` keep `features` in memory and call this directly.
+pub fn render(
+    args: &ResamplerArgs,
+    features: &AxisFeatures,
+    input_samples: &[f64],
+    sample_rate: u32,
+    plugins: &mut [&mut dyn crate::api::AxisPlugin],
+    config: &crate::api::AxisConfig,
+) -> Result<Vec<f64>> {
+    if input_samples.is_empty() || features.f0.is_empty() {
+        return Ok(vec![]);
+    }
+
+    info!("Starting resampling [STYDL]: pitch={}Hz (MIDI {}), tempo={}", midi_to_hz(args.pitch as f64), args.pitch, args.tempo);
+    
+    let modulation = args.modulation / 100.0;
+    let flags = Flags::from_str(&args.flags).unwrap_or(Flags { gender: 0.0, breathiness: 50.0, seed: None });
+    let seed = synthesis_seed(args, config);
+    
+    debug!("Flags applied: gender={}, breathiness={}, seed={}", flags.gender, flags.breathiness, seed);
+
+    let f0_len = features.f0.len();
+    // Unvoiced frames hold the pitch of the nearest voiced frame, so partly
+    // voiced frames at a boundary still have a pitch to render
+    let voiced_frames: Vec<usize> = (0..f0_len).filter(|&i| features.f0[i] >= features.f0_threshold).collect();
+    let f0_off: Vec<f64> = (0..f0_len).map(|i| {
+        let next = voiced_frames.partition_point(|&j| j < i);
+        let nearest = match (next.checked_sub(1).map(|p| voiced_frames[p]), voiced_frames.get(next).copied()) {
+            (Some(p), Some(n)) => if i - p <= n - i { p } else { n },
+            (Some(j), None) | (None, Some(j)) => j,
+            (None, None) => return 0.0,
+        };
+        12.0 * (features.f0[nearest].log2() - features.source_base_hz.log2())
+    }).collect();
+
+    let fps = 1000.0 / FRAME_PERIOD;
+    let t_render = time_map(args, f0_len);
     let render_length = t_render.len();
     let t_sec: Vec<f64> = arange(render_length as i32).iter().map(|x| x / fps).collect();
 
     let mut f0_off_render = Vec::with_capacity(render_length);
     let mut spec_render: Vec<Vec<f64>> = Vec::with_capacity(render_length);
     let mut ap_render: Vec<Vec<f64>> = Vec::with_capacity(render_length);
-    let vuv_render: Vec<bool> = t_render.iter().map(|&t: &f64| features.f0[t as usize] != 0.0).collect();
+    let mut voicing_render = Vec::with_capacity(render_length);
 
     for &t in &t_render {
         let idx0 = t.floor() as usize;
         let idx1 = (idx0 + 1).min(f0_len - 1);
         let weight = t - idx0 as f64;
         f0_off_render.push(lerp(f0_off[idx0], f0_off[idx1], weight));
+        voicing_render.push(lerp(features.voicing[idx0], features.voicing[idx1], weight));
         spec_render.push((0..features.spec[0].len()).map(|i| lerp(features.spec[idx0][i], features.spec[idx1][i], weight)).collect());
         ap_render.push((0..features.ap[0].len()).map(|i| lerp(features.ap[idx0][i], features.ap[idx1][i], weight)).collect());
     }
@@ -165,7 +202,7 @@ pub fn resample(
     let pps = 8.0 * args.tempo / 5.0;
     
     let f0_render: Vec<f64> = (0..render_length).map(|i| {
-        if !vuv_render[i] { return 0.0; }
+        if voicing_render[i] < VOICING_FLOOR { return 0.0; }
         let t_p = t_sec[i] * pps;
         let pb_v = if pb.is_empty() { 0.0 } else {
             let idx = t_p.floor() as usize;
@@ -194,15 +231,14 @@ pub fn resample(
         crate::util::smooth_spectrum(frame, 3);
     }
 
-    for i in 0..render_length {
-        if f0_p[i] == 0.0 {
-            for val in ap_p[i].iter_mut() { *val = 1.0; }
-        }
-    }
-
-    info!("Using STYDL vocoder for synthesis...");
-    let mut vocoder = StydlVocoder::new(sample_rate, features.fft_size);
-    let mut syn = vocoder.process(&f0_p, &spec_p, &ap_p, input_samples, &t_render);
+    let mut syn = if config.stydl() {
+        info!("Using STYDL vocoder for synthesis...");
+        let mut vocoder = StydlVocoder::new(sample_rate, features.fft_size).with_threads(config.threads()).with_seed(seed).with_f0_threshold(features.f0_threshold);
+        vocoder.process(&f0_p, &spec_p, &ap_p, &voicing_render)
+    } else {
+        info!("Using pulse vocoder for synthesis...");
+        PulseEngine::new(FramePeriod::new(sample_rate, FRAME_PERIOD)).with_threads(config.threads()).with_seed(seed).with_f0_threshold(features.f0_threshold).synthesize(&f0_p, &spec_p, &ap_p, &voicing_render)
+    };
 
     for plugin in plugins.iter_mut() {
         plugin.process_audio(&mut syn, sample_rate)?;
@@ -215,3 +251,80 @@ pub fn resample(
     info!("Resampling complete. Output: {} samples", syn.len());
     Ok(syn)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::AxisConfig;
+    use crate::api::config::GeneralConfig;
+
+    fn note(consonant: f64, length: f64) -> ResamplerArgs {
+        ResamplerArgs {
+            in_file: "a.wav".into(),
+            out_file: "out.wav".into(),
+            pitch: 60,
+            velocity: 100.0,
+            flags: String::new(),
+            offset: 0.0,
+            length,
+            consonant,
+            cutoff: 0.0,
+            volume: 100.0,
+            modulation: 0.0,
+            tempo: 120.0,
+            pitchbend: None,
+        }
+    }
+
+    #[test]
+    fn test_output_duration_matches_note() {
+        for stydl in [true, false] {
+            let config = AxisConfig {
+                general: Some(GeneralConfig { threads: Some(1), stydl: Some(stydl), ..AxisConfig::default().general.unwrap() }),
+                ..AxisConfig::default()
+            };
+            for sample_rate in [44100, 48000, 96000] {
+                let frames = FramePeriod::new(sample_rate, FRAME_PERIOD);
+                let num_frames = frames.frame_count(sample_rate as usize);
+                let features = AxisFeatures {
+                    f0: vec![880.0; num_frames],
+                    spec: vec![vec![1e-4; 513]; num_frames],
+                    ap: vec![vec![0.2; 513]; num_frames],
+                    voicing: vec![1.0; num_frames],
+                    source_base_hz: 880.0,
+                    f0_threshold: 40.0,
+                    fft_size: 1024,
+                };
+                let input = vec![0.0; sample_rate as usize];
+                for (consonant, length) in [(0.0, 250.0), (100.0, 300.0)] {
+                    let out = render(&note(consonant, length), &features, &input, sample_rate, &mut [], &config).unwrap();
+                    let expected = (consonant + length) * sample_rate as f64 / 1000.0;
+                    assert!((out.len() as f64 - expected).abs() <= frames.hop(),
+                        "stydl={} {} Hz, {}+{} ms: {} samples, expected {}", stydl, sample_rate, consonant, length, out.len(), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_note_metadata_follows_time_map() {
+        // 1 s source; the consonant plays at half speed, the rest stretched to twice its length
+        let args = ResamplerArgs { velocity: 0.0, offset: 100.0, ..note(100.0, 1600.0) };
+        let source = WavMetadata {
+            sample_rate: 48000,
+            cues: vec![CuePoint { position: 2400, label: "breath".into() }, CuePoint { position: 28800, label: "release".into() }],
+            ..WavMetadata::default()
+        };
+        let oto = OtoEntry { file: "a.wav".into(), alias: "a".into(), offset: 100.0, consonant: 100.0, cutoff: 0.0, preutterance: 120.0, overlap: 40.0 };
+        let metadata = note_metadata(&args, 200, &source, Some(&oto), 44100);
+
+        let seconds: Vec<(&str, f64)> = metadata.cues.iter().map(|c| (c.label.as_str(), c.position as f64 / 44100.0)).collect();
+        let expected = [("overlap", 0.08), ("consonant end", 0.2), ("preutterance", 0.2399), ("release", 0.9975)];
+        assert_eq!(seconds.len(), expected.len(), "{:?}", seconds);
+        for ((label, t), (expected_label, expected_t)) in seconds.iter().zip(expected) {
+            assert_eq!(*label, expected_label);
+            assert!((t - expected_t).abs() < 1e-3, "{} at {} s, expected {}", label, t, expected_t);
+        }
+        assert!(output_time(&time_map(&args, 200), 0.05).is_none());
+    }
+}
@@ -0,0 +1,380 @@
+use anyhow::{Context, Result};
+use crate::args::ResamplerArgs;
+use crate::audio::AudioError;
+use crate::session::Session;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 47700;
+
+/// One line of the JSON-lines protocol: the 13 UTAU arguments plus an
+/// optional `id` that is echoed back in the response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// The server's token, required over TCP (see `token_path`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub args: ResamplerArgs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobResponse {
+    pub id: Option<u64>,
+    pub ok: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Exit code a local render would have ended with, for failed jobs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+/// A note the server failed to render, returned (inside `anyhow::Error`) by `try_forward`.
+#[derive(Debug)]
+pub struct RemoteError {
+    pub message: String,
+    pub exit_code: i32,
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+struct Job {
+    request: JobRequest,
+    reply: Sender<JobResponse>,
+}
+
+/// Fixed pool of render threads, each owning its own `Session`.
+struct WorkerPool {
+    jobs: Sender<Job>,
+}
+
+impl WorkerPool {
+    fn new(sessions: Vec<Session>) -> Self {
+        Self::with_renderers(sessions.into_iter().map(|mut session| move |args: &ResamplerArgs| session.render(args)).collect())
+    }
+
+    fn with_renderers<F>(renderers: Vec<F>) -> Self
+    where
+        F: FnMut(&ResamplerArgs) -> Result<()> + Send + 'static,
+    {
+        let (jobs, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for (i, mut render) in renderers.into_iter().enumerate() {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("axis-worker-{}", i))
+                .spawn(move || worker_loop(&mut render, &rx))
+                .expect("failed to spawn render worker");
+        }
+        Self { jobs }
+    }
+
+    fn submit(&self, request: JobRequest) -> Receiver<JobResponse> {
+        let (reply, rx) = channel();
+        let id = request.id;
+        if let Err(e) = self.jobs.send(Job { request, reply }) {
+            // Only possible once every worker has died; answer directly.
+            let _ = e.0.reply.send(JobResponse { id, ok: false, error: Some("No render workers available".into()), exit_code: None });
+        }
+        rx
+    }
+}
+
+fn worker_loop(render: &mut impl FnMut(&ResamplerArgs) -> Result<()>, jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let started = Instant::now();
+        let args = &job.request.args;
+        // A panicking render must still be answered, and must not take the worker with it
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| render(args)));
+        let response = match result {
+            Ok(Ok(())) => {
+                info!("Rendered {} -> {} in {:.1}ms", args.in_file, args.out_file, started.elapsed().as_secs_f64() * 1000.0);
+                JobResponse { id: job.request.id, ok: true, error: None, exit_code: None }
+            }
+            Ok(Err(e)) => {
+                error!("Failed to render {}: {:#}", args.out_file, e);
+                let exit_code = AudioError::find(&e).map_or(1, AudioError::exit_code);
+                JobResponse { id: job.request.id, ok: false, error: Some(format!("{:#}", e)), exit_code: Some(exit_code) }
+            }
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                error!("Render of {} panicked: {}", args.out_file, message);
+                // The exit code of a panicking local render
+                JobResponse { id: job.request.id, ok: false, error: Some(format!("Render panicked: {}", message)), exit_code: Some(101) }
+            }
+        };
+        let _ = job.reply.send(response);
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+fn parse_request(line: &str) -> std::result::Result<JobRequest, JobResponse> {
+    serde_json::from_str(line).map_err(|e| JobResponse {
+        id: None,
+        ok: false,
+        error: Some(format!("Invalid request: {}", e)),
+        exit_code: None,
+    })
+}
+
+/// Serves jobs read line-by-line from stdin. Jobs render concurrently and
+/// responses are written to stdout in request order.
+pub fn serve_stdio(sessions: Vec<Session>) -> Result<()> {
+    let pool = WorkerPool::new(sessions);
+    let (done_tx, done_rx) = channel::<Receiver<JobResponse>>();
+
+    let writer = thread::spawn(move || -> Result<()> {
+        let stdout = std::io::stdout();
+        for rx in done_rx {
+            // Every request gets a line, so hosts can match responses by order
+            let response = rx.recv().unwrap_or_else(|_| JobResponse {
+                id: None,
+                ok: false,
+                error: Some("Render worker exited without answering".into()),
+                exit_code: None,
+            });
+            let mut out = stdout.lock();
+            serde_json::to_writer(&mut out, &response)?;
+            out.write_all(b"\n")?;
+            out.flush()?;
+        }
+        Ok(())
+    });
+
+    info!("Serving resample jobs on stdin");
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let rx = match parse_request(&line) {
+            Ok(request) => pool.submit(request),
+            Err(response) => {
+                let (tx, rx) = channel();
+                let _ = tx.send(response);
+                rx
+            }
+        };
+        if done_tx.send(rx).is_err() {
+            break;
+        }
+    }
+    drop(done_tx);
+    writer.join().expect("stdout writer panicked")
+}
+
+/// Where the server on `port` keeps its token. Anything that can connect
+/// to localhost can reach the port, including web pages sending
+/// cross-protocol requests, so jobs must prove they can read this file.
+pub fn token_path(port: u16) -> Result<PathBuf> {
+    let dirs = crate::util::project_dirs().context("Could not determine project directories")?;
+    Ok(dirs.data_dir().join(format!("server-{}.token", port)))
+}
+
+fn write_token(port: u16) -> Result<String> {
+    let token = format!("{:032x}", rand::random::<u128>());
+    let path = token_path(port)?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(token)
+}
+
+/// Listens on localhost and serves one JSON-lines conversation per connection.
+pub fn serve_tcp(sessions: Vec<Session>, port: u16) -> Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to bind 127.0.0.1:{}", port))?;
+    let token = Arc::new(write_token(port)?);
+    let pool = Arc::new(WorkerPool::new(sessions));
+    info!("Serving resample jobs on 127.0.0.1:{}", port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let (pool, token) = (pool.clone(), token.clone());
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &pool, &token) {
+                warn!("Connection error: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serves jobs until the client disconnects. The first line that is not a
+/// job with the right token gets an error response and closes the connection.
+fn handle_connection(stream: TcpStream, pool: &WorkerPool, token: &str) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = parse_request(&line).and_then(|request| match request.token.as_deref() {
+            Some(t) if t == token => Ok(request),
+            _ => Err(JobResponse { id: request.id, ok: false, error: Some("Invalid or missing server token".into()), exit_code: None }),
+        });
+        let (response, accepted) = match request {
+            Ok(request) => (pool.submit(request).recv()?, true),
+            Err(response) => (response, false),
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        if !accepted {
+            warn!("Closing connection after a rejected request");
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Thin client: sends `args` to a running `axis serve`. Returns `None` when
+/// no server is listening or its token cannot be read, so the caller can
+/// fall back to rendering locally.
+pub fn try_forward(port: u16, args: &ResamplerArgs) -> Option<Result<()>> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_millis(250)).ok()?;
+    let token = match token_path(port).and_then(|path| Ok(std::fs::read_to_string(path)?)) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("A server is listening on port {} but its token is unreadable: {:#}", port, e);
+            return None;
+        }
+    };
+    info!("Forwarding note to server on port {}", port);
+    Some(forward(stream, args, token.trim()))
+}
+
+fn forward(mut stream: TcpStream, args: &ResamplerArgs, token: &str) -> Result<()> {
+    // The server has its own working directory, so send absolute paths.
+    let cwd = std::env::current_dir()?;
+    let mut args = args.clone();
+    args.in_file = cwd.join(&args.in_file).to_string_lossy().into_owned();
+    args.out_file = cwd.join(&args.out_file).to_string_lossy().into_owned();
+
+    let request = JobRequest { id: None, token: Some(token.to_string()), args };
+    serde_json::to_writer(&mut stream, &request)?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)
+        .context("Server closed the connection")?;
+    let response: JobResponse = serde_json::from_str(&line)
+        .context("Invalid response from server")?;
+    if response.ok {
+        Ok(())
+    } else {
+        Err(RemoteError {
+            message: response.error.unwrap_or_else(|| "Server failed to render note".into()),
+            exit_code: response.exit_code.unwrap_or(1),
+        }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job_line(id: u64, token: &str, out_file: &str) -> String {
+        format!(
+            r#"{{"id":{},"token":"{}","in_file":"a.wav","out_file":"{}","pitch":60,"velocity":100,"flags":"","offset":0,"length":500,"consonant":0,"cutoff":0,"volume":100,"modulation":0,"tempo":120,"pitchbend":null}}"#,
+            id, token, out_file
+        )
+    }
+
+    #[test]
+    fn test_connection_requires_token() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        // No workers, so accepted jobs are answered without rendering
+        let pool = WorkerPool::new(Vec::new());
+        let server = thread::spawn(move || {
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().unwrap();
+                handle_connection(stream, &pool, "secret").unwrap();
+            }
+        });
+
+        let job = |token: &str| job_line(7, token, "b.wav");
+        let converse = |lines: &[String]| -> Vec<JobResponse> {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for line in lines {
+                // The server may already have closed the connection
+                let _ = stream.write_all(format!("{}\n", line).as_bytes());
+            }
+            stream.shutdown(std::net::Shutdown::Write).ok();
+            BufReader::new(stream).lines().map(|l| serde_json::from_str(&l.unwrap()).unwrap()).collect()
+        };
+
+        let accepted = converse(&[job("secret"), job("secret")]);
+        assert_eq!(accepted.len(), 2);
+        assert_eq!(accepted[0].id, Some(7));
+        assert_eq!(accepted[0].error.as_deref(), Some("No render workers available"));
+
+        // A cross-protocol request: its first line is rejected and the job in the body never runs
+        let rejected = converse(&["POST / HTTP/1.1".into(), job("secret")]);
+        assert_eq!(rejected.len(), 1);
+        assert!(rejected[0].error.as_deref().unwrap().starts_with("Invalid request"));
+
+        let rejected = converse(&[job("wrong"), job("secret")]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].error.as_deref(), Some("Invalid or missing server token"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_render() {
+        let pool = WorkerPool::with_renderers(vec![|args: &ResamplerArgs| {
+            assert!(args.out_file != "panic.wav", "render blew up");
+            Ok(())
+        }]);
+        let request = |out_file: &str, id| -> JobRequest { serde_json::from_str(&job_line(id, "", out_file)).unwrap() };
+
+        let failed = pool.submit(request("panic.wav", 1)).recv().unwrap();
+        assert!(!failed.ok);
+        assert_eq!(failed.id, Some(1));
+        assert_eq!(failed.exit_code, Some(101));
+        assert!(failed.error.unwrap().contains("render blew up"));
+
+        // The single worker is still there for the next job
+        assert!(pool.submit(request("b.wav", 2)).recv().unwrap().ok);
+    }
+}
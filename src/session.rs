@@ -0,0 +1,319 @@
+use anyhow::{Context, Result};
+use crate::analysis::{hook_plugins, load_or_analyze, AnalysisOptions, AxisFeatures};
+use crate::api::{AxisConfig, AxisPlugin, IncompatiblePlugin, PluginAbi, PluginDatabase, PluginLoader, PluginMetadata, PluginSettings, PluginStatus};
+use crate::args::ResamplerArgs;
+use crate::audio::OutputFormat;
+use crate::metadata::WavMetadata;
+use crate::oto::{self, OtoEntry};
+use crate::{audio, rate, resampler};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A decoded source sample together with its analysis.
+pub struct CachedSample {
+    pub samples: Vec<f64>,
+    pub sample_rate: u32,
+    pub features: Option<AxisFeatures>,
+    /// Cue points, tags and loops of the source file.
+    pub metadata: WavMetadata,
+    /// The `oto.ini` entries for the source file.
+    pub oto: Vec<OtoEntry>,
+}
+
+struct CacheEntry {
+    modified: Option<SystemTime>,
+    len: u64,
+    last_used: u64,
+    sample: Arc<CachedSample>,
+}
+
+/// In-memory cache of decoded samples and their features, shared by all
+/// render workers. Entries are invalidated when the source file changes and
+/// evicted least-recently-used once `capacity` is exceeded.
+pub struct SampleCache {
+    capacity: usize,
+    entries: Mutex<(u64, HashMap<PathBuf, CacheEntry>)>,
+}
+
+impl SampleCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// `plugins` are the analysis-hook plugins described by `options.plugin_chain`.
+    pub fn get_or_load(&self, path: &str, options: &AnalysisOptions, plugins: &mut [&mut dyn AxisPlugin]) -> Result<Arc<CachedSample>> {
+        let key = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let meta = std::fs::metadata(&key)
+            .map_err(audio::AudioError::Io)
+            .with_context(|| format!("Failed to open audio file: {}", key.display()))?;
+        let modified = meta.modified().ok();
+
+        {
+            let mut guard = self.entries.lock().unwrap();
+            let (clock, entries) = &mut *guard;
+            *clock += 1;
+            if let Some(entry) = entries.get_mut(&key) {
+                if entry.modified == modified && entry.len == meta.len() {
+                    entry.last_used = *clock;
+                    debug!("Using cached sample {}", key.display());
+                    return Ok(entry.sample.clone());
+                }
+            }
+        }
+
+        let sample = Arc::new(load_sample(path, options, plugins)?);
+
+        let mut guard = self.entries.lock().unwrap();
+        let (clock, entries) = &mut *guard;
+        *clock += 1;
+        entries.insert(key, CacheEntry { modified, len: meta.len(), last_used: *clock, sample: sample.clone() });
+        while entries.len() > self.capacity {
+            let oldest = entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => { entries.remove(&k); }
+                None => break,
+            }
+        }
+        Ok(sample)
+    }
+}
+
+fn load_sample(path: &str, options: &AnalysisOptions, plugins: &mut [&mut dyn AxisPlugin]) -> Result<CachedSample> {
+    let (samples, sample_rate) = options.load_source(Path::new(path))
+        .with_context(|| format!("Failed to load audio from {}", path))?;
+    let features = if samples.is_empty() {
+        None
+    } else {
+        Some(load_or_analyze(path, &samples, sample_rate, options, plugins)?)
+    };
+    let metadata = WavMetadata::read(Path::new(path)).unwrap_or_else(|e| {
+        warn!("Failed to read metadata of {}: {:#}", path, e);
+        WavMetadata::default()
+    });
+    let oto = oto::entries_for(Path::new(path)).unwrap_or_else(|e| {
+        warn!("Failed to read oto.ini for {}: {:#}", path, e);
+        Vec::new()
+    });
+    Ok(CachedSample { samples, sample_rate, features, metadata, oto })
+}
+
+/// A plugin selected for rendering, with its settings from `config.kdl`.
+#[derive(Debug, Clone)]
+pub struct EnabledPlugin {
+    pub meta: PluginMetadata,
+    pub path: String,
+    pub settings: PluginSettings,
+    /// Compatibility recorded in `plugin.db` when the plugin was last checked.
+    pub status: PluginStatus,
+}
+
+/// Lists the plugins to load, in `plugin.db` order. An `enabled` property on
+/// a plugin's `config.kdl` block takes precedence over the database flag.
+pub fn enabled_plugins(db: &PluginDatabase, config: &AxisConfig) -> Result<Vec<EnabledPlugin>> {
+    let registered = db.list_plugins()?;
+    for block in &config.plugins {
+        if !registered.iter().any(|r| r.meta.name == block.name) {
+            warn!("config.kdl configures plugin '{}', which is not registered", block.name);
+        }
+    }
+
+    let mut plugins = Vec::new();
+    for record in registered {
+        let (meta, db_enabled) = (record.meta, record.enabled);
+        let block = config.plugin(&meta.name);
+        let enabled = match block.and_then(|b| b.enabled) {
+            Some(kdl_enabled) => {
+                if kdl_enabled != db_enabled {
+                    info!("Plugin {} is {} by config.kdl (plugin.db: {})", meta.name,
+                        if kdl_enabled { "enabled" } else { "disabled" },
+                        if db_enabled { "enabled" } else { "disabled" });
+                }
+                kdl_enabled
+            }
+            None => db_enabled,
+        };
+        if enabled {
+            let settings = block.map(|b| b.plugin_settings()).unwrap_or_default();
+            plugins.push(EnabledPlugin { meta, path: record.path, settings, status: record.status });
+        }
+    }
+    Ok(plugins)
+}
+
+/// Loads and configures every plugin in `entries`, logging and skipping the
+/// ones that fail. Compatibility changes are written back to `db`.
+pub fn load_plugins(entries: &[EnabledPlugin], db: &PluginDatabase) -> Vec<PluginLoader> {
+    let mut loaders = Vec::new();
+    for entry in entries {
+        info!("Loading plugin: {} v{} from {}", entry.meta.name, entry.meta.version, entry.path);
+        let mut loader = match unsafe { PluginLoader::load(&entry.path) } {
+            Ok(loader) => loader,
+            Err(e) => {
+                error!("Failed to load plugin {}: {}", entry.meta.name, e);
+                if let Some(incompatible) = e.downcast_ref::<IncompatiblePlugin>() {
+                    if incompatible.status != entry.status {
+                        record_compatibility(db, entry, &incompatible.abi);
+                    }
+                }
+                continue;
+            }
+        };
+        if entry.status != PluginStatus::Compatible {
+            record_compatibility(db, entry, &loader.abi());
+        }
+        if let Err(e) = loader.plugin().on_load().and_then(|_| loader.configure(&entry.settings)) {
+            error!("Failed to initialize plugin {}: {:#}", entry.meta.name, e);
+            continue;
+        }
+        loaders.push(loader);
+    }
+    loaders
+}
+
+/// Something `axis plugin doctor` found wrong with a plugin entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginProblem {
+    /// Nothing exists at the registered library path.
+    MissingLibrary(String),
+    /// The library was built for another ABI or host version.
+    Incompatible(String),
+    /// The library exists but could not be loaded as a plugin.
+    LoadFailed(String),
+    /// The library now reports a different name or version than was registered.
+    Changed { name: String, version: String },
+    /// Another entry is registered with the same library.
+    DuplicatePath(String),
+    /// `config.kdl` has a block for a plugin that is not registered.
+    UnregisteredConfig,
+}
+
+impl std::fmt::Display for PluginProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingLibrary(path) => write!(f, "library not found at {} (remove the entry with `axis plugin remove`)", path),
+            Self::Incompatible(reason) => write!(f, "incompatible: {}", reason),
+            Self::LoadFailed(reason) => write!(f, "failed to load: {}", reason),
+            Self::Changed { name, version } => {
+                write!(f, "library now reports {} v{} (re-register it with `axis plugin add`)", name, version)
+            }
+            Self::DuplicatePath(other) => write!(f, "uses the same library as {}", other),
+            Self::UnregisteredConfig => write!(f, "configured in config.kdl but not registered"),
+        }
+    }
+}
+
+/// Checks every registered plugin's library and refreshes its compatibility
+/// status in `db`. Returns (plugin name, problem) pairs in chain order.
+pub fn check_plugins(db: &PluginDatabase, config: &AxisConfig) -> Result<Vec<(String, PluginProblem)>> {
+    let registered = db.list_plugins()?;
+    let mut problems = Vec::new();
+
+    for (i, record) in registered.iter().enumerate() {
+        let name = &record.meta.name;
+        if !Path::new(&record.path).exists() {
+            problems.push((name.clone(), PluginProblem::MissingLibrary(record.path.clone())));
+            continue;
+        }
+        if let Some(other) = registered[..i].iter().find(|r| r.path == record.path) {
+            problems.push((name.clone(), PluginProblem::DuplicatePath(other.meta.name.clone())));
+        }
+
+        let entry = EnabledPlugin {
+            meta: record.meta.clone(),
+            path: record.path.clone(),
+            settings: PluginSettings::default(),
+            status: record.status,
+        };
+        match unsafe { PluginLoader::load(&record.path) } {
+            Ok(mut loader) => {
+                if record.status != PluginStatus::Compatible {
+                    record_compatibility(db, &entry, &loader.abi());
+                }
+                let meta = loader.plugin().metadata();
+                if meta.name != record.meta.name || meta.version != record.meta.version {
+                    problems.push((name.clone(), PluginProblem::Changed { name: meta.name, version: meta.version }));
+                }
+            }
+            Err(e) => match e.downcast_ref::<IncompatiblePlugin>() {
+                Some(incompatible) => {
+                    if incompatible.status != record.status {
+                        record_compatibility(db, &entry, &incompatible.abi);
+                    }
+                    problems.push((name.clone(), PluginProblem::Incompatible(incompatible.to_string())));
+                }
+                None => problems.push((name.clone(), PluginProblem::LoadFailed(format!("{:#}", e)))),
+            },
+        }
+    }
+
+    for block in &config.plugins {
+        if !registered.iter().any(|r| r.meta.name == block.name) {
+            problems.push((block.name.clone(), PluginProblem::UnregisteredConfig));
+        }
+    }
+    Ok(problems)
+}
+
+fn record_compatibility(db: &PluginDatabase, entry: &EnabledPlugin, abi: &PluginAbi) {
+    if let Err(e) = db.set_plugin_compatibility(&entry.meta.name, abi) {
+        warn!("Failed to record compatibility of plugin {}: {:#}", entry.meta.name, e);
+    }
+}
+
+/// Everything needed to render notes: config, a private set of plugin
+/// instances and a (possibly shared) sample cache.
+pub struct Session {
+    config: Arc<AxisConfig>,
+    options: AnalysisOptions,
+    loaders: Vec<PluginLoader>,
+    cache: Arc<SampleCache>,
+}
+
+impl Session {
+    pub fn new(config: Arc<AxisConfig>, loaders: Vec<PluginLoader>, cache: Arc<SampleCache>) -> Result<Self> {
+        let options = AnalysisOptions::from_config(&config)?.with_plugins(&loaders);
+        Ok(Self { config, options, loaders, cache })
+    }
+
+    pub fn render(&mut self, args: &ResamplerArgs) -> Result<()> {
+        let sample = self.cache.get_or_load(&args.in_file, &self.options, &mut hook_plugins(&mut self.loaders))?;
+
+        let resampled = match &sample.features {
+            Some(features) => {
+                let mut plugin_refs: Vec<&mut dyn AxisPlugin> = self.loaders
+                    .iter_mut()
+                    .map(|l| l.plugin())
+                    .collect();
+                resampler::render(args, features, &sample.samples, sample.sample_rate, &mut plugin_refs, &self.config)
+                    .context("Failed to resample audio")?
+            }
+            None => Vec::new(),
+        };
+
+        let (resampled, output_rate) = match self.options.output_rate {
+            Some(rate) if rate != sample.sample_rate => (rate::convert(&resampled, sample.sample_rate, rate)?, rate),
+            _ => (resampled, sample.sample_rate),
+        };
+
+        let metadata = match &sample.features {
+            Some(features) => {
+                let entry = oto::find_entry(&sample.oto, args.offset, args.consonant);
+                resampler::note_metadata(args, features.f0.len(), &sample.metadata, entry, output_rate)
+            }
+            None => WavMetadata::default(),
+        };
+
+        let out_file = Path::new(&args.out_file);
+        let format = OutputFormat::resolve(out_file, self.config.output_format().as_deref())
+            .context("Invalid output-format")?;
+        let seed = resampler::synthesis_seed(args, &self.config);
+        audio::save_audio(out_file, &resampled, output_rate, format, &metadata, seed)
+            .with_context(|| format!("Failed to save audio to {}", args.out_file))
+    }
+}
@@ -1,3 +1,5 @@
+use rand::{rngs::StdRng, SeedableRng};
+use std::hash::Hasher;
 use std::str::FromStr;
 
 pub fn pitch_parser(s: &str) -> Result<i32, String> {
@@ -135,6 +137,73 @@ pub fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
     t * t * (3.0 - 2.0 * t)
 }
 
+/// Platform config and data directories of axis.
+pub fn project_dirs() -> Option<directories::ProjectDirs> {
+    directories::ProjectDirs::from("com", "cyntheria", "axis")
+}
+
+/// Resolves the `threads` setting: zero, negative or unset means one worker per CPU.
+pub fn thread_count(threads: Option<i32>) -> usize {
+    match threads {
+        Some(n) if n > 0 => n as usize,
+        _ => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    }
+}
+
+/// Computes `f(0..len)` on up to `threads` scoped threads, preserving order.
+pub fn parallel_map<T, F>(len: usize, threads: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    let threads = threads.clamp(1, len.max(1));
+    if threads == 1 {
+        return (0..len).map(f).collect();
+    }
+    let chunk = len.div_ceil(threads);
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| scope.spawn(move || (t * chunk..((t + 1) * chunk).min(len)).map(f).collect::<Vec<T>>()))
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+/// Random stream number `index` of `seed`. Synthesis draws one stream per
+/// frame or event, so the output does not depend on how work is split across threads.
+pub fn seeded_rng(seed: u64, index: u64) -> StdRng {
+    StdRng::seed_from_u64(seed ^ index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+/// Streams of `seeded_rng` past every frame and event index: the initial
+/// harmonic phases of a note and the dither of its output file.
+pub const PHASE_STREAM: u64 = u64::MAX;
+pub const DITHER_STREAM: u64 = u64::MAX - 1;
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is fixed across Rust
+/// releases, so it is safe to persist in `.axxf` headers.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -175,6 +244,29 @@ mod tests {
         assert_eq!(smoothstep(0.0, 1.0, 1.0), 1.0);
         assert_eq!(smoothstep(0.0, 1.0, 0.5), 0.5);
     }
+
+    #[test]
+    fn test_parallel_map_preserves_order() {
+        let squares = parallel_map(10, 3, |i| i * i);
+        assert_eq!(squares, (0..10).map(|i| i * i).collect::<Vec<_>>());
+        assert!(parallel_map(0, 4, |i| i).is_empty());
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        let mut h = Fnv1a::default();
+        h.write(b"a");
+        assert_eq!(h.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_phase_and_dither_streams_differ() {
+        use rand::Rng;
+        for seed in [0, 7, u64::MAX] {
+            let draws = |stream| seeded_rng(seed, stream).sample_iter(rand::distributions::Standard).take(64).collect::<Vec<f64>>();
+            assert_ne!(draws(PHASE_STREAM), draws(DITHER_STREAM), "seed {}", seed);
+        }
+    }
 }
 
 pub fn smooth_spectrum(spec: &mut [f64], width: usize) {
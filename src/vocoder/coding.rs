@@ -0,0 +1,276 @@
+use crate::analysis::AxisFeatures;
+use serde::{Deserialize, Serialize};
+
+/// Mel-cepstral coefficients kept per frame.
+pub const MCEP_ORDER: usize = 60;
+
+/// Aperiodicity bands, evenly spaced on the same warped axis as the cepstrum.
+pub const AP_BANDS: usize = 32;
+
+/// Envelope floor relative to the loudest bin of a frame (-100 dB), so the
+/// log of silent bins does not swamp the cepstrum.
+const RELATIVE_FLOOR: f64 = 1e-10;
+
+/// How `.axxf` files store the spectral envelope and aperiodicity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheCompression {
+    /// Every FFT bin as f64.
+    #[default]
+    None,
+    /// Mel-cepstrum for the envelope and band aperiodicity, as f32.
+    MelCepstrum,
+}
+
+impl CacheCompression {
+    /// Identifies the coding in `.axxf` headers.
+    pub fn name(&self) -> String {
+        match self {
+            CacheCompression::None => "none".to_string(),
+            CacheCompression::MelCepstrum => format!("mcep order={} bands={}", MCEP_ORDER, AP_BANDS),
+        }
+    }
+}
+
+impl std::str::FromStr for CacheCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CacheCompression::None),
+            "mcep" => Ok(CacheCompression::MelCepstrum),
+            other => anyhow::bail!("Unknown cache compression \"{}\" (expected \"none\" or \"mcep\")", other),
+        }
+    }
+}
+
+/// `AxisFeatures` with the envelope and aperiodicity coded, about 1% of the size.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CodedFeatures {
+    pub f0: Vec<f64>,
+    pub voicing: Vec<f32>,
+    /// `MCEP_ORDER` coefficients per frame of the log power envelope.
+    pub mcep: Vec<Vec<f32>>,
+    /// `AP_BANDS` mean aperiodicities per frame.
+    pub band_ap: Vec<Vec<f32>>,
+    pub source_base_hz: f64,
+    pub fft_size: usize,
+    pub f0_threshold: f64,
+    /// Number of bins of the decoded envelope and aperiodicity.
+    pub num_bins: usize,
+    pub alpha: f64,
+}
+
+/// All-pass frequency warping constant that approximates the mel scale at
+/// `sample_rate`, interpolated from the usual SPTK values.
+pub fn mel_alpha(sample_rate: u32) -> f64 {
+    const TABLE: [(f64, f64); 6] = [(8000.0, 0.31), (16000.0, 0.42), (22050.0, 0.45), (32000.0, 0.50), (44100.0, 0.53), (48000.0, 0.55)];
+    let fs = (sample_rate as f64).clamp(TABLE[0].0, TABLE[TABLE.len() - 1].0);
+    let i = TABLE.windows(2).position(|w| fs <= w[1].0).unwrap_or(TABLE.len() - 2);
+    let ((f_lo, a_lo), (f_hi, a_hi)) = (TABLE[i], TABLE[i + 1]);
+    crate::util::lerp(a_lo, a_hi, (fs / f_lo).ln() / (f_hi / f_lo).ln())
+}
+
+/// Maps a normalized frequency `omega` in `0..=PI` through the all-pass
+/// warping of `alpha`; `-alpha` undoes it.
+fn warp(omega: f64, alpha: f64) -> f64 {
+    omega + 2.0 * (alpha * omega.sin() / (1.0 - alpha * omega.cos())).atan()
+}
+
+/// `values` read at fractional index `pos`, clamped to its ends.
+fn sample(values: &[f64], pos: f64) -> f64 {
+    let pos = pos.clamp(0.0, (values.len() - 1) as f64);
+    let i = (pos.floor() as usize).min(values.len() - 1);
+    let j = (i + 1).min(values.len() - 1);
+    crate::util::lerp(values[i], values[j], pos - i as f64)
+}
+
+/// Mel-cepstrum and band aperiodicity coding for spectra of `num_bins` bins,
+/// with the cosine tables computed once for all frames.
+pub struct MelCoder {
+    pub alpha: f64,
+    pub num_bins: usize,
+    /// Fractional linear bin under each point of the evenly spaced warped grid.
+    grid_bins: Vec<f64>,
+    /// `[q][j]` DCT basis over the warped grid.
+    analysis: Vec<Vec<f64>>,
+    /// `[k][q]` cosine of each coefficient at linear bin `k`.
+    synthesis: Vec<Vec<f64>>,
+    /// Position of each linear bin on the warped axis, in `0.0..=1.0`.
+    positions: Vec<f64>,
+}
+
+impl MelCoder {
+    pub fn new(num_bins: usize, alpha: f64) -> Self {
+        let pi = std::f64::consts::PI;
+        let grid = 2 * (num_bins - 1);
+        let grid_bins = (0..grid)
+            .map(|j| warp(pi * (j as f64 + 0.5) / grid as f64, -alpha) / pi * (num_bins - 1) as f64)
+            .collect();
+        let analysis = (0..MCEP_ORDER)
+            .map(|q| (0..grid).map(|j| (pi * q as f64 * (j as f64 + 0.5) / grid as f64).cos()).collect())
+            .collect();
+        let positions: Vec<f64> = (0..num_bins).map(|k| warp(pi * k as f64 / (num_bins - 1) as f64, alpha) / pi).collect();
+        let synthesis = positions.iter().map(|&pos| (0..MCEP_ORDER).map(|q| (pi * q as f64 * pos).cos()).collect()).collect();
+        Self { alpha, num_bins, grid_bins, analysis, synthesis, positions }
+    }
+
+    /// Mel-cepstrum of one power envelope: the log envelope resampled evenly
+    /// on the warped axis, then a DCT truncated to `MCEP_ORDER` coefficients.
+    pub fn encode_envelope(&self, spec: &[f64]) -> Vec<f32> {
+        let floor = spec.iter().cloned().fold(0.0, f64::max) * RELATIVE_FLOOR + f64::MIN_POSITIVE;
+        let log_spec: Vec<f64> = spec.iter().map(|&p| p.max(floor).ln()).collect();
+        let warped: Vec<f64> = self.grid_bins.iter().map(|&pos| sample(&log_spec, pos)).collect();
+        self.analysis
+            .iter()
+            .map(|basis| (2.0 * basis.iter().zip(&warped).map(|(b, l)| b * l).sum::<f64>() / warped.len() as f64) as f32)
+            .collect()
+    }
+
+    pub fn decode_envelope(&self, mcep: &[f32]) -> Vec<f64> {
+        self.synthesis
+            .iter()
+            .map(|cosines| {
+                let log: f64 = mcep.iter().zip(cosines).skip(1).map(|(&c, cos)| c as f64 * cos).sum();
+                (mcep[0] as f64 / 2.0 + log).exp()
+            })
+            .collect()
+    }
+
+    /// Mean aperiodicity of `AP_BANDS` bands evenly spaced on the warped axis.
+    pub fn encode_aperiodicity(&self, ap: &[f64]) -> Vec<f32> {
+        let mut sums = vec![0.0; AP_BANDS];
+        let mut counts = vec![0usize; AP_BANDS];
+        for (&a, &pos) in ap.iter().zip(&self.positions) {
+            let band = ((pos * AP_BANDS as f64) as usize).min(AP_BANDS - 1);
+            sums[band] += a;
+            counts[band] += 1;
+        }
+        // Bands too narrow to hold a bin borrow from the next band up
+        let mut band_ap = vec![1.0f32; AP_BANDS];
+        let mut carry = None;
+        for b in (0..AP_BANDS).rev() {
+            if counts[b] > 0 {
+                carry = Some((sums[b] / counts[b] as f64) as f32);
+            }
+            if let Some(value) = carry {
+                band_ap[b] = value;
+            }
+        }
+        band_ap
+    }
+
+    /// Linear between band centres.
+    pub fn decode_aperiodicity(&self, band_ap: &[f32]) -> Vec<f64> {
+        let bands: Vec<f64> = band_ap.iter().map(|&a| a as f64).collect();
+        self.positions
+            .iter()
+            .map(|&pos| sample(&bands, pos * bands.len() as f64 - 0.5).clamp(0.0, 1.0))
+            .collect()
+    }
+}
+
+impl CodedFeatures {
+    pub fn encode(features: &AxisFeatures, sample_rate: u32) -> Self {
+        let alpha = mel_alpha(sample_rate);
+        let num_bins = features.spec.first().map_or(features.fft_size / 2 + 1, Vec::len);
+        let coder = MelCoder::new(num_bins, alpha);
+        Self {
+            f0: features.f0.clone(),
+            voicing: features.voicing.iter().map(|&v| v as f32).collect(),
+            mcep: features.spec.iter().map(|s| coder.encode_envelope(s)).collect(),
+            band_ap: features.ap.iter().map(|a| coder.encode_aperiodicity(a)).collect(),
+            source_base_hz: features.source_base_hz,
+            fft_size: features.fft_size,
+            f0_threshold: features.f0_threshold,
+            num_bins,
+            alpha,
+        }
+    }
+
+    pub fn decode(&self) -> AxisFeatures {
+        let coder = MelCoder::new(self.num_bins, self.alpha);
+        AxisFeatures {
+            f0: self.f0.clone(),
+            spec: self.mcep.iter().map(|c| coder.decode_envelope(c)).collect(),
+            ap: self.band_ap.iter().map(|a| coder.decode_aperiodicity(a)).collect(),
+            voicing: self.voicing.iter().map(|&v| v as f64).collect(),
+            source_base_hz: self.source_base_hz,
+            fft_size: self.fft_size,
+            f0_threshold: self.f0_threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: u32 = 44100;
+    const NUM_BINS: usize = 2049;
+
+    fn bin_hz(k: usize) -> f64 {
+        k as f64 * FS as f64 / 2.0 / (NUM_BINS - 1) as f64
+    }
+
+    /// Formant peaks over a spectral tilt of about -12 dB per octave, as a power envelope.
+    fn envelope(shift: f64) -> Vec<f64> {
+        (0..NUM_BINS)
+            .map(|k| {
+                let f = bin_hz(k) * shift;
+                let formants: f64 = [(700.0, 20.0, 120.0), (1200.0, 15.0, 150.0), (2600.0, 12.0, 250.0), (3500.0, 8.0, 300.0)]
+                    .iter()
+                    .map(|&(center, gain, width): &(f64, f64, f64)| gain * (-0.5 * ((f - center) / width).powi(2)).exp())
+                    .sum();
+                let db = -40.0 * (1.0 + f / 500.0).log10() + formants;
+                10f64.powf(db / 10.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_error_bounds() {
+        let coder = MelCoder::new(NUM_BINS, mel_alpha(FS));
+        for shift in [0.8, 1.0, 1.25] {
+            let spec = envelope(shift);
+            let decoded = coder.decode_envelope(&coder.encode_envelope(&spec));
+            assert_eq!(decoded.len(), NUM_BINS);
+            let errors: Vec<f64> = (0..NUM_BINS)
+                .filter(|&k| (50.0..16000.0).contains(&bin_hz(k)))
+                .map(|k| 10.0 * (decoded[k] / spec[k]).log10())
+                .collect();
+            let rms = (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt();
+            let max = errors.iter().fold(0.0, |m: f64, e| m.max(e.abs()));
+            assert!(rms < 0.5 && max < 2.0, "shift {}: rms {:.3} dB, max {:.3} dB", shift, rms, max);
+        }
+
+        // Breathier towards the top, as D4C reports for most voices
+        let ap: Vec<f64> = (0..NUM_BINS).map(|k| 0.05 + 0.9 / (1.0 + (-(bin_hz(k) - 6000.0) / 1500.0).exp())).collect();
+        let decoded = coder.decode_aperiodicity(&coder.encode_aperiodicity(&ap));
+        let max = ap.iter().zip(&decoded).fold(0.0, |m: f64, (a, d)| m.max((a - d).abs()));
+        assert!(max < 0.03, "aperiodicity error {:.4}", max);
+        assert!(coder.decode_aperiodicity(&coder.encode_aperiodicity(&[1.0; NUM_BINS])).iter().all(|&a| a == 1.0));
+    }
+
+    #[test]
+    fn test_coded_features_shrink() {
+        let features = AxisFeatures {
+            f0: vec![220.0; 10],
+            spec: vec![envelope(1.0); 10],
+            ap: vec![vec![0.2; NUM_BINS]; 10],
+            voicing: vec![1.0; 10],
+            source_base_hz: 220.0,
+            fft_size: 4096,
+            f0_threshold: 40.0,
+        };
+        let coded = CodedFeatures::encode(&features, FS);
+        let full_size = bincode::serialize(&features).unwrap().len();
+        let coded_size = bincode::serialize(&coded).unwrap().len();
+        assert!(coded_size * 50 < full_size, "{} vs {} bytes", coded_size, full_size);
+
+        let decoded = coded.decode();
+        assert_eq!(decoded.f0, features.f0);
+        assert_eq!(decoded.spec.len(), 10);
+        assert_eq!(decoded.ap[3].len(), NUM_BINS);
+        assert!(decoded.ap[3].iter().all(|&a| (a - 0.2).abs() < 1e-6));
+    }
+}
@@ -1,37 +1,56 @@
+use crate::vocoder::f0::{F0Candidate, F0Estimator};
+use crate::vocoder::frames::FramePeriod;
+
+/// Analysis window of the autocorrelation pitch search, centred on each frame.
+const WINDOW_LEN: usize = 1024;
+
+/// Candidates reported per frame by `F0Estimator::candidates`.
+const MAX_CANDIDATES: usize = 3;
+
 pub struct Dio {
     pub sample_rate: u32,
+    pub floor: f64,
+    pub ceiling: f64,
 }
 
 impl Dio {
+    pub const DEFAULT_RANGE: (f64, f64) = (50.0, 500.0);
+
     pub fn new(sample_rate: u32) -> Self {
-        Self { sample_rate }
+        let (floor, ceiling) = Self::DEFAULT_RANGE;
+        Self { sample_rate, floor, ceiling }
     }
 
-    pub fn estimate(&self, input: &[f64]) -> Vec<f64> {
-        let hop_size = 256;
-        let num_frames = input.len() / hop_size;
-        let mut f0 = vec![0.0; num_frames];
-        for i in 0..num_frames {
-            let start = i * hop_size;
-            let end = (start + 1024).min(input.len());
-            let chunk = &input[start..end];
-            f0[i] = self.detect_pitch(chunk);
-        }
-        self.stonemask(input, &f0)
+    /// Limits the pitch search to `floor..ceiling` Hz.
+    pub fn with_range(mut self, floor: f64, ceiling: f64) -> Self {
+        self.floor = floor;
+        self.ceiling = ceiling;
+        self
+    }
+
+    fn lag_range(&self) -> (usize, usize) {
+        ((self.sample_rate as f64 / self.ceiling) as usize, (self.sample_rate as f64 / self.floor) as usize)
+    }
+
+    /// Long enough that the longest lag still overlaps the shortest by a period.
+    fn window_len(&self, input_len: usize) -> usize {
+        let (min_lag, max_lag) = self.lag_range();
+        WINDOW_LEN.max(max_lag + min_lag).min(input_len)
+    }
+
+    /// Raw autocorrelation of `chunk` at every lag of the search range.
+    fn correlation(&self, chunk: &[f64]) -> Vec<(usize, f64)> {
+        if chunk.len() < 512 { return Vec::new(); }
+        let (min_lag, max_lag) = self.lag_range();
+        (min_lag..max_lag.min(chunk.len()))
+            .map(|lag| (lag, (0..chunk.len() - lag).map(|i| chunk[i] * chunk[i + lag]).sum()))
+            .collect()
     }
 
     fn detect_pitch(&self, chunk: &[f64]) -> f64 {
-        if chunk.len() < 512 { return 0.0; }
         let mut max_corr = 0.0;
         let mut best_lag = 0;
-        let min_lag = self.sample_rate as usize / 500;
-        let max_lag = self.sample_rate as usize / 50;
-        for lag in min_lag..max_lag {
-            if lag >= chunk.len() { break; }
-            let mut corr = 0.0;
-            for i in 0..chunk.len().saturating_sub(lag) {
-                corr += chunk[i] * chunk[i + lag];
-            }
+        for (lag, corr) in self.correlation(chunk) {
             if corr > max_corr {
                 max_corr = corr;
                 best_lag = lag;
@@ -44,15 +63,12 @@ impl Dio {
         }
     }
 
-    fn stonemask(&self, input: &[f64], f0: &[f64]) -> Vec<f64> {
+    fn stonemask(&self, input: &[f64], f0: &[f64], frames: &FramePeriod) -> Vec<f64> {
         let mut refined_f0 = f0.to_vec();
-        let hop_size = 256;
         for (i, &initial_f0) in f0.iter().enumerate() {
             if initial_f0 <= 40.0 { continue; }
-            let start = i * hop_size;
-            let end = (start + 1024).min(input.len());
-            let chunk = &input[start..end];
-            refined_f0[i] = self.refine_local(chunk, initial_f0);
+            let chunk = frames.chunk(input, i, self.window_len(input.len()));
+            refined_f0[i] = self.refine_local(&chunk, initial_f0);
         }
         refined_f0
     }
@@ -82,3 +98,38 @@ impl Dio {
         energy
     }
 }
+
+impl F0Estimator for Dio {
+    fn name(&self) -> String {
+        format!("dio {}-{}", self.floor, self.ceiling)
+    }
+
+    fn estimate(&self, input: &[f64], frames: &FramePeriod) -> Vec<f64> {
+        let f0: Vec<f64> = (0..frames.frame_count(input.len()))
+            .map(|i| self.detect_pitch(&frames.chunk(input, i, self.window_len(input.len()))))
+            .collect();
+        self.stonemask(input, &f0, frames)
+    }
+
+    /// The strongest autocorrelation peaks, with their correlation normalized by the frame energy.
+    fn candidates(&self, input: &[f64], frames: &FramePeriod) -> Vec<Vec<F0Candidate>> {
+        (0..frames.frame_count(input.len()))
+            .map(|i| {
+                let chunk = frames.chunk(input, i, self.window_len(input.len()));
+                let energy: f64 = chunk.iter().map(|x| x * x).sum();
+                let corr = self.correlation(&chunk);
+                let mut peaks: Vec<F0Candidate> = corr
+                    .windows(3)
+                    .filter(|w| w[1].1 > 0.0 && w[1].1 > w[0].1 && w[1].1 >= w[2].1)
+                    .map(|w| F0Candidate {
+                        f0: self.sample_rate as f64 / w[1].0 as f64,
+                        confidence: (w[1].1 / energy).clamp(0.0, 1.0),
+                    })
+                    .collect();
+                peaks.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+                peaks.truncate(MAX_CANDIDATES);
+                peaks
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,21 @@
+use crate::vocoder::frames::FramePeriod;
+
+/// One possible pitch of a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct F0Candidate {
+    pub f0: f64,
+    /// How likely this candidate is the true pitch, in `0.0..=1.0`.
+    pub confidence: f64,
+}
+
+pub trait F0Estimator: Send + Sync {
+    /// Identifies the algorithm and its search range in `.axxf` headers.
+    fn name(&self) -> String;
+
+    /// One F0 value per frame of `frames`, 0.0 where unvoiced.
+    fn estimate(&self, input: &[f64], frames: &FramePeriod) -> Vec<f64>;
+
+    /// Pitch candidates of every frame of `frames`, most confident first.
+    /// Unvoiced frames have none.
+    fn candidates(&self, input: &[f64], frames: &FramePeriod) -> Vec<Vec<F0Candidate>>;
+}
@@ -0,0 +1,78 @@
+/// Frame timing shared by analysis and synthesis. Frame `i` sits at
+/// `i * period_ms` milliseconds. The hop in samples is fractional, so the
+/// frame rate, and with it every duration, is the same at any sample rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FramePeriod {
+    pub sample_rate: u32,
+    pub period_ms: f64,
+}
+
+impl FramePeriod {
+    pub fn new(sample_rate: u32, period_ms: f64) -> Self {
+        Self { sample_rate, period_ms }
+    }
+
+    /// Samples per frame; usually not a whole number.
+    pub fn hop(&self) -> f64 {
+        self.sample_rate as f64 * self.period_ms / 1000.0
+    }
+
+    /// Sample position of frame `i`.
+    pub fn center(&self, i: usize) -> f64 {
+        i as f64 * self.hop()
+    }
+
+    /// Frames needed to analyze `num_samples` samples: one at every period
+    /// up to and including the last sample.
+    pub fn frame_count(&self, num_samples: usize) -> usize {
+        if num_samples == 0 {
+            return 0;
+        }
+        ((num_samples - 1) as f64 / self.hop()).floor() as usize + 1
+    }
+
+    /// First output sample of frame `i` during synthesis; frame `i` covers
+    /// `sample(i)..sample(i + 1)`.
+    pub fn sample(&self, i: usize) -> usize {
+        self.center(i).round() as usize
+    }
+
+    /// `len` samples of `input` centred on frame `i`, zero-padded past either end.
+    pub fn chunk(&self, input: &[f64], i: usize, len: usize) -> Vec<f64> {
+        let start = self.center(i).round() as isize - (len / 2) as isize;
+        (0..len as isize)
+            .map(|j| {
+                let pos = start + j;
+                if pos >= 0 && (pos as usize) < input.len() { input[pos as usize] } else { 0.0 }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fractional_hop() {
+        for sample_rate in [44100, 48000, 96000, 22050] {
+            let frames = FramePeriod::new(sample_rate, 5.0);
+            // One second of audio is 200 frames at any rate, without drift
+            assert_eq!(frames.frame_count(sample_rate as usize), 200);
+            assert_eq!(frames.sample(200), sample_rate as usize);
+            let lengths: Vec<usize> = (0..200).map(|i| frames.sample(i + 1) - frames.sample(i)).collect();
+            assert!(lengths.iter().all(|&l| (l as f64 - frames.hop()).abs() < 1.0), "{}: {:?}", sample_rate, lengths);
+        }
+    }
+
+    #[test]
+    fn test_centred_chunk() {
+        let frames = FramePeriod::new(1000, 10.0);
+        let input: Vec<f64> = (0..50).map(|i| i as f64).collect();
+        assert_eq!(frames.chunk(&input, 2, 4), vec![18.0, 19.0, 20.0, 21.0]);
+        assert_eq!(frames.chunk(&input, 0, 4), vec![0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(frames.chunk(&input, 5, 4), vec![48.0, 49.0, 0.0, 0.0]);
+        assert_eq!(frames.frame_count(50), 5);
+        assert_eq!(frames.frame_count(51), 6);
+    }
+}
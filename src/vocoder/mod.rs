@@ -2,8 +2,15 @@ pub mod stydl;
 pub mod transient;
 pub mod synthesis;
 pub mod dio;
+pub mod pyin;
+pub mod f0;
 pub mod trickresolve;
 pub mod d4c;
 pub mod hmm;
+pub mod pulse;
+pub mod frames;
+pub mod coding;
 
 pub use stydl::StydlVocoder;
+pub use frames::FramePeriod;
+pub use f0::{F0Candidate, F0Estimator};
@@ -0,0 +1,209 @@
+use crate::vocoder::synthesis::StydlEngine;
+use crate::util::seeded_rng;
+use crate::vocoder::frames::FramePeriod;
+use crate::vocoder::hmm::DEFAULT_F0_THRESHOLD;
+use rand::Rng;
+use rustfft::{FftPlanner, num_complex::Complex};
+use std::sync::Mutex;
+
+/// Excitation rate used to lay out noise events in unvoiced frames.
+const UNVOICED_RATE: f64 = 500.0;
+
+/// WORLD-style source-filter vocoder, used when `stydl=false`. Every pitch
+/// period emits a minimum-phase pulse shaped by the periodic part of the
+/// envelope plus a burst of noise shaped by the aperiodic part.
+pub struct PulseEngine {
+    pub sample_rate: u32,
+    pub frame_period: FramePeriod,
+    pub threads: usize,
+    /// Seed of the aperiodic noise.
+    pub seed: u64,
+    /// F0 below which a frame has no pitch to render.
+    pub f0_threshold: f64,
+    fft_size: usize,
+    planner: Mutex<FftPlanner<f64>>,
+}
+
+struct Event {
+    position: usize,
+    frame: usize,
+    period: f64,
+    /// Voicing posterior, 0.0 for noise-only events.
+    voicing: f64,
+}
+
+impl PulseEngine {
+    pub fn new(frame_period: FramePeriod) -> Self {
+        let sample_rate = frame_period.sample_rate;
+        // Room for a noise burst one period long at the lowest F0, plus the filter tail
+        let fft_size = ((sample_rate as f64 / 40.0) as usize * 2).next_power_of_two();
+        Self {
+            sample_rate,
+            frame_period,
+            threads: 1,
+            seed: 0,
+            f0_threshold: DEFAULT_F0_THRESHOLD,
+            fft_size,
+            planner: Mutex::new(FftPlanner::new()),
+        }
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_f0_threshold(mut self, f0_threshold: f64) -> Self {
+        self.f0_threshold = f0_threshold;
+        self
+    }
+
+    /// Renders one frame per entry of `f0`, splitting each event between pulse
+    /// and noise by its frame's `voicing` posterior.
+    pub fn synthesize(&self, f0: &[f64], spectral: &[Vec<f64>], aperiodicity: &[Vec<f64>], voicing: &[f64]) -> Vec<f64> {
+        let hop_size = self.frame_period.hop();
+        let num_frames = f0.len();
+        let total_samples = self.frame_period.sample(num_frames);
+        if num_frames == 0 {
+            return Vec::new();
+        }
+
+        let fs = self.sample_rate as f64;
+        let mut events = Vec::new();
+        let mut pos = 0.0;
+        while (pos as usize) < total_samples {
+            let t = pos / hop_size;
+            let i0 = (t.floor() as usize).min(num_frames - 1);
+            let i1 = (i0 + 1).min(num_frames - 1);
+            let voiced = f0[i0] >= self.f0_threshold && f0[i1] >= self.f0_threshold;
+            let rate = if voiced { crate::util::lerp(f0[i0], f0[i1], t - i0 as f64) } else { UNVOICED_RATE };
+            let period = fs / rate;
+            let voicing = if voiced { StydlEngine::voicing_at(f0, voicing, i0, self.f0_threshold) } else { 0.0 };
+            events.push(Event { position: pos.round() as usize, frame: i0, period, voicing });
+            pos += period;
+        }
+
+        let responses = crate::util::parallel_map(events.len(), self.threads, |i| {
+            let e = &events[i];
+            self.render_event(i, &spectral[e.frame], &aperiodicity[e.frame], e.period, e.voicing)
+        });
+
+        let mut output = vec![0.0; total_samples + self.fft_size];
+        for (event, response) in events.iter().zip(responses) {
+            for (j, v) in response.into_iter().enumerate() {
+                output[event.position + j] += v;
+            }
+        }
+        output.truncate(total_samples);
+
+        let peak = output.iter().map(|x| x.abs()).fold(0.0_f64, f64::max);
+        if peak > 0.001 {
+            let scale = 0.85 / peak;
+            for x in output.iter_mut() {
+                *x *= scale;
+            }
+        }
+
+        output
+    }
+
+    fn render_event(&self, index: usize, spec: &[f64], bap: &[f64], period: f64, voicing: f64) -> Vec<f64> {
+        let n = self.fft_size;
+        let num_bins = n / 2 + 1;
+        let mut periodic = vec![0.0; num_bins];
+        let mut aperiodic = vec![0.0; num_bins];
+        for k in 0..num_bins {
+            let freq = k as f64 * self.sample_rate as f64 / n as f64;
+            let amp = StydlEngine::get_amp(spec, freq, self.sample_rate);
+            let ap = StydlEngine::blend_bap(StydlEngine::get_bap(bap, freq, self.sample_rate), voicing);
+            periodic[k] = amp * (1.0 - ap);
+            aperiodic[k] = amp * ap;
+        }
+
+        let fft = self.planner.lock().unwrap().plan_fft_forward(n);
+        let ifft = self.planner.lock().unwrap().plan_fft_inverse(n);
+        let mut output = vec![0.0; n];
+
+        // Periodic part: one pulse, scaled by sqrt(period) so loudness does not depend on F0
+        if voicing > 0.0 {
+            let mut response = self.min_phase_spectrum(&periodic);
+            ifft.process(&mut response);
+            let gain = period.sqrt() / n as f64;
+            for (o, c) in output.iter_mut().zip(&response) {
+                *o += c.re * gain;
+            }
+        }
+
+        // Aperiodic part: one period of unit-variance noise through the aperiodic filter
+        let mut rng = seeded_rng(self.seed, index as u64);
+        let noise_len = (period.round() as usize).min(n);
+        let mut noise: Vec<Complex<f64>> = (0..n)
+            .map(|i| {
+                let v = if i < noise_len { (rng.gen::<f64>() * 2.0 - 1.0) * 3.0_f64.sqrt() } else { 0.0 };
+                Complex::new(v, 0.0)
+            })
+            .collect();
+        fft.process(&mut noise);
+        let filter = self.min_phase_spectrum(&aperiodic);
+        for (x, h) in noise.iter_mut().zip(&filter) {
+            *x *= h;
+        }
+        ifft.process(&mut noise);
+        for (o, c) in output.iter_mut().zip(&noise) {
+            *o += c.re / n as f64;
+        }
+
+        output
+    }
+
+    /// Minimum-phase spectrum with magnitude `amp` (one-sided), via the folded real cepstrum.
+    fn min_phase_spectrum(&self, amp: &[f64]) -> Vec<Complex<f64>> {
+        let n = self.fft_size;
+        let fft = self.planner.lock().unwrap().plan_fft_forward(n);
+        let ifft = self.planner.lock().unwrap().plan_fft_inverse(n);
+
+        let mut cep: Vec<Complex<f64>> = (0..n)
+            .map(|k| {
+                let bin = if k <= n / 2 { k } else { n - k };
+                Complex::new(amp[bin].max(1e-12).ln(), 0.0)
+            })
+            .collect();
+        ifft.process(&mut cep);
+
+        for (k, c) in cep.iter_mut().enumerate() {
+            let scale = if k == 0 || k == n / 2 { 1.0 } else if k < n / 2 { 2.0 } else { 0.0 };
+            *c = Complex::new(c.re * scale / n as f64, 0.0);
+        }
+        fft.process(&mut cep);
+        cep.iter().map(|c| c.exp()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_pitch() {
+        let frames = 60;
+        let frame_period = FramePeriod::new(16000, 5.0);
+        let f0 = vec![200.0; frames];
+        let spectral = vec![(0..257).map(|k| 1.0 / (1.0 + k as f64 * 0.05)).collect::<Vec<f64>>(); frames];
+        let aperiodicity = vec![vec![0.0; 257]; frames];
+        let voicing = vec![1.0; frames];
+        let output = PulseEngine::new(frame_period).synthesize(&f0, &spectral, &aperiodicity, &voicing);
+        assert_eq!(output.len(), frame_period.sample(frames));
+        assert!(output.iter().all(|x| x.abs() <= 0.85 + 1e-9));
+
+        // The autocorrelation of the middle peaks at one period, 80 samples at 200 Hz
+        let middle = &output[1000..3800];
+        let correlation = |lag: usize| (0..middle.len() - lag).map(|i| middle[i] * middle[i + lag]).sum::<f64>();
+        let period = (40..200).max_by(|&a, &b| correlation(a).total_cmp(&correlation(b))).unwrap();
+        assert_eq!(period, 80);
+    }
+}
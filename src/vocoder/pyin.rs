@@ -0,0 +1,271 @@
+use crate::vocoder::f0::{F0Candidate, F0Estimator};
+use crate::vocoder::frames::FramePeriod;
+use rustfft::{FftPlanner, num_complex::Complex};
+use std::sync::Mutex;
+
+/// Candidates kept per frame.
+const MAX_CANDIDATES: usize = 8;
+
+/// Frames whose candidates add up to less than this are unvoiced.
+const VOICING_THRESHOLD: f64 = 0.5;
+
+/// Pitch change, in cents per frame, at which a candidate's score halves while tracking.
+const TRACKING_CENTS: f64 = 50.0;
+
+/// Probabilistic YIN (Mauch & Dixon, 2014) with Harvest-style contour tracking.
+///
+/// Every frame's cumulative mean normalized difference function is searched
+/// with a spread of thresholds rather than YIN's single one; each threshold
+/// votes for the first dip below it, so a frame yields several weighted
+/// candidates, octave alternatives included. Voiced runs are then tracked
+/// outwards from their most confident frame, following the candidate that
+/// continues the contour best.
+pub struct Pyin {
+    pub sample_rate: u32,
+    pub floor: f64,
+    pub ceiling: f64,
+    /// (threshold, weight) pairs of the threshold prior.
+    thresholds: Vec<(f64, f64)>,
+    planner: Mutex<FftPlanner<f64>>,
+}
+
+impl Pyin {
+    pub const DEFAULT_RANGE: (f64, f64) = (50.0, 1100.0);
+
+    pub fn new(sample_rate: u32) -> Self {
+        let (floor, ceiling) = Self::DEFAULT_RANGE;
+        // Beta(2, 11.33) prior over thresholds 0.01..=1.0, mean 0.15 as in the paper
+        let (a, b) = (2.0, 34.0 / 3.0);
+        let mut thresholds: Vec<(f64, f64)> = (1..=100)
+            .map(|i| {
+                let s = i as f64 / 100.0;
+                (s, s.powf(a - 1.0) * (1.0 - s).powf(b - 1.0))
+            })
+            .collect();
+        let total: f64 = thresholds.iter().map(|t| t.1).sum();
+        thresholds.iter_mut().for_each(|t| t.1 /= total);
+
+        Self {
+            sample_rate,
+            floor,
+            ceiling,
+            thresholds,
+            planner: Mutex::new(FftPlanner::new()),
+        }
+    }
+
+    /// Limits the pitch search to `floor..ceiling` Hz.
+    pub fn with_range(mut self, floor: f64, ceiling: f64) -> Self {
+        self.floor = floor;
+        self.ceiling = ceiling;
+        self
+    }
+
+    fn lag_range(&self) -> (usize, usize) {
+        let fs = self.sample_rate as f64;
+        (((fs / self.ceiling).floor() as usize).max(2), (fs / self.floor).ceil() as usize)
+    }
+
+    /// Cumulative mean normalized difference of `chunk`, for lags `0..=max_lag`
+    /// over a window of `chunk.len() - max_lag` samples.
+    fn cmndf(&self, chunk: &[f64], max_lag: usize) -> Vec<f64> {
+        let window = chunk.len() - max_lag;
+        let n = (chunk.len() + window).next_power_of_two();
+        let (fft, ifft) = {
+            let mut planner = self.planner.lock().unwrap();
+            (planner.plan_fft_forward(n), planner.plan_fft_inverse(n))
+        };
+
+        // Cross-correlation of the first window with the whole chunk
+        let mut head: Vec<Complex<f64>> = (0..n).map(|i| Complex::new(if i < window { chunk[i] } else { 0.0 }, 0.0)).collect();
+        let mut full: Vec<Complex<f64>> = (0..n).map(|i| Complex::new(chunk.get(i).copied().unwrap_or(0.0), 0.0)).collect();
+        fft.process(&mut head);
+        fft.process(&mut full);
+        let mut cross: Vec<Complex<f64>> = head.iter().zip(&full).map(|(h, f)| h.conj() * f).collect();
+        ifft.process(&mut cross);
+
+        let mut energy = vec![0.0; chunk.len() + 1];
+        for (i, &x) in chunk.iter().enumerate() {
+            energy[i + 1] = energy[i] + x * x;
+        }
+        let head_energy = energy[window];
+
+        let mut cmndf = vec![1.0; max_lag + 1];
+        let mut running = 0.0;
+        for lag in 1..=max_lag {
+            let lagged_energy = energy[lag + window] - energy[lag];
+            let diff = (head_energy + lagged_energy - 2.0 * cross[lag].re / n as f64).max(0.0);
+            running += diff;
+            cmndf[lag] = if running > 0.0 { diff * lag as f64 / running } else { 1.0 };
+        }
+        cmndf
+    }
+
+    /// Weighted candidates of one frame: every threshold votes for the first
+    /// dip below it, or with a small weight for the deepest dip if none is.
+    fn frame_candidates(&self, chunk: &[f64], min_lag: usize, max_lag: usize) -> Vec<F0Candidate> {
+        if chunk.iter().all(|&x| x.abs() < 1e-9) {
+            return Vec::new();
+        }
+        let d = self.cmndf(chunk, max_lag);
+
+        // Local minima with parabolic refinement: (lag, value)
+        let dips: Vec<(f64, f64)> = (min_lag.max(1)..max_lag)
+            .filter(|&t| d[t] < d[t - 1] && d[t] <= d[t + 1])
+            .map(|t| {
+                let (a, b, c) = (d[t - 1], d[t], d[t + 1]);
+                let denom = a - 2.0 * b + c;
+                let shift = if denom > 0.0 { (0.5 * (a - c) / denom).clamp(-0.5, 0.5) } else { 0.0 };
+                (t as f64 + shift, b - 0.25 * (a - c) * shift)
+            })
+            .collect();
+        if dips.is_empty() {
+            return Vec::new();
+        }
+        let deepest = (0..dips.len()).min_by(|&a, &b| dips[a].1.total_cmp(&dips[b].1)).unwrap();
+
+        let mut weights = vec![0.0; dips.len()];
+        for &(threshold, weight) in &self.thresholds {
+            match dips.iter().position(|&(_, value)| value < threshold) {
+                Some(i) => weights[i] += weight,
+                None => weights[deepest] += 0.01 * weight,
+            }
+        }
+
+        let mut candidates: Vec<F0Candidate> = dips
+            .iter()
+            .zip(weights)
+            .filter(|&(_, w)| w > 0.0)
+            .map(|(&(lag, _), confidence)| F0Candidate { f0: self.sample_rate as f64 / lag, confidence })
+            .collect();
+        candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        candidates.truncate(MAX_CANDIDATES);
+        candidates
+    }
+
+    /// Follows each voiced run outwards from its most confident frame.
+    fn track(candidates: &[Vec<F0Candidate>]) -> Vec<f64> {
+        let voiced: Vec<bool> = candidates
+            .iter()
+            .map(|c| c.iter().map(|c| c.confidence).sum::<f64>() >= VOICING_THRESHOLD)
+            .collect();
+        let mut f0 = vec![0.0; candidates.len()];
+
+        let mut start = 0;
+        while start < candidates.len() {
+            if !voiced[start] {
+                start += 1;
+                continue;
+            }
+            let end = (start..candidates.len()).find(|&i| !voiced[i]).unwrap_or(candidates.len());
+            let anchor = (start..end)
+                .max_by(|&a, &b| candidates[a][0].confidence.total_cmp(&candidates[b][0].confidence))
+                .unwrap();
+            f0[anchor] = candidates[anchor][0].f0;
+
+            let follow = |previous: f64, frame: &[F0Candidate]| -> f64 {
+                frame
+                    .iter()
+                    .max_by(|a, b| {
+                        let score = |c: &F0Candidate| c.confidence * (-(1200.0 * (c.f0 / previous).log2()).abs() / TRACKING_CENTS).exp2();
+                        score(a).total_cmp(&score(b))
+                    })
+                    .map_or(previous, |c| c.f0)
+            };
+            for i in anchor + 1..end {
+                f0[i] = follow(f0[i - 1], &candidates[i]);
+            }
+            for i in (start..anchor).rev() {
+                f0[i] = follow(f0[i + 1], &candidates[i]);
+            }
+            start = end;
+        }
+        f0
+    }
+}
+
+impl F0Estimator for Pyin {
+    fn name(&self) -> String {
+        format!("pyin {}-{}", self.floor, self.ceiling)
+    }
+
+    fn estimate(&self, input: &[f64], frames: &FramePeriod) -> Vec<f64> {
+        Self::track(&self.candidates(input, frames))
+    }
+
+    fn candidates(&self, input: &[f64], frames: &FramePeriod) -> Vec<Vec<F0Candidate>> {
+        let (min_lag, max_lag) = self.lag_range();
+        // The window spans the longest period, so every lag sees a full one
+        let chunk_len = 2 * max_lag + 1;
+        (0..frames.frame_count(input.len()))
+            .map(|i| self.frame_candidates(&frames.chunk(input, i, chunk_len), min_lag, max_lag))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocoder::dio::Dio;
+    use crate::vocoder::hmm::VoicingHmm;
+
+    const FS: u32 = 44100;
+
+    /// Harmonics of a pitch contour with the given amplitudes, starting at the fundamental.
+    fn voice(f0: impl Fn(f64) -> f64, amplitudes: &[f64], seconds: f64) -> Vec<f64> {
+        let mut phase = 0.0;
+        (0..(seconds * FS as f64) as usize)
+            .map(|i| {
+                phase += 2.0 * std::f64::consts::PI * f0(i as f64 / FS as f64) / FS as f64;
+                amplitudes.iter().enumerate().map(|(h, a)| a * ((h + 1) as f64 * phase).sin()).sum::<f64>() * 0.3
+            })
+            .collect()
+    }
+
+    /// Median ratio of estimated to true F0 over the voiced frames, and the fraction of frames within 2%.
+    fn accuracy(estimated: &[f64], truth: impl Fn(f64) -> f64, frames: &FramePeriod) -> f64 {
+        let inner = &estimated[4..estimated.len() - 4];
+        let good = inner
+            .iter()
+            .enumerate()
+            .filter(|&(i, &f)| (f / truth((i + 4) as f64 * frames.period_ms / 1000.0) - 1.0).abs() < 0.02)
+            .count();
+        good as f64 / inner.len() as f64
+    }
+
+    #[test]
+    fn test_tracks_low_and_high_voices() {
+        let frames = FramePeriod::new(FS, 5.0);
+        let pyin = Pyin::new(FS);
+        // A low voice with a weak fundamental, where autocorrelation tends to jump an octave up
+        let low = |t: f64| 85.0 + 10.0 * (2.0 * std::f64::consts::PI * 3.0 * t).sin();
+        let low_voice = voice(low, &[0.2, 1.0, 0.8, 0.6, 0.5, 0.4, 0.3, 0.2], 0.5);
+        assert!(accuracy(&pyin.estimate(&low_voice, &frames), low, &frames) > 0.95);
+        let decoded = VoicingHmm::new().track_candidates(&pyin.candidates(&low_voice, &frames));
+        assert!(accuracy(&decoded, low, &frames) > 0.95);
+
+        // Belting above Dio's 500 Hz ceiling
+        let high = |t: f64| 700.0 + 200.0 * t;
+        let high_voice = voice(high, &[1.0, 0.5, 0.3], 0.5);
+        assert!(accuracy(&pyin.estimate(&high_voice, &frames), high, &frames) > 0.95);
+        assert!(accuracy(&Dio::new(FS).estimate(&high_voice, &frames), high, &frames) < 0.5);
+    }
+
+    #[test]
+    fn test_candidates_and_unvoiced_frames() {
+        let frames = FramePeriod::new(FS, 5.0);
+        let pyin = Pyin::new(FS);
+        let tone = voice(|_| 220.0, &[1.0, 0.5], 0.2);
+        let candidates = pyin.candidates(&tone, &frames);
+        assert_eq!(candidates.len(), frames.frame_count(tone.len()));
+        let middle = &candidates[candidates.len() / 2];
+        assert!((middle[0].f0 - 220.0).abs() < 1.0, "{:?}", middle);
+        assert!(middle.windows(2).all(|w| w[0].confidence >= w[1].confidence));
+
+        let mut noise_rng = crate::util::seeded_rng(1, 0);
+        let noise: Vec<f64> = (0..FS as usize / 5).map(|_| rand::Rng::gen::<f64>(&mut noise_rng) - 0.5).collect();
+        let f0 = pyin.estimate(&noise, &frames);
+        assert!(f0.iter().filter(|&&f| f > 0.0).count() < f0.len() / 10, "{:?}", f0);
+        assert!(pyin.estimate(&vec![0.0; 4410], &frames).iter().all(|&f| f == 0.0));
+    }
+}
@@ -1,15 +1,23 @@
 use crate::vocoder::transient::TransientDetector;
 use crate::vocoder::synthesis::StydlEngine;
 use crate::vocoder::dio::Dio;
-use crate::vocoder::trickresolve::TrickResolve;
+use crate::vocoder::f0::F0Estimator;
+use crate::vocoder::hmm::VoicingHmm;
+use crate::vocoder::trickresolve::{SpectralMethod, TrickResolve};
 use crate::vocoder::d4c::D4C;
+use crate::vocoder::frames::FramePeriod;
+use crate::analysis::FRAME_PERIOD;
 
 pub struct StydlVocoder {
     pub sample_rate: u32,
     pub fft_size: usize,
+    /// Frame timing of the analysis and of the synthesis engine.
+    pub frame_period: FramePeriod,
     pub detector: TransientDetector,
     pub engine: StydlEngine,
-    pub f0_estimator: Dio,
+    pub f0_estimator: Box<dyn F0Estimator>,
+    /// Turns the raw F0 estimates into the final voiced/unvoiced pitch track.
+    pub voicing: VoicingHmm,
     pub spectral_resolver: TrickResolve,
     pub aperiodicity_estimator: D4C,
 }
@@ -17,37 +25,54 @@ pub struct StydlVocoder {
 impl StydlVocoder {
     pub fn new(sample_rate: u32, _fft_size: usize) -> Self {
         let max_fft_size = 4096;
+        let frame_period = FramePeriod::new(sample_rate, FRAME_PERIOD);
         Self { 
             sample_rate,
             fft_size: max_fft_size,
+            frame_period,
             detector: TransientDetector::new(512, 256),
-            engine: StydlEngine::new(sample_rate, max_fft_size),
-            f0_estimator: Dio::new(sample_rate),
+            engine: StydlEngine::new(frame_period),
+            f0_estimator: Box::new(Dio::new(sample_rate)),
+            voicing: VoicingHmm::new(),
             spectral_resolver: TrickResolve::new(sample_rate),
             aperiodicity_estimator: D4C::new(sample_rate),
         }
     }
 
-    pub fn process(&mut self, f0: &[f64], spectral: &[Vec<f64>], aperiodicity: &[Vec<f64>], source: &[f64], _source_frames: &[f64]) -> Vec<f64> {
-        let mut refined_spectral = Vec::with_capacity(f0.len());
-        let mut refined_aperiodicity = Vec::with_capacity(f0.len());
-
-        let hop_size = 256;
-        for (i, &f) in f0.iter().enumerate() {
-            let start = (i * hop_size).min(source.len());
-            let end = (start + self.fft_size).min(source.len());
-            let chunk = &source[start..end];
-            
-            if chunk.is_empty() || f < 40.0 {
-                refined_spectral.push(spectral[i].clone());
-                refined_aperiodicity.push(aperiodicity[i].clone());
-                continue;
-            }
-
-            refined_spectral.push(spectral[i].clone());
-            refined_aperiodicity.push(aperiodicity[i].clone());
-        }
+    /// Renders synthesis frames on `threads` worker threads.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.engine.threads = threads.max(1);
+        self
+    }
+
+    pub fn with_f0_estimator(mut self, estimator: Box<dyn F0Estimator>) -> Self {
+        self.f0_estimator = estimator;
+        self
+    }
+
+    pub fn with_voicing(mut self, voicing: VoicingHmm) -> Self {
+        self.voicing = voicing;
+        self
+    }
+
+    pub fn with_spectral_method(mut self, method: SpectralMethod) -> Self {
+        self.spectral_resolver = self.spectral_resolver.with_method(method);
+        self
+    }
+
+    pub fn with_f0_threshold(mut self, f0_threshold: f64) -> Self {
+        self.engine.f0_threshold = f0_threshold;
+        self
+    }
+
+    /// Seeds the engine's random phases and noise.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.engine.reseed(seed);
+        self
+    }
 
-        self.engine.synthesize(f0, &refined_spectral, &refined_aperiodicity)
+    /// Synthesizes rendered features, one frame per `frame_period`.
+    pub fn process(&mut self, f0: &[f64], spectral: &[Vec<f64>], aperiodicity: &[Vec<f64>], voicing: &[f64]) -> Vec<f64> {
+        self.engine.synthesize(f0, spectral, aperiodicity, voicing)
     }
 }
@@ -1,25 +1,54 @@
-use rand::{thread_rng, Rng};
+use crate::util::{seeded_rng, PHASE_STREAM};
+use crate::vocoder::frames::FramePeriod;
+use crate::vocoder::hmm::DEFAULT_F0_THRESHOLD;
+use rand::Rng;
 use rustfft::{FftPlanner, num_complex::Complex};
 use std::sync::Mutex;
 
 pub struct StydlEngine {
     pub sample_rate: u32,
+    /// Frame `i` of the features is rendered from `frame_period.sample(i)` on.
+    pub frame_period: FramePeriod,
+    /// Frames rendered in parallel; harmonic phases are precomputed so the result does not depend on it.
+    pub threads: usize,
+    /// Seed of the harmonic phase offsets and the noise stream.
+    pub seed: u64,
+    /// F0 below which a frame has no pitch to render.
+    pub f0_threshold: f64,
     harmonic_phases: Vec<f64>,
     fft_planner: Mutex<FftPlanner<f64>>,
 }
 
 impl StydlEngine {
-    pub fn new(sample_rate: u32, _fft_size: usize) -> Self {
-        let mut rng = thread_rng();
-        let phases: Vec<f64> = (0..1024).map(|_| rng.gen::<f64>() * 2.0 * std::f64::consts::PI).collect();
+    pub fn new(frame_period: FramePeriod) -> Self {
         Self {
-            sample_rate,
-            harmonic_phases: phases,
+            sample_rate: frame_period.sample_rate,
+            frame_period,
+            threads: 1,
+            seed: 0,
+            f0_threshold: DEFAULT_F0_THRESHOLD,
+            harmonic_phases: Self::initial_phases(0),
             fft_planner: Mutex::new(FftPlanner::new()),
         }
     }
 
-    fn get_amp(spec: &[f64], freq: f64, fs: u32) -> f64 {
+    /// Re-seeds the harmonic phases and noise; equal seeds render bit-identical output.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.reseed(seed);
+        self
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.harmonic_phases = Self::initial_phases(seed);
+    }
+
+    fn initial_phases(seed: u64) -> Vec<f64> {
+        let mut rng = seeded_rng(seed, PHASE_STREAM);
+        (0..1024).map(|_| rng.gen::<f64>() * 2.0 * std::f64::consts::PI).collect()
+    }
+
+    pub(crate) fn get_amp(spec: &[f64], freq: f64, fs: u32) -> f64 {
         let n = spec.len();
         if n == 0 { return 0.0; }
         let idx_f = freq * (n - 1) as f64 / (fs as f64 / 2.0);
@@ -31,7 +60,7 @@ impl StydlEngine {
         power.max(0.0).sqrt()
     }
 
-    fn get_bap(bap: &[f64], freq: f64, fs: u32) -> f64 {
+    pub(crate) fn get_bap(bap: &[f64], freq: f64, fs: u32) -> f64 {
         let n = bap.len();
         if n == 0 { return 1.0; }
         let idx_f = freq * (n - 1) as f64 / (fs as f64 / 2.0);
@@ -43,10 +72,20 @@ impl StydlEngine {
         val.clamp(0.0, 1.0)
     }
 
-    fn synthesize_noise_grain(&self, spec: &[f64], bap: &[f64], fft_size: usize) -> Vec<f64> {
-        let mut planner = self.fft_planner.lock().unwrap();
-        let fft = planner.plan_fft_inverse(fft_size);
-        let mut rng = thread_rng();
+    /// How much of frame `i` is voiced: its voicing posterior, or nothing
+    /// where there is no pitch to render.
+    pub(crate) fn voicing_at(f0: &[f64], voicing: &[f64], i: usize, f0_threshold: f64) -> f64 {
+        if f0[i] >= f0_threshold { voicing[i].clamp(0.0, 1.0) } else { 0.0 }
+    }
+
+    /// Aperiodicity of a partly voiced frame: the unvoiced share is all noise.
+    pub(crate) fn blend_bap(bap: f64, voicing: f64) -> f64 {
+        1.0 - voicing * (1.0 - bap)
+    }
+
+    fn synthesize_noise_grain(&self, frame: usize, spec: &[f64], bap: &[f64], voicing: f64, fft_size: usize) -> Vec<f64> {
+        let fft = self.fft_planner.lock().unwrap().plan_fft_inverse(fft_size);
+        let mut rng = seeded_rng(self.seed, frame as u64);
 
         let mut buffer = vec![Complex::new(0.0, 0.0); fft_size];
         let num_bins = fft_size / 2 + 1;
@@ -56,7 +95,7 @@ impl StydlEngine {
             let amp = Self::get_amp(spec, freq, self.sample_rate);
             let bap_val = Self::get_bap(bap, freq, self.sample_rate);
 
-            let target_amp = amp * bap_val;
+            let target_amp = amp * Self::blend_bap(bap_val, voicing);
             let phase = rng.gen::<f64>() * 2.0 * std::f64::consts::PI;
 
             let val = Complex::from_polar(target_amp, phase);
@@ -70,91 +109,195 @@ impl StydlEngine {
         buffer.iter().map(|c| c.re / fft_size as f64).collect()
     }
 
-    pub fn synthesize(&mut self, f0: &[f64], spectral: &[Vec<f64>], aperiodicity: &[Vec<f64>]) -> Vec<f64> {
-        let hop_size = 256;
+    /// Renders one frame per entry of `f0`. `voicing` is each frame's voicing
+    /// posterior, by which the harmonic and noise streams are crossfaded.
+    pub fn synthesize(&mut self, f0: &[f64], spectral: &[Vec<f64>], aperiodicity: &[Vec<f64>], voicing: &[f64]) -> Vec<f64> {
         let num_frames = f0.len();
-        let total_samples = num_frames * hop_size;
-        let mut output = vec![0.0; total_samples + 2048];
+        let total_samples = self.frame_period.sample(num_frames);
+        let two_pi = 2.0 * std::f64::consts::PI;
 
-        // ── Sinusoidal & Noise Dual-Stream ──
+        // Fundamental phase at the start of every frame. Harmonic k runs at k times
+        // this phase plus its own offset, so frames can be rendered independently.
+        let mut frame_phase = vec![0.0; num_frames];
+        let mut phase = 0.0;
         for f_idx in 0..num_frames.saturating_sub(1) {
-            let f0_start = f0[f_idx];
-            let f0_end = f0[f_idx + 1];
-
-            let out_start = f_idx * hop_size;
-            
-            // Generate a high-resolution noise stream for this frame (Overlap-Add)
-            let noise_fft_size = 1024;
-            let noise_grain = self.synthesize_noise_grain(&spectral[f_idx], &aperiodicity[f_idx], noise_fft_size);
-
+            frame_phase[f_idx] = phase;
+            let hop_size = self.frame_period.sample(f_idx + 1) - self.frame_period.sample(f_idx);
             for t in 0..hop_size {
-                let out_idx = out_start + t;
-                if out_idx >= total_samples { break; }
-
                 let alpha = t as f64 / hop_size as f64;
-                let current_f0 = f0_start * (1.0 - alpha) + f0_end * alpha;
-                
-                // Voice activity detector with smoothing (to prevent snaps)
-                // If F0 is near zero, we fade out the sines.
-                let voicing_v0 = if f0_start > 40.0 { 1.0 } else { 0.0 };
-                let voicing_v1 = if f0_end > 40.0 { 1.0 } else { 0.0 };
-                let voicing_weight = voicing_v0 * (1.0 - alpha) + voicing_v1 * alpha;
-
-                // 1. Voiced Stream (Sinusoidal)
-                let mut sample_voiced = 0.0;
-                if voicing_weight > 0.001 && current_f0 > 40.0 {
-                    let num_harmonics = (self.sample_rate as f64 / (2.0 * current_f0)).floor() as usize;
-                    let num_harmonics = num_harmonics.min(512);
-
-                    for k in 1..=num_harmonics {
-                        let phase_inc = 2.0 * std::f64::consts::PI * (current_f0 * k as f64) / self.sample_rate as f64;
-                        self.harmonic_phases[k % 1024] = (self.harmonic_phases[k % 1024] + phase_inc) % (2.0 * std::f64::consts::PI);
-
-                        let freq = current_f0 * k as f64;
-                        let amp_s = Self::get_amp(&spectral[f_idx], freq, self.sample_rate);
-                        let amp_e = Self::get_amp(&spectral[f_idx + 1], freq, self.sample_rate);
-                        let amp = amp_s * (1.0 - alpha) + amp_e * alpha;
-
-                        let bap_s = Self::get_bap(&aperiodicity[f_idx], freq, self.sample_rate);
-                        let bap_e = Self::get_bap(&aperiodicity[f_idx + 1], freq, self.sample_rate);
-                        let bap = bap_s * (1.0 - alpha) + bap_e * alpha;
-
-                        // Voiced component is purely the NON-aperiodic part
-                        let v_comp = (1.0 - bap).max(0.0);
-                        sample_voiced += amp * v_comp * self.harmonic_phases[k % 1024].sin();
-                    }
-                } else if current_f0 > 40.0 {
-                    // Even if suppressed, we must keep phase accumulators moving to maintain coherence
-                    let num_harmonics = (self.sample_rate as f64 / (2.0 * current_f0)).floor() as usize;
-                    let num_harmonics = num_harmonics.min(512);
-                    for k in 1..=num_harmonics {
-                        let phase_inc = 2.0 * std::f64::consts::PI * (current_f0 * k as f64) / self.sample_rate as f64;
-                        self.harmonic_phases[k % 1024] = (self.harmonic_phases[k % 1024] + phase_inc) % (2.0 * std::f64::consts::PI);
-                    }
+                let current_f0 = f0[f_idx] * (1.0 - alpha) + f0[f_idx + 1] * alpha;
+                if current_f0 >= self.f0_threshold {
+                    phase = (phase + two_pi * current_f0 / self.sample_rate as f64) % two_pi;
                 }
+            }
+        }
 
-                // Balanced gain scaling for voiced stream
-                let num_v_h = (self.sample_rate as f64 / (2.0 * current_f0.max(40.0))).floor().max(1.0);
-                let voiced_signal = sample_voiced * voicing_weight * (0.2 / num_v_h.powf(0.5));
+        // ── Sinusoidal & Noise Dual-Stream ──
+        let frames = crate::util::parallel_map(num_frames.saturating_sub(1), self.threads, |f_idx| {
+            self.synthesize_frame(f_idx, frame_phase[f_idx], f0, spectral, aperiodicity, voicing)
+        });
 
-                // 2. Unvoiced Stream (Noise Grain from OLA)
-                let noise_win = 0.5 * (1.0 - (2.0 * std::f64::consts::PI * t as f64 / (noise_fft_size as f64 - 1.0)).cos());
-                let unvoiced_signal = noise_grain[t % noise_grain.len()] * noise_win;
+        let mut output: Vec<f64> = frames.into_iter().flatten().collect();
+        output.resize(total_samples, 0.0);
 
-                output[out_idx] += voiced_signal + unvoiced_signal;
-            }
+        // Carry harmonic phases over to the next call
+        for (k, p) in self.harmonic_phases.iter_mut().enumerate() {
+            *p = (*p + k as f64 * phase) % two_pi;
         }
 
         // ── Peak Normalization ──
-        let peak = output.iter().take(total_samples).map(|x| x.abs()).fold(0.0_f64, f64::max);
+        let peak = output.iter().map(|x| x.abs()).fold(0.0_f64, f64::max);
         if peak > 0.001 {
             let scale = 0.85 / peak;
-            for x in output[..total_samples].iter_mut() {
+            for x in output.iter_mut() {
                 *x *= scale;
             }
         }
 
-        output.truncate(total_samples);
         output
     }
-}
\ No newline at end of file
+
+    fn synthesize_frame(
+        &self,
+        f_idx: usize,
+        start_phase: f64,
+        f0: &[f64],
+        spectral: &[Vec<f64>],
+        aperiodicity: &[Vec<f64>],
+        voicing: &[f64],
+    ) -> Vec<f64> {
+        let two_pi = 2.0 * std::f64::consts::PI;
+        let f0_start = f0[f_idx];
+        let f0_end = f0[f_idx + 1];
+        let mut phase = start_phase;
+        let hop_size = self.frame_period.sample(f_idx + 1) - self.frame_period.sample(f_idx);
+        let mut output = vec![0.0; hop_size];
+
+        // Generate a high-resolution noise stream for this frame (Overlap-Add)
+        let noise_fft_size = 1024;
+        let voicing_start = Self::voicing_at(f0, voicing, f_idx, self.f0_threshold);
+        let voicing_end = Self::voicing_at(f0, voicing, f_idx + 1, self.f0_threshold);
+        let noise_grain = self.synthesize_noise_grain(f_idx, &spectral[f_idx], &aperiodicity[f_idx], voicing_start, noise_fft_size);
+
+        for t in 0..hop_size {
+            let alpha = t as f64 / hop_size as f64;
+            let current_f0 = f0_start * (1.0 - alpha) + f0_end * alpha;
+
+            // Phase keeps moving even while the voiced stream is faded out, to maintain coherence
+            if current_f0 >= self.f0_threshold {
+                phase = (phase + two_pi * current_f0 / self.sample_rate as f64) % two_pi;
+            }
+
+            // Crossfade between the streams by the voicing posterior
+            let voicing_weight = voicing_start * (1.0 - alpha) + voicing_end * alpha;
+
+            // 1. Voiced Stream (Sinusoidal)
+            let mut sample_voiced = 0.0;
+            if voicing_weight > 0.001 && current_f0 >= self.f0_threshold {
+                let num_harmonics = (self.sample_rate as f64 / (2.0 * current_f0)).floor() as usize;
+                let num_harmonics = num_harmonics.min(512);
+
+                for k in 1..=num_harmonics {
+                    let freq = current_f0 * k as f64;
+                    let amp_s = Self::get_amp(&spectral[f_idx], freq, self.sample_rate);
+                    let amp_e = Self::get_amp(&spectral[f_idx + 1], freq, self.sample_rate);
+                    let amp = amp_s * (1.0 - alpha) + amp_e * alpha;
+
+                    let bap_s = Self::get_bap(&aperiodicity[f_idx], freq, self.sample_rate);
+                    let bap_e = Self::get_bap(&aperiodicity[f_idx + 1], freq, self.sample_rate);
+                    let bap = bap_s * (1.0 - alpha) + bap_e * alpha;
+
+                    // Voiced component is purely the NON-aperiodic part
+                    let v_comp = (1.0 - bap).max(0.0);
+                    let harmonic_phase = self.harmonic_phases[k % 1024] + k as f64 * phase;
+                    sample_voiced += amp * v_comp * harmonic_phase.sin();
+                }
+            }
+
+            // Balanced gain scaling for voiced stream
+            let num_v_h = (self.sample_rate as f64 / (2.0 * current_f0.max(40.0))).floor().max(1.0);
+            let voiced_signal = sample_voiced * voicing_weight * (0.2 / num_v_h.powf(0.5));
+
+            // 2. Unvoiced Stream (Noise Grain from OLA)
+            let noise_win = 0.5 * (1.0 - (2.0 * std::f64::consts::PI * t as f64 / (noise_fft_size as f64 - 1.0)).cos());
+            let unvoiced_signal = noise_grain[t % noise_grain.len()] * noise_win;
+
+            output[t] = voiced_signal + unvoiced_signal;
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vocoder::pulse::PulseEngine;
+
+    /// F0, spectral envelope, aperiodicity and voicing.
+    type Features = (Vec<f64>, Vec<Vec<f64>>, Vec<Vec<f64>>, Vec<f64>);
+
+    /// A voiced note that turns breathy and ends unvoiced, so both streams are exercised.
+    fn features() -> Features {
+        let frames = 40;
+        let f0 = (0..frames).map(|i| if i < 30 { 220.0 + i as f64 } else { 0.0 }).collect();
+        let spectral = (0..frames).map(|_| (0..513).map(|k| 1.0 / (1.0 + k as f64 * 0.05)).collect()).collect();
+        let aperiodicity = (0..frames).map(|i| vec![(i as f64 / frames as f64).min(1.0); 513]).collect();
+        let voicing = (0..frames).map(|i| (30.0 - i as f64).clamp(0.0, 1.0)).collect();
+        (f0, spectral, aperiodicity, voicing)
+    }
+
+    #[test]
+    fn test_seeded_synthesis_is_reproducible() {
+        let (f0, spectral, aperiodicity, voicing) = features();
+        let render = |seed: u64, threads: usize| {
+            let mut engine = StydlEngine::new(FramePeriod::new(44100, 5.0)).with_seed(seed);
+            engine.threads = threads;
+            engine.synthesize(&f0, &spectral, &aperiodicity, &voicing)
+        };
+        let reference = render(7, 1);
+        assert_eq!(reference, render(7, 1));
+        assert_eq!(reference, render(7, 3));
+        assert_ne!(reference, render(8, 1));
+
+        let pulse = |seed: u64, threads: usize| {
+            PulseEngine::new(FramePeriod::new(44100, 5.0)).with_threads(threads).with_seed(seed).synthesize(&f0, &spectral, &aperiodicity, &voicing)
+        };
+        let reference = pulse(7, 1);
+        assert_eq!(reference, pulse(7, 3));
+        assert_ne!(reference, pulse(8, 1));
+    }
+
+    /// Normalized autocorrelation at `lag` of the samples around `center`.
+    fn periodicity(signal: &[f64], center: usize, lag: usize) -> f64 {
+        let window = &signal[center - 1000..center + 1000];
+        let (mut cross, mut energy_a, mut energy_b) = (0.0, 0.0, 0.0);
+        for i in 0..window.len() - lag {
+            cross += window[i] * window[i + lag];
+            energy_a += window[i] * window[i];
+            energy_b += window[i + lag] * window[i + lag];
+        }
+        cross / (energy_a * energy_b).sqrt()
+    }
+
+    #[test]
+    fn test_voicing_crossfade() {
+        // Voiced, then fading to unvoiced over 20 frames at a steady pitch with no aperiodicity
+        let frames = 80;
+        let period = 220;
+        let f0 = vec![44100.0 / period as f64; frames];
+        let spectral = vec![(0..513).map(|k| 1.0 / (1.0 + k as f64 * 0.05)).collect::<Vec<f64>>(); frames];
+        let aperiodicity = vec![vec![0.0; 513]; frames];
+        let voicing: Vec<f64> = (0..frames).map(|i| ((50.0 - i as f64) / 20.0).clamp(0.0, 1.0)).collect();
+
+        let frame_period = FramePeriod::new(44100, 5.0);
+        let stydl = StydlEngine::new(frame_period).with_seed(1).synthesize(&f0, &spectral, &aperiodicity, &voicing);
+        let pulse = PulseEngine::new(frame_period).with_seed(1).synthesize(&f0, &spectral, &aperiodicity, &voicing);
+        for output in [stydl, pulse] {
+            let at = |frame: usize| periodicity(&output, frame_period.sample(frame), period);
+            let (voiced, mixed, unvoiced) = (at(15), at(40), at(65));
+            assert!(voiced > 0.9 && unvoiced.abs() < 0.3, "{:.3} {:.3} {:.3}", voiced, mixed, unvoiced);
+            assert!(mixed < voiced && mixed > unvoiced, "{:.3} {:.3} {:.3}", voiced, mixed, unvoiced);
+        }
+    }
+}
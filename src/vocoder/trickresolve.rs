@@ -1,8 +1,48 @@
 use rustfft::{FftPlanner, num_complex::Complex};
 use std::sync::Mutex;
 
+/// Pitch assumed for unvoiced frames by `SpectralMethod::CheapTrick`, as in WORLD.
+const UNVOICED_F0: f64 = 500.0;
+
+/// Weight of the CheapTrick compensation lifter, which restores the
+/// harmonic peaks flattened by smoothing.
+const Q1: f64 = -0.15;
+
+/// How `TrickResolve` turns a frame into a spectral envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpectralMethod {
+    /// A 3-period Hann window and a box-car smoothing that widens with frequency.
+    #[default]
+    Smoothed,
+    /// WORLD's CheapTrick: pitch-adaptive windowing, smoothing over 2/3 of
+    /// the F0 and cepstral liftering, so the envelope does not depend on the pitch.
+    CheapTrick,
+}
+
+impl SpectralMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpectralMethod::Smoothed => "trickresolve",
+            SpectralMethod::CheapTrick => "cheaptrick",
+        }
+    }
+}
+
+impl std::str::FromStr for SpectralMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trickresolve" => Ok(SpectralMethod::Smoothed),
+            "cheaptrick" => Ok(SpectralMethod::CheapTrick),
+            other => anyhow::bail!("Unknown spectral estimator \"{}\" (expected \"trickresolve\" or \"cheaptrick\")", other),
+        }
+    }
+}
+
 pub struct TrickResolve {
     sample_rate: u32,
+    pub method: SpectralMethod,
     planner: Mutex<FftPlanner<f64>>,
 }
 
@@ -10,14 +50,32 @@ impl TrickResolve {
     pub fn new(sample_rate: u32) -> Self {
         Self {
             sample_rate,
+            method: SpectralMethod::default(),
             planner: Mutex::new(FftPlanner::new()),
         }
     }
 
+    pub fn with_method(mut self, method: SpectralMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.method.as_str()
+    }
+
+    /// Power envelope of the frame in the middle of `input`, `fft_size / 2 + 1`
+    /// bins, scaled so a harmonic of amplitude A reads A².
     pub fn resolve(&self, input: &[f64], f0: f64, fft_size: usize) -> Vec<f64> {
+        match self.method {
+            SpectralMethod::Smoothed => self.smoothed(input, f0, fft_size),
+            SpectralMethod::CheapTrick => self.cheaptrick(input, f0, fft_size),
+        }
+    }
+
+    fn smoothed(&self, input: &[f64], f0: f64, fft_size: usize) -> Vec<f64> {
         if f0 <= 40.0 {
-            let mut planner = self.planner.lock().unwrap();
-            let fft = planner.plan_fft_forward(fft_size);
+            let fft = self.planner.lock().unwrap().plan_fft_forward(fft_size);
             let mut windowed = vec![0.0; fft_size];
             let mut window_sum = 0.0;
             for i in 0..input.len().min(fft_size) {
@@ -31,18 +89,19 @@ impl TrickResolve {
                 .map(|c| (c.norm_sqr() * 4.0) / (window_sum * window_sum)).collect();
         }
 
-        let window_len = (3.0 * self.sample_rate as f64 / f0) as usize;
+        let window_len = ((3.0 * self.sample_rate as f64 / f0) as usize).min(input.len()).min(fft_size);
+        // The frame sits in the middle of the chunk
+        let offset = (input.len() - window_len) / 2;
         let mut window_sum = 0.0;
         let mut windowed = vec![0.0; fft_size];
-        for i in 0..window_len.min(input.len()).min(fft_size) {
+        for i in 0..window_len {
             let pos = (i as f64 + 0.5) / window_len as f64;
             let win = 0.5 * (1.0 - (2.0 * std::f64::consts::PI * pos).cos());
-            windowed[i] = input[i] * win;
+            windowed[i] = input[offset + i] * win;
             window_sum += win;
         }
 
-        let mut planner = self.planner.lock().unwrap();
-        let fft = planner.plan_fft_forward(fft_size);
+        let fft = self.planner.lock().unwrap().plan_fft_forward(fft_size);
         let mut buffer: Vec<Complex<f64>> = windowed.iter().map(|&x| Complex::new(x, 0.0)).collect();
         fft.process(&mut buffer);
 
@@ -77,4 +136,160 @@ impl TrickResolve {
 
         smoothed
     }
+
+    fn cheaptrick(&self, input: &[f64], f0: f64, fft_size: usize) -> Vec<f64> {
+        let fs = self.sample_rate as f64;
+        let f0 = if f0 > 40.0 { f0 } else { UNVOICED_F0 };
+        let num_bins = fft_size / 2 + 1;
+        let bin_hz = fs / fft_size as f64;
+
+        // 1. Pitch-adaptive Hann window, three periods long
+        let window_len = ((3.0 * fs / f0).round() as usize).min(input.len()).min(fft_size);
+        let offset = (input.len() - window_len) / 2;
+        let mut buffer = vec![Complex::new(0.0, 0.0); fft_size];
+        let mut window_energy = 0.0;
+        for i in 0..window_len {
+            let pos = (i as f64 + 0.5) / window_len as f64;
+            let win = 0.5 * (1.0 - (2.0 * std::f64::consts::PI * pos).cos());
+            buffer[i] = Complex::new(input[offset + i] * win, 0.0);
+            window_energy += win * win;
+        }
+        let (fft, ifft) = {
+            let mut planner = self.planner.lock().unwrap();
+            (planner.plan_fft_forward(fft_size), planner.plan_fft_inverse(fft_size))
+        };
+        fft.process(&mut buffer);
+        // A harmonic spreads A² fs Σw² / (4 f0) over its F0-wide cell; normalize the cell mean to A²
+        let scale = 4.0 * f0 / (fs * window_energy);
+        let original: Vec<f64> = buffer[..num_bins].iter().map(|c| c.norm_sqr() * scale).collect();
+
+        // 2. Fold the power below F0 back onto itself, as the window smears DC over it
+        let mut power = original.clone();
+        for (k, p) in power.iter_mut().enumerate() {
+            let freq = k as f64 * bin_hz;
+            if freq >= f0 {
+                break;
+            }
+            let mirror = (f0 - freq) / bin_hz;
+            let i = mirror.floor() as usize;
+            *p += crate::util::lerp(original[i], original[(i + 1).min(num_bins - 1)], mirror - i as f64);
+        }
+
+        // 3. Mean over a rectangle 2/3 of F0 wide, which levels the harmonic lobes
+        let half_width = f0 / 3.0 / bin_hz;
+        let margin = half_width.ceil() as usize + 2;
+        let mirrored = |k: isize| -> f64 {
+            let last = (num_bins - 1) as isize;
+            let k = k.abs();
+            power[(if k > last { 2 * last - k } else { k }) as usize]
+        };
+        // cumulative[j] is the power of bins -margin.. below bin j - margin, each bin one unit wide
+        let mut cumulative = vec![0.0; num_bins + 2 * margin + 1];
+        for j in 0..num_bins + 2 * margin {
+            cumulative[j + 1] = cumulative[j] + mirrored(j as isize - margin as isize);
+        }
+        let integral = |x: f64| -> f64 {
+            let pos = x + margin as f64 + 0.5;
+            let j = (pos.floor() as usize).min(cumulative.len() - 2);
+            crate::util::lerp(cumulative[j], cumulative[j + 1], pos - j as f64)
+        };
+        let floor = original.iter().cloned().fold(0.0, f64::max) * 1e-12 + f64::MIN_POSITIVE;
+        let smoothed: Vec<f64> = (0..num_bins)
+            .map(|k| (integral(k as f64 + half_width) - integral(k as f64 - half_width)) / (2.0 * half_width))
+            .map(|p| p.max(floor))
+            .collect();
+
+        // 4. Cepstral liftering: smoothing over one F0 plus the q1 compensation
+        let mut cepstrum: Vec<Complex<f64>> = (0..fft_size)
+            .map(|k| Complex::new(smoothed[k.min(fft_size - k)].ln(), 0.0))
+            .collect();
+        ifft.process(&mut cepstrum);
+        for (i, c) in cepstrum.iter_mut().enumerate() {
+            let quefrency = i.min(fft_size - i) as f64 / fs;
+            let x = std::f64::consts::PI * f0 * quefrency;
+            let smoothing = if i == 0 { 1.0 } else { x.sin() / x };
+            let compensation = (1.0 - 2.0 * Q1) + 2.0 * Q1 * (2.0 * x).cos();
+            *c *= smoothing * compensation / fft_size as f64;
+        }
+        fft.process(&mut cepstrum);
+        cepstrum[..num_bins].iter().map(|c| c.re.exp()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: u32 = 44100;
+    const FFT_SIZE: usize = 4096;
+
+    /// Power response of three vocal-tract-like resonances.
+    fn formant_filter(freq: f64) -> f64 {
+        [(700.0, 130.0), (1200.0, 150.0), (2600.0, 200.0)]
+            .iter()
+            .map(|&(f, b): &(f64, f64)| 1.0 / ((1.0 - (freq / f).powi(2)).powi(2) + (freq * b / (f * f)).powi(2)))
+            .product()
+    }
+
+    /// Harmonics of `f0` shaped by `formant_filter`, with seeded random phases.
+    fn filtered_harmonics(f0: f64) -> Vec<f64> {
+        let mut rng = crate::util::seeded_rng(f0.to_bits(), 0);
+        let harmonics: Vec<(f64, f64, f64)> = (1..)
+            .map(|h| h as f64 * f0)
+            .take_while(|&f| f < FS as f64 / 2.0)
+            .map(|f| (f, formant_filter(f).sqrt(), rand::Rng::gen::<f64>(&mut rng) * 2.0 * std::f64::consts::PI))
+            .collect();
+        (0..FFT_SIZE)
+            .map(|i| {
+                let t = i as f64 / FS as f64;
+                harmonics.iter().map(|&(f, a, phase)| a * (2.0 * std::f64::consts::PI * f * t + phase).sin()).sum()
+            })
+            .collect()
+    }
+
+    /// Envelope minus the true response in dB, for bins between 300 Hz and 5 kHz.
+    fn error_db(envelope: &[f64]) -> Vec<f64> {
+        let bin_hz = FS as f64 / FFT_SIZE as f64;
+        (0..envelope.len())
+            .filter(|&k| (300.0..5000.0).contains(&(k as f64 * bin_hz)))
+            .map(|k| 10.0 * (envelope[k] / formant_filter(k as f64 * bin_hz)).log10())
+            .collect()
+    }
+
+    fn rms(values: &[f64]) -> f64 {
+        (values.iter().map(|v| v * v).sum::<f64>() / values.len() as f64).sqrt()
+    }
+
+    /// RMS error against the true response at each F0, and the RMS over bins
+    /// of how far the estimates for different F0s spread.
+    fn invariance(method: SpectralMethod) -> (Vec<f64>, f64) {
+        let resolver = TrickResolve::new(FS).with_method(method);
+        let errors: Vec<Vec<f64>> = [100.0, 150.0, 200.0, 250.0]
+            .iter()
+            .map(|&f0| error_db(&resolver.resolve(&filtered_harmonics(f0), f0, FFT_SIZE)))
+            .collect();
+        let spread: Vec<f64> = (0..errors[0].len())
+            .map(|k| {
+                let values = errors.iter().map(|e| e[k]);
+                values.clone().fold(f64::MIN, f64::max) - values.fold(f64::MAX, f64::min)
+            })
+            .collect();
+        (errors.iter().map(|e| rms(e)).collect(), rms(&spread))
+    }
+
+    #[test]
+    fn test_cheaptrick_envelope_is_f0_invariant() {
+        let (errors, spread) = invariance(SpectralMethod::CheapTrick);
+        assert!(errors.iter().all(|&e| e < 1.5), "{:?}", errors);
+        assert!(spread < 2.0, "{}", spread);
+
+        // The box-car smoothing leaks harmonics into the envelope
+        let (smoothed_errors, smoothed_spread) = invariance(SpectralMethod::Smoothed);
+        assert!(errors.iter().zip(&smoothed_errors).all(|(e, s)| e < s), "{:?} {:?}", errors, smoothed_errors);
+        assert!(spread < smoothed_spread);
+
+        let unvoiced = TrickResolve::new(FS).with_method(SpectralMethod::CheapTrick).resolve(&filtered_harmonics(100.0), 0.0, FFT_SIZE);
+        assert_eq!(unvoiced.len(), FFT_SIZE / 2 + 1);
+        assert!(unvoiced.iter().all(|v| v.is_finite() && *v > 0.0));
+    }
 }